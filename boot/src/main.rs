@@ -8,16 +8,12 @@ const RUN_ARGS: &[&str] = &[
     "--no-reboot",
     // "-smp",
     // "4",
-    "-device",
-    "isa-debug-exit,iobase=0xf4,iosize=0x04",
     "-serial",
     "stdio",
     "-s",
 ];
 const TEST_ARGS: &[&str] = &[
     "--no-reboot",
-    // "-smp",
-    // "4",
     "-device",
     "isa-debug-exit,iobase=0xf4,iosize=0x04",
     "-serial",
@@ -26,6 +22,11 @@ const TEST_ARGS: &[&str] = &[
     "none",
 ];
 const TEST_TIMEOUT_SECS: u64 = 10;
+/// Left in test binaries by the kernel's `smp_test!`, followed by a little-endian `u32` CPU
+/// count. Must match `SMP_MARKER_MAGIC` in the kernel.
+const SMP_MARKER_MAGIC: &[u8; 16] = b"os81-smp-test-cp";
+/// QEMU's limit for the `pc` machine.
+const MAX_TEST_CPUS: u32 = 255;
 
 fn main() {
     let mut args = std::env::args().skip(1); // skip executable name
@@ -58,6 +59,8 @@ fn main() {
     let binary_kind = runner_utils::binary_kind(&kernel_binary_path);
     if binary_kind.is_test() {
         run_cmd.args(TEST_ARGS);
+        let cpus = test_cpus(&kernel_binary_path);
+        run_cmd.arg("-smp").arg(cpus.to_string());
 
         let exit_status = run_test_command(run_cmd);
         match exit_status.code() {
@@ -74,6 +77,21 @@ fn main() {
     }
 }
 
+/// The most CPUs any `smp_test!` in the test binary asks for, or 1.
+fn test_cpus(kernel_binary_path: &Path) -> u32 {
+    let binary = std::fs::read(kernel_binary_path).unwrap();
+    binary
+        .windows(SMP_MARKER_MAGIC.len() + 4)
+        .filter(|window| window.starts_with(SMP_MARKER_MAGIC))
+        .map(|window| {
+            let count = &window[SMP_MARKER_MAGIC.len()..];
+            u32::from_le_bytes([count[0], count[1], count[2], count[3]])
+        })
+        .max()
+        .unwrap_or(1)
+        .clamp(1, MAX_TEST_CPUS)
+}
+
 fn run_test_command(mut cmd: Command) -> ExitStatus {
     runner_utils::run_with_timeout(&mut cmd, Duration::from_secs(TEST_TIMEOUT_SECS)).unwrap()
 }
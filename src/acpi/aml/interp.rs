@@ -0,0 +1,1747 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::time::Duration;
+
+use crate::{serial_println, time};
+
+use super::name::{is_name_start, AmlName, NameString};
+use super::namespace::Namespace;
+use super::region::{self, PciAddress};
+use super::stream::Stream;
+use super::value::{AmlValue, Field, FieldKind, Method, MethodCode, OpRegion, Reference};
+use super::{eisa_id_to_string, AmlError};
+
+/// How deep terms may nest, counting method calls. A level takes up to about 3 KiB of stack
+/// in debug builds, so this leaves room in the default 80 KiB kernel stack.
+const MAX_DEPTH: usize = 20;
+/// How many times a `While` may loop before it's taken to be stuck.
+const MAX_LOOP_ITERATIONS: usize = 1_000_000;
+/// Largest buffer a `Buffer` may ask for.
+const MAX_BUFFER_SIZE: usize = 1 << 20;
+/// ACPI revision `Revision` reports.
+const REVISION: u64 = 2;
+
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const ALIAS_OP: u8 = 0x06;
+const NAME_OP: u8 = 0x08;
+const BYTE_PREFIX: u8 = 0x0A;
+const WORD_PREFIX: u8 = 0x0B;
+const DWORD_PREFIX: u8 = 0x0C;
+const STRING_PREFIX: u8 = 0x0D;
+const QWORD_PREFIX: u8 = 0x0E;
+const SCOPE_OP: u8 = 0x10;
+const BUFFER_OP: u8 = 0x11;
+const PACKAGE_OP: u8 = 0x12;
+const VAR_PACKAGE_OP: u8 = 0x13;
+const METHOD_OP: u8 = 0x14;
+const EXTERNAL_OP: u8 = 0x15;
+const EXT_OP_PREFIX: u8 = 0x5B;
+const LOCAL0_OP: u8 = 0x60;
+const LOCAL7_OP: u8 = 0x67;
+const ARG0_OP: u8 = 0x68;
+const ARG6_OP: u8 = 0x6E;
+const STORE_OP: u8 = 0x70;
+const REF_OF_OP: u8 = 0x71;
+const ADD_OP: u8 = 0x72;
+const CONCAT_OP: u8 = 0x73;
+const SUBTRACT_OP: u8 = 0x74;
+const INCREMENT_OP: u8 = 0x75;
+const DECREMENT_OP: u8 = 0x76;
+const MULTIPLY_OP: u8 = 0x77;
+const DIVIDE_OP: u8 = 0x78;
+const SHIFT_LEFT_OP: u8 = 0x79;
+const SHIFT_RIGHT_OP: u8 = 0x7A;
+const AND_OP: u8 = 0x7B;
+const NAND_OP: u8 = 0x7C;
+const OR_OP: u8 = 0x7D;
+const NOR_OP: u8 = 0x7E;
+const XOR_OP: u8 = 0x7F;
+const NOT_OP: u8 = 0x80;
+const FIND_SET_LEFT_BIT_OP: u8 = 0x81;
+const FIND_SET_RIGHT_BIT_OP: u8 = 0x82;
+const DEREF_OF_OP: u8 = 0x83;
+const CONCAT_RES_OP: u8 = 0x84;
+const MOD_OP: u8 = 0x85;
+const NOTIFY_OP: u8 = 0x86;
+const SIZE_OF_OP: u8 = 0x87;
+const INDEX_OP: u8 = 0x88;
+const MATCH_OP: u8 = 0x89;
+const CREATE_DWORD_FIELD_OP: u8 = 0x8A;
+const CREATE_WORD_FIELD_OP: u8 = 0x8B;
+const CREATE_BYTE_FIELD_OP: u8 = 0x8C;
+const CREATE_BIT_FIELD_OP: u8 = 0x8D;
+const OBJECT_TYPE_OP: u8 = 0x8E;
+const CREATE_QWORD_FIELD_OP: u8 = 0x8F;
+const LAND_OP: u8 = 0x90;
+const LOR_OP: u8 = 0x91;
+const LNOT_OP: u8 = 0x92;
+const LEQUAL_OP: u8 = 0x93;
+const LGREATER_OP: u8 = 0x94;
+const LLESS_OP: u8 = 0x95;
+const TO_BUFFER_OP: u8 = 0x96;
+const TO_DECIMAL_STRING_OP: u8 = 0x97;
+const TO_HEX_STRING_OP: u8 = 0x98;
+const TO_INTEGER_OP: u8 = 0x99;
+const TO_STRING_OP: u8 = 0x9C;
+const COPY_OBJECT_OP: u8 = 0x9D;
+const MID_OP: u8 = 0x9E;
+const CONTINUE_OP: u8 = 0x9F;
+const IF_OP: u8 = 0xA0;
+const ELSE_OP: u8 = 0xA1;
+const WHILE_OP: u8 = 0xA2;
+const NOOP_OP: u8 = 0xA3;
+const RETURN_OP: u8 = 0xA4;
+const BREAK_OP: u8 = 0xA5;
+const BREAKPOINT_OP: u8 = 0xCC;
+const ONES_OP: u8 = 0xFF;
+
+// after EXT_OP_PREFIX
+const MUTEX_OP: u8 = 0x01;
+const EVENT_OP: u8 = 0x02;
+const COND_REF_OF_OP: u8 = 0x12;
+const CREATE_FIELD_OP: u8 = 0x13;
+const STALL_OP: u8 = 0x21;
+const SLEEP_OP: u8 = 0x22;
+const ACQUIRE_OP: u8 = 0x23;
+const SIGNAL_OP: u8 = 0x24;
+const WAIT_OP: u8 = 0x25;
+const RESET_OP: u8 = 0x26;
+const RELEASE_OP: u8 = 0x27;
+const FROM_BCD_OP: u8 = 0x28;
+const TO_BCD_OP: u8 = 0x29;
+const REVISION_OP: u8 = 0x30;
+const DEBUG_OP: u8 = 0x31;
+const FATAL_OP: u8 = 0x32;
+const TIMER_OP: u8 = 0x33;
+const OP_REGION_OP: u8 = 0x80;
+const FIELD_OP: u8 = 0x81;
+const DEVICE_OP: u8 = 0x82;
+const PROCESSOR_OP: u8 = 0x83;
+const POWER_RES_OP: u8 = 0x84;
+const THERMAL_ZONE_OP: u8 = 0x85;
+const INDEX_FIELD_OP: u8 = 0x86;
+const BANK_FIELD_OP: u8 = 0x87;
+const DATA_REGION_OP: u8 = 0x88;
+
+/// What running a term list ended with.
+enum Flow {
+    Next,
+    Return(AmlValue),
+    Break,
+    Continue,
+}
+
+/// The state of one method invocation, or of loading a table.
+struct Frame {
+    /// Where relative names are resolved from and new objects go.
+    scope: AmlName,
+    locals: Vec<AmlValue>,
+    args: Vec<AmlValue>,
+    /// Objects the method declared, which go away when it returns.
+    created: Vec<AmlName>,
+    /// Whether this runs a method, rather than loading a table or reading an object.
+    in_method: bool,
+    /// How many nested terms and method calls deep this is.
+    depth: usize,
+}
+
+impl Frame {
+    fn new(scope: AmlName, args: Vec<AmlValue>, depth: usize) -> Frame {
+        Frame {
+            scope,
+            locals: vec![AmlValue::Uninitialized; 8],
+            args,
+            created: Vec::new(),
+            in_method: false,
+            depth,
+        }
+    }
+
+    /// Go one term deeper, or fail if that's past `MAX_DEPTH`.
+    fn enter(&mut self) -> Result<(), AmlError> {
+        if self.depth >= MAX_DEPTH {
+            return Err(AmlError::TooDeep);
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn leave(&mut self) {
+        self.depth -= 1;
+    }
+}
+
+type TermHandler = fn(&mut Namespace, u8, &mut Stream, &mut Frame) -> Result<Flow, AmlError>;
+type TermArgHandler = fn(&mut Namespace, u8, &mut Stream, &mut Frame) -> Result<AmlValue, AmlError>;
+type DataHandler =
+    fn(&mut Namespace, u8, AmlValue, &mut Stream, &mut Frame) -> Result<AmlValue, AmlError>;
+
+impl Namespace {
+    /// Load a table's AML, i.e. its data after the header: declare everything in it.
+    pub fn load(&mut self, code: &'static [u8]) -> Result<(), AmlError> {
+        let mut frame = Frame::new(AmlName::root(), Vec::new(), 0);
+        self.term_list(&mut Stream::new(code), &mut frame)?;
+        Ok(())
+    }
+
+    /// Evaluate the object at `name`: run it with `args` if it's a method, and read it
+    /// otherwise.
+    pub fn evaluate(&mut self, name: &AmlName, args: Vec<AmlValue>) -> Result<AmlValue, AmlError> {
+        self.evaluate_at(name, args, 0)
+    }
+
+    fn evaluate_at(
+        &mut self,
+        name: &AmlName,
+        args: Vec<AmlValue>,
+        depth: usize,
+    ) -> Result<AmlValue, AmlError> {
+        let name = self.follow_alias(name);
+        match self.get(&name) {
+            Some(AmlValue::Method(method)) => {
+                let method = method.clone();
+                self.call(&name, &method, args, depth)
+            }
+            Some(_) => {
+                let mut frame = Frame::new(AmlName::root(), Vec::new(), depth);
+                self.read_name(&name, &mut frame)
+            }
+            None => Err(AmlError::NameNotFound(name)),
+        }
+    }
+
+    /// Evaluate `name`'s child `segment` if it has one, e.g. a device's `_ADR`.
+    pub(super) fn evaluate_child(
+        &mut self,
+        name: &AmlName,
+        segment: &[u8; 4],
+        depth: usize,
+    ) -> Result<Option<AmlValue>, AmlError> {
+        let child = name.child(*segment);
+        if self.get(&child).is_none() {
+            return Ok(None);
+        }
+        self.evaluate_at(&child, Vec::new(), depth).map(Some)
+    }
+
+    /// The hardware IDs of a device: its `_HID` and any `_CID`s, with EISA IDs decoded to
+    /// strings like `PNP0A03`.
+    pub fn hardware_ids(&mut self, device: &AmlName) -> Result<Vec<String>, AmlError> {
+        self.hardware_ids_at(device, 0)
+    }
+
+    fn hardware_ids_at(&mut self, device: &AmlName, depth: usize) -> Result<Vec<String>, AmlError> {
+        let mut ids = Vec::new();
+        for segment in [b"_HID", b"_CID"] {
+            let value = match self.evaluate_child(device, segment, depth)? {
+                Some(value) => value,
+                None => continue,
+            };
+            let values = match value {
+                AmlValue::Package(elements) => elements,
+                value => vec![value],
+            };
+            for value in values {
+                match value {
+                    AmlValue::Integer(id) => ids.push(eisa_id_to_string(id as u32)),
+                    AmlValue::String(id) => ids.push(id),
+                    _ => (),
+                }
+            }
+        }
+        Ok(ids)
+    }
+
+    fn call(
+        &mut self,
+        name: &AmlName,
+        method: &Method,
+        mut args: Vec<AmlValue>,
+        depth: usize,
+    ) -> Result<AmlValue, AmlError> {
+        if depth >= MAX_DEPTH {
+            return Err(AmlError::TooDeep);
+        }
+        args.resize(usize::from(method.arg_count), AmlValue::Uninitialized);
+        let code = match method.code {
+            MethodCode::Aml(code) => code,
+            MethodCode::Native(native) => return native(&args),
+        };
+        let mut frame = Frame::new(name.clone(), args, depth + 1);
+        frame.in_method = true;
+        let result = self.term_list(&mut Stream::new(code), &mut frame);
+        for created in frame.created.iter().rev() {
+            self.remove(created);
+        }
+        match result? {
+            Flow::Return(value) => Ok(value),
+            _ => Ok(AmlValue::Uninitialized),
+        }
+    }
+
+    fn follow_alias(&self, name: &AmlName) -> AmlName {
+        let mut name = name.clone();
+        // bounded, in case aliases point at each other
+        for _ in 0..8 {
+            match self.get(&name) {
+                Some(AmlValue::Alias(target)) => name = target.clone(),
+                _ => break,
+            }
+        }
+        name
+    }
+
+    /// Declare `value` at `name`, relative to the frame's scope.
+    fn declare(
+        &mut self,
+        name: &NameString,
+        value: AmlValue,
+        f: &mut Frame,
+    ) -> Result<AmlName, AmlError> {
+        let name = name.resolve(&f.scope)?;
+        self.add(name.clone(), value)?;
+        if f.in_method {
+            f.created.push(name.clone());
+        }
+        Ok(name)
+    }
+
+    fn term_list(&mut self, s: &mut Stream, f: &mut Frame) -> Result<Flow, AmlError> {
+        while !s.at_end() {
+            match self.term(s, f)? {
+                Flow::Next => (),
+                flow => return Ok(flow),
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    /// Run `body` with `scope` as the frame's scope.
+    fn in_scope(
+        &mut self,
+        scope: AmlName,
+        body: &mut Stream,
+        f: &mut Frame,
+    ) -> Result<Flow, AmlError> {
+        let outer = core::mem::replace(&mut f.scope, scope);
+        let flow = self.term_list(body, f);
+        f.scope = outer;
+        flow
+    }
+
+    /// One TermObj: a declaration, a statement, or an expression whose value goes unused.
+    fn term(&mut self, s: &mut Stream, f: &mut Frame) -> Result<Flow, AmlError> {
+        let op = s.peek()?;
+        // each kind in a function of its own, so that nesting through here takes little stack
+        let handler: TermHandler = match op {
+            ALIAS_OP => Self::alias_term,
+            NAME_OP => Self::name_decl_term,
+            SCOPE_OP => Self::scope_term,
+            METHOD_OP => Self::method_term,
+            EXTERNAL_OP => Self::external_term,
+            CREATE_BIT_FIELD_OP
+            | CREATE_BYTE_FIELD_OP
+            | CREATE_WORD_FIELD_OP
+            | CREATE_DWORD_FIELD_OP
+            | CREATE_QWORD_FIELD_OP => Self::create_sized_field_term,
+            IF_OP => Self::if_term,
+            WHILE_OP => Self::while_term,
+            NOOP_OP | BREAKPOINT_OP | RETURN_OP | BREAK_OP | CONTINUE_OP => Self::flow_term,
+            NOTIFY_OP => Self::notify_term,
+            EXT_OP_PREFIX => Self::ext_term,
+            _ => Self::expression_term,
+        };
+        f.enter()?;
+        let flow = handler(self, op, s, f);
+        f.leave();
+        flow
+    }
+
+    fn alias_term(&mut self, _op: u8, s: &mut Stream, f: &mut Frame) -> Result<Flow, AmlError> {
+        s.byte()?;
+        let source = s.name_string()?;
+        let alias = s.name_string()?;
+        let source = self.search(&source, &f.scope)?;
+        self.declare(&alias, AmlValue::Alias(source), f)?;
+        Ok(Flow::Next)
+    }
+
+    fn name_decl_term(&mut self, _op: u8, s: &mut Stream, f: &mut Frame) -> Result<Flow, AmlError> {
+        s.byte()?;
+        let name = s.name_string()?;
+        let value = self.term_arg(s, f)?;
+        self.declare(&name, value, f)?;
+        Ok(Flow::Next)
+    }
+
+    fn scope_term(&mut self, _op: u8, s: &mut Stream, f: &mut Frame) -> Result<Flow, AmlError> {
+        s.byte()?;
+        let end = s.pkg_end()?;
+        let name = s.name_string()?;
+        let scope = match self.search(&name, &f.scope) {
+            Ok(scope) => scope,
+            Err(AmlError::NameNotFound(_)) => self.declare(&name, AmlValue::Scope, f)?,
+            Err(error) => return Err(error),
+        };
+        self.in_scope(scope, &mut s.take_until(end), f)
+    }
+
+    fn method_term(&mut self, _op: u8, s: &mut Stream, f: &mut Frame) -> Result<Flow, AmlError> {
+        s.byte()?;
+        let end = s.pkg_end()?;
+        let name = s.name_string()?;
+        let flags = s.byte()?;
+        let method = Method {
+            arg_count: flags & 0x7,
+            serialized: flags & 0x8 != 0,
+            code: MethodCode::Aml(s.slice_until(end)),
+        };
+        self.declare(&name, AmlValue::Method(method), f)?;
+        Ok(Flow::Next)
+    }
+
+    fn external_term(&mut self, _op: u8, s: &mut Stream, _f: &mut Frame) -> Result<Flow, AmlError> {
+        s.byte()?;
+        s.name_string()?;
+        s.bytes(2)?;
+        Ok(Flow::Next)
+    }
+
+    /// `CreateBitField`, `CreateByteField` and the like.
+    fn create_sized_field_term(
+        &mut self,
+        op: u8,
+        s: &mut Stream,
+        f: &mut Frame,
+    ) -> Result<Flow, AmlError> {
+        s.byte()?;
+        let buffer = self.source_reference(s, f)?;
+        let index = self.integer_arg(s, f)?;
+        let name = s.name_string()?;
+        let (bit_offset, bit_length) = match op {
+            CREATE_BIT_FIELD_OP => (index, 1),
+            CREATE_BYTE_FIELD_OP => (index.wrapping_mul(8), 8),
+            CREATE_WORD_FIELD_OP => (index.wrapping_mul(8), 16),
+            CREATE_DWORD_FIELD_OP => (index.wrapping_mul(8), 32),
+            _ => (index.wrapping_mul(8), 64),
+        };
+        let field = AmlValue::BufferField {
+            buffer,
+            bit_offset,
+            bit_length,
+        };
+        self.declare(&name, field, f)?;
+        Ok(Flow::Next)
+    }
+
+    fn if_term(&mut self, _op: u8, s: &mut Stream, f: &mut Frame) -> Result<Flow, AmlError> {
+        s.byte()?;
+        let end = s.pkg_end()?;
+        let mut body = s.take_until(end);
+        let predicate = self.integer_arg(&mut body, f)? != 0;
+        let otherwise = if s.peek_at(0) == Some(ELSE_OP) {
+            s.byte()?;
+            let end = s.pkg_end()?;
+            Some(s.take_until(end))
+        } else {
+            None
+        };
+        if predicate {
+            self.term_list(&mut body, f)
+        } else if let Some(mut otherwise) = otherwise {
+            self.term_list(&mut otherwise, f)
+        } else {
+            Ok(Flow::Next)
+        }
+    }
+
+    fn while_term(&mut self, _op: u8, s: &mut Stream, f: &mut Frame) -> Result<Flow, AmlError> {
+        s.byte()?;
+        let end = s.pkg_end()?;
+        let code = s.slice_until(end);
+        for iteration in 0.. {
+            if iteration == MAX_LOOP_ITERATIONS {
+                return Err(AmlError::LoopLimit);
+            }
+            let mut body = Stream::new(code);
+            if self.integer_arg(&mut body, f)? == 0 {
+                break;
+            }
+            match self.term_list(&mut body, f)? {
+                Flow::Break => break,
+                Flow::Return(value) => return Ok(Flow::Return(value)),
+                Flow::Next | Flow::Continue => (),
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    /// `Noop`, `BreakPoint`, `Return`, `Break` and `Continue`.
+    fn flow_term(&mut self, op: u8, s: &mut Stream, f: &mut Frame) -> Result<Flow, AmlError> {
+        s.byte()?;
+        let flow = match op {
+            RETURN_OP => Flow::Return(self.term_arg(s, f)?),
+            BREAK_OP => Flow::Break,
+            CONTINUE_OP => Flow::Continue,
+            _ => Flow::Next,
+        };
+        Ok(flow)
+    }
+
+    fn notify_term(&mut self, _op: u8, s: &mut Stream, f: &mut Frame) -> Result<Flow, AmlError> {
+        s.byte()?;
+        self.super_name(s, f)?;
+        self.term_arg(s, f)?;
+        Ok(Flow::Next)
+    }
+
+    /// An expression as a statement, like a method call or `Store`.
+    fn expression_term(
+        &mut self,
+        _op: u8,
+        s: &mut Stream,
+        f: &mut Frame,
+    ) -> Result<Flow, AmlError> {
+        self.term_arg(s, f)?;
+        Ok(Flow::Next)
+    }
+
+    /// A TermObj starting with `EXT_OP_PREFIX`.
+    fn ext_term(&mut self, _op: u8, s: &mut Stream, f: &mut Frame) -> Result<Flow, AmlError> {
+        let op = s.peek_at(1).ok_or(AmlError::UnexpectedEnd)?;
+        let handler: TermHandler = match op {
+            MUTEX_OP | EVENT_OP => Self::sync_object_term,
+            CREATE_FIELD_OP => Self::create_field_term,
+            STALL_OP | SLEEP_OP => Self::delay_term,
+            SIGNAL_OP | RESET_OP | RELEASE_OP => Self::sync_term,
+            FATAL_OP => Self::fatal_term,
+            OP_REGION_OP => Self::op_region_term,
+            FIELD_OP | INDEX_FIELD_OP | BANK_FIELD_OP => Self::field_term,
+            DEVICE_OP | THERMAL_ZONE_OP | PROCESSOR_OP | POWER_RES_OP => Self::scoped_object_term,
+            DATA_REGION_OP => return Err(AmlError::Unsupported("DataTableRegion")),
+            _ => Self::expression_term,
+        };
+        handler(self, op, s, f)
+    }
+
+    /// `Mutex` and `Event`.
+    fn sync_object_term(
+        &mut self,
+        op: u8,
+        s: &mut Stream,
+        f: &mut Frame,
+    ) -> Result<Flow, AmlError> {
+        s.bytes(2)?;
+        let name = s.name_string()?;
+        let value = match op {
+            MUTEX_OP => AmlValue::Mutex {
+                sync_level: s.byte()? & 0xF,
+            },
+            _ => AmlValue::Event,
+        };
+        self.declare(&name, value, f)?;
+        Ok(Flow::Next)
+    }
+
+    fn create_field_term(
+        &mut self,
+        _op: u8,
+        s: &mut Stream,
+        f: &mut Frame,
+    ) -> Result<Flow, AmlError> {
+        s.bytes(2)?;
+        let buffer = self.source_reference(s, f)?;
+        let bit_offset = self.integer_arg(s, f)?;
+        let bit_length = self.integer_arg(s, f)?;
+        let name = s.name_string()?;
+        let field = AmlValue::BufferField {
+            buffer,
+            bit_offset,
+            bit_length,
+        };
+        self.declare(&name, field, f)?;
+        Ok(Flow::Next)
+    }
+
+    /// `Stall` and `Sleep`.
+    fn delay_term(&mut self, op: u8, s: &mut Stream, f: &mut Frame) -> Result<Flow, AmlError> {
+        s.bytes(2)?;
+        let amount = self.integer_arg(s, f)?;
+        if op == STALL_OP {
+            time::sleep_for(Duration::from_micros(amount.min(100)));
+        } else {
+            time::sleep_for(Duration::from_millis(amount));
+        }
+        Ok(Flow::Next)
+    }
+
+    /// `Signal`, `Reset` and `Release`. Only one method runs at a time, so mutexes and events
+    /// are no-ops.
+    fn sync_term(&mut self, _op: u8, s: &mut Stream, f: &mut Frame) -> Result<Flow, AmlError> {
+        s.bytes(2)?;
+        self.super_name(s, f)?;
+        Ok(Flow::Next)
+    }
+
+    fn fatal_term(&mut self, _op: u8, s: &mut Stream, f: &mut Frame) -> Result<Flow, AmlError> {
+        s.bytes(2)?;
+        let kind = s.byte()?;
+        let code = s.u32()?;
+        let arg = self.integer_arg(s, f)?;
+        Err(AmlError::Fatal { kind, code, arg })
+    }
+
+    fn op_region_term(&mut self, _op: u8, s: &mut Stream, f: &mut Frame) -> Result<Flow, AmlError> {
+        s.bytes(2)?;
+        let name = s.name_string()?;
+        let space = s.byte()?;
+        let offset = self.integer_arg(s, f)?;
+        let length = self.integer_arg(s, f)?;
+        let region = OpRegion {
+            space,
+            offset,
+            length,
+            scope: f.scope.clone(),
+        };
+        self.declare(&name, AmlValue::OpRegion(region), f)?;
+        Ok(Flow::Next)
+    }
+
+    /// `Field`, `IndexField` and `BankField`.
+    fn field_term(&mut self, op: u8, s: &mut Stream, f: &mut Frame) -> Result<Flow, AmlError> {
+        s.bytes(2)?;
+        let end = s.pkg_end()?;
+        let mut body = s.take_until(end);
+        let first = body.name_string()?;
+        let first = self.search(&first, &f.scope)?;
+        let kind = match op {
+            FIELD_OP => FieldKind::Region(first),
+            INDEX_FIELD_OP => {
+                let data = body.name_string()?;
+                let data = self.search(&data, &f.scope)?;
+                FieldKind::Index { index: first, data }
+            }
+            _ => {
+                let bank = body.name_string()?;
+                let bank = self.search(&bank, &f.scope)?;
+                let value = self.integer_arg(&mut body, f)?;
+                FieldKind::Bank {
+                    region: first,
+                    bank,
+                    value,
+                }
+            }
+        };
+        let flags = body.byte()?;
+        self.field_list(&mut body, kind, flags, f)?;
+        Ok(Flow::Next)
+    }
+
+    /// `Device`, `ThermalZone`, `Processor` and `PowerResource`, which are scopes too.
+    fn scoped_object_term(
+        &mut self,
+        op: u8,
+        s: &mut Stream,
+        f: &mut Frame,
+    ) -> Result<Flow, AmlError> {
+        s.bytes(2)?;
+        let end = s.pkg_end()?;
+        let mut body = s.take_until(end);
+        let name = body.name_string()?;
+        let value = scoped_object(op, &mut body)?;
+        let name = self.declare(&name, value, f)?;
+        self.in_scope(name, &mut body, f)
+    }
+
+    /// Declare the fields in a FieldList, which lays them out one after another.
+    fn field_list(
+        &mut self,
+        s: &mut Stream,
+        kind: FieldKind,
+        mut flags: u8,
+        f: &mut Frame,
+    ) -> Result<(), AmlError> {
+        let mut bit_offset = 0;
+        while !s.at_end() {
+            match s.peek()? {
+                // ReservedField
+                0x00 => {
+                    s.byte()?;
+                    bit_offset += s.pkg_length()? as u64;
+                }
+                // AccessField, and ExtendedAccessField: change the access type from here on
+                0x01 | 0x03 => {
+                    let extended = s.byte()? == 0x03;
+                    let access_type = s.byte()?;
+                    s.bytes(if extended { 2 } else { 1 })?;
+                    flags = flags & 0xF0 | access_type & 0x0F;
+                }
+                // ConnectField, for GPIO and serial bus regions
+                0x02 => {
+                    s.byte()?;
+                    if s.peek()? == BUFFER_OP {
+                        self.term_arg(s, f)?;
+                    } else {
+                        s.name_string()?;
+                    }
+                }
+                _ => {
+                    let segment = s.name_seg()?;
+                    let bit_length = s.pkg_length()? as u64;
+                    let name = NameString {
+                        segments: vec![segment],
+                        ..NameString::default()
+                    };
+                    let field = Field {
+                        kind: kind.clone(),
+                        bit_offset,
+                        bit_length,
+                        flags,
+                    };
+                    self.declare(&name, AmlValue::Field(field), f)?;
+                    bit_offset += bit_length;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// A TermArg whose value is used as data, with references read through.
+    fn operand(&mut self, s: &mut Stream, f: &mut Frame) -> Result<AmlValue, AmlError> {
+        match self.term_arg(s, f)? {
+            AmlValue::Reference(reference) => self.read_ref(&reference, f),
+            value => Ok(value),
+        }
+    }
+
+    fn integer_arg(&mut self, s: &mut Stream, f: &mut Frame) -> Result<u64, AmlError> {
+        self.operand(s, f)?.as_integer()
+    }
+
+    fn bool_value(&self, value: bool) -> AmlValue {
+        AmlValue::Integer(if value { self.ones() } else { 0 })
+    }
+
+    /// Evaluate a TermArg: a constant, a local or argument, an expression, or a name, which
+    /// calls the method there or reads the object.
+    fn term_arg(&mut self, s: &mut Stream, f: &mut Frame) -> Result<AmlValue, AmlError> {
+        let op = s.peek()?;
+        // as in `term`, one function per kind
+        let handler: TermArgHandler = if is_name_start(op) {
+            Self::name_term
+        } else {
+            s.byte()?;
+            match op {
+                ZERO_OP | ONE_OP | ONES_OP | BYTE_PREFIX | WORD_PREFIX | DWORD_PREFIX
+                | QWORD_PREFIX | STRING_PREFIX => Self::constant_arg,
+                BUFFER_OP => Self::buffer_arg,
+                PACKAGE_OP | VAR_PACKAGE_OP => Self::package_arg,
+                LOCAL0_OP..=LOCAL7_OP | ARG0_OP..=ARG6_OP => Self::local_arg,
+                STORE_OP | COPY_OBJECT_OP => Self::store_arg,
+                REF_OF_OP | DEREF_OF_OP => Self::reference_arg,
+                INDEX_OP => Self::index_arg,
+                ADD_OP | SUBTRACT_OP | MULTIPLY_OP | SHIFT_LEFT_OP | SHIFT_RIGHT_OP | AND_OP
+                | NAND_OP | OR_OP | NOR_OP | XOR_OP | MOD_OP => Self::binary_arg,
+                DIVIDE_OP => Self::divide_arg,
+                NOT_OP | FIND_SET_LEFT_BIT_OP | FIND_SET_RIGHT_BIT_OP => Self::unary_arg,
+                INCREMENT_OP | DECREMENT_OP => Self::increment_arg,
+                LAND_OP | LOR_OP | LNOT_OP | LEQUAL_OP | LGREATER_OP | LLESS_OP => {
+                    Self::logical_arg
+                }
+                CONCAT_OP | CONCAT_RES_OP | TO_BUFFER_OP | TO_DECIMAL_STRING_OP
+                | TO_HEX_STRING_OP | TO_INTEGER_OP | TO_STRING_OP | MID_OP => Self::data_op,
+                SIZE_OF_OP | OBJECT_TYPE_OP => Self::inspect_arg,
+                MATCH_OP => Self::match_op,
+                EXT_OP_PREFIX => Self::ext_term_arg,
+                _ => return Err(AmlError::UnknownOpcode(u16::from(op))),
+            }
+        };
+        f.enter()?;
+        let value = handler(self, op, s, f);
+        f.leave();
+        value
+    }
+
+    /// `Zero`, `One`, `Ones`, and integer and string constants.
+    fn constant_arg(
+        &mut self,
+        op: u8,
+        s: &mut Stream,
+        _f: &mut Frame,
+    ) -> Result<AmlValue, AmlError> {
+        let value = match op {
+            ZERO_OP => AmlValue::Integer(0),
+            ONE_OP => AmlValue::Integer(1),
+            ONES_OP => AmlValue::Integer(self.ones()),
+            BYTE_PREFIX => AmlValue::Integer(u64::from(s.byte()?)),
+            WORD_PREFIX => AmlValue::Integer(u64::from(s.u16()?)),
+            DWORD_PREFIX => AmlValue::Integer(u64::from(s.u32()?)),
+            QWORD_PREFIX => AmlValue::Integer(s.u64()?),
+            _ => AmlValue::String(String::from(s.string()?)),
+        };
+        Ok(value)
+    }
+
+    fn buffer_arg(&mut self, _op: u8, s: &mut Stream, f: &mut Frame) -> Result<AmlValue, AmlError> {
+        let end = s.pkg_end()?;
+        let mut body = s.take_until(end);
+        let size = self.integer_arg(&mut body, f)? as usize;
+        if size > MAX_BUFFER_SIZE {
+            return Err(AmlError::OutOfBounds);
+        }
+        let mut bytes = body.rest().to_vec();
+        if bytes.len() < size {
+            bytes.resize(size, 0);
+        }
+        Ok(AmlValue::Buffer(bytes))
+    }
+
+    fn package_arg(&mut self, op: u8, s: &mut Stream, f: &mut Frame) -> Result<AmlValue, AmlError> {
+        let end = s.pkg_end()?;
+        let mut body = s.take_until(end);
+        let count = match op {
+            PACKAGE_OP => u64::from(body.byte()?),
+            _ => self.integer_arg(&mut body, f)?,
+        };
+        self.package(&mut body, count as usize, f)
+    }
+
+    /// `Local0` to `Local7` and `Arg0` to `Arg6`.
+    fn local_arg(&mut self, op: u8, _s: &mut Stream, f: &mut Frame) -> Result<AmlValue, AmlError> {
+        let value = match op {
+            LOCAL0_OP..=LOCAL7_OP => f.locals[usize::from(op - LOCAL0_OP)].clone(),
+            _ => f
+                .args
+                .get(usize::from(op - ARG0_OP))
+                .cloned()
+                .unwrap_or(AmlValue::Uninitialized),
+        };
+        Ok(value)
+    }
+
+    /// `Store` and `CopyObject`.
+    fn store_arg(&mut self, op: u8, s: &mut Stream, f: &mut Frame) -> Result<AmlValue, AmlError> {
+        let value = self.term_arg(s, f)?;
+        let target = self.super_name(s, f)?;
+        if op == STORE_OP {
+            self.store(&target, value.clone(), f)?;
+        } else {
+            self.copy_object(&target, value.clone(), f)?;
+        }
+        Ok(value)
+    }
+
+    /// `RefOf` and `DerefOf`.
+    fn reference_arg(
+        &mut self,
+        op: u8,
+        s: &mut Stream,
+        f: &mut Frame,
+    ) -> Result<AmlValue, AmlError> {
+        if op == REF_OF_OP {
+            return Ok(AmlValue::Reference(self.super_name(s, f)?));
+        }
+        match self.term_arg(s, f)? {
+            AmlValue::Reference(reference) => self.read_ref(&reference, f),
+            AmlValue::String(path) => {
+                let name = AmlName::from_path(&path).ok_or(AmlError::InvalidName)?;
+                self.read_name(&name, f)
+            }
+            _ => Err(AmlError::WrongType("Reference")),
+        }
+    }
+
+    fn index_arg(&mut self, _op: u8, s: &mut Stream, f: &mut Frame) -> Result<AmlValue, AmlError> {
+        let source = self.source_reference(s, f)?;
+        let index = self.integer_arg(s, f)? as usize;
+        let target = self.target(s, f)?;
+        let reference = AmlValue::Reference(Reference::Index(Box::new(source), index));
+        self.store(&target, reference.clone(), f)?;
+        Ok(reference)
+    }
+
+    /// Integer operators with two operands and a target.
+    fn binary_arg(&mut self, op: u8, s: &mut Stream, f: &mut Frame) -> Result<AmlValue, AmlError> {
+        let a = self.integer_arg(s, f)?;
+        let b = self.integer_arg(s, f)?;
+        let result = match op {
+            ADD_OP => a.wrapping_add(b),
+            SUBTRACT_OP => a.wrapping_sub(b),
+            MULTIPLY_OP => a.wrapping_mul(b),
+            SHIFT_LEFT_OP => u32::try_from(b)
+                .ok()
+                .and_then(|b| a.checked_shl(b))
+                .unwrap_or(0),
+            SHIFT_RIGHT_OP => u32::try_from(b)
+                .ok()
+                .and_then(|b| a.checked_shr(b))
+                .unwrap_or(0),
+            AND_OP => a & b,
+            NAND_OP => !(a & b),
+            OR_OP => a | b,
+            NOR_OP => !(a | b),
+            XOR_OP => a ^ b,
+            _ => a.checked_rem(b).ok_or(AmlError::DivideByZero)?,
+        };
+        self.store_result(s, f, AmlValue::Integer(self.truncate(result)))
+    }
+
+    fn divide_arg(&mut self, _op: u8, s: &mut Stream, f: &mut Frame) -> Result<AmlValue, AmlError> {
+        let dividend = self.integer_arg(s, f)?;
+        let divisor = self.integer_arg(s, f)?;
+        if divisor == 0 {
+            return Err(AmlError::DivideByZero);
+        }
+        let remainder = self.target(s, f)?;
+        self.store(&remainder, AmlValue::Integer(dividend % divisor), f)?;
+        self.store_result(s, f, AmlValue::Integer(dividend / divisor))
+    }
+
+    /// `Not`, `FindSetLeftBit` and `FindSetRightBit`.
+    fn unary_arg(&mut self, op: u8, s: &mut Stream, f: &mut Frame) -> Result<AmlValue, AmlError> {
+        let value = self.integer_arg(s, f)?;
+        let result = match op {
+            NOT_OP => self.truncate(!value),
+            _ if value == 0 => 0,
+            FIND_SET_LEFT_BIT_OP => u64::from(64 - value.leading_zeros()),
+            _ => u64::from(value.trailing_zeros() + 1),
+        };
+        self.store_result(s, f, AmlValue::Integer(result))
+    }
+
+    fn increment_arg(
+        &mut self,
+        op: u8,
+        s: &mut Stream,
+        f: &mut Frame,
+    ) -> Result<AmlValue, AmlError> {
+        let target = self.super_name(s, f)?;
+        let value = self.read_ref(&target, f)?.as_integer()?;
+        let result = match op {
+            INCREMENT_OP => value.wrapping_add(1),
+            _ => value.wrapping_sub(1),
+        };
+        let result = AmlValue::Integer(self.truncate(result));
+        self.store(&target, result.clone(), f)?;
+        Ok(result)
+    }
+
+    /// `LAnd`, `LOr`, `LNot` and the comparisons.
+    fn logical_arg(&mut self, op: u8, s: &mut Stream, f: &mut Frame) -> Result<AmlValue, AmlError> {
+        let result = match op {
+            LAND_OP | LOR_OP => {
+                let a = self.integer_arg(s, f)? != 0;
+                let b = self.integer_arg(s, f)? != 0;
+                if op == LAND_OP {
+                    a && b
+                } else {
+                    a || b
+                }
+            }
+            // LNotEqual, LLessEqual and LGreaterEqual are LNot of the opposite comparison
+            LNOT_OP => match s.peek()? {
+                LEQUAL_OP | LGREATER_OP | LLESS_OP => {
+                    let comparison = s.byte()?;
+                    !self.compare(comparison, s, f)?
+                }
+                _ => self.integer_arg(s, f)? == 0,
+            },
+            _ => self.compare(op, s, f)?,
+        };
+        Ok(self.bool_value(result))
+    }
+
+    /// `SizeOf` and `ObjectType`.
+    fn inspect_arg(&mut self, op: u8, s: &mut Stream, f: &mut Frame) -> Result<AmlValue, AmlError> {
+        let target = self.super_name(s, f)?;
+        if op == OBJECT_TYPE_OP {
+            let code = match target {
+                Reference::Name(name) => self
+                    .get(&self.follow_alias(&name))
+                    .map_or(0, AmlValue::type_code),
+                Reference::Debug => 16,
+                reference => self.read_ref(&reference, f)?.type_code(),
+            };
+            return Ok(AmlValue::Integer(code));
+        }
+        let size = match self.read_ref(&target, f)? {
+            AmlValue::Buffer(bytes) => bytes.len(),
+            AmlValue::String(string) => string.len(),
+            AmlValue::Package(elements) => elements.len(),
+            _ => return Err(AmlError::WrongType("Buffer, String or Package")),
+        };
+        Ok(AmlValue::Integer(size as u64))
+    }
+
+    /// A TermArg starting with `EXT_OP_PREFIX`, which `term_arg` has read.
+    fn ext_term_arg(
+        &mut self,
+        _op: u8,
+        s: &mut Stream,
+        f: &mut Frame,
+    ) -> Result<AmlValue, AmlError> {
+        let op = s.byte()?;
+        let handler: TermArgHandler = match op {
+            COND_REF_OF_OP => Self::cond_ref_of_arg,
+            ACQUIRE_OP | WAIT_OP => Self::wait_arg,
+            FROM_BCD_OP | TO_BCD_OP => Self::bcd_arg,
+            REVISION_OP => return Ok(AmlValue::Integer(REVISION)),
+            // in 100 ns units
+            TIMER_OP => return Ok(AmlValue::Integer(time::monotonic_ns() / 100)),
+            DEBUG_OP => return Err(AmlError::WrongType("a value, not the debug object")),
+            _ => {
+                return Err(AmlError::UnknownOpcode(
+                    u16::from(EXT_OP_PREFIX) << 8 | u16::from(op),
+                ))
+            }
+        };
+        handler(self, op, s, f)
+    }
+
+    fn cond_ref_of_arg(
+        &mut self,
+        _op: u8,
+        s: &mut Stream,
+        f: &mut Frame,
+    ) -> Result<AmlValue, AmlError> {
+        let reference = self.super_name(s, f);
+        let target = self.target(s, f)?;
+        match reference {
+            Ok(reference) => {
+                self.store(&target, AmlValue::Reference(reference), f)?;
+                Ok(self.bool_value(true))
+            }
+            Err(AmlError::NameNotFound(_)) => Ok(self.bool_value(false)),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// `Acquire` and `Wait`, which never time out.
+    fn wait_arg(&mut self, op: u8, s: &mut Stream, f: &mut Frame) -> Result<AmlValue, AmlError> {
+        self.super_name(s, f)?;
+        if op == ACQUIRE_OP {
+            s.u16()?;
+        } else {
+            self.integer_arg(s, f)?;
+        }
+        Ok(self.bool_value(false))
+    }
+
+    /// `FromBCD` and `ToBCD`.
+    fn bcd_arg(&mut self, op: u8, s: &mut Stream, f: &mut Frame) -> Result<AmlValue, AmlError> {
+        let value = self.integer_arg(s, f)?;
+        let result = if op == FROM_BCD_OP {
+            from_bcd(value)
+        } else {
+            to_bcd(value)
+        };
+        self.store_result(s, f, AmlValue::Integer(result))
+    }
+
+    /// Operators on strings and buffers, which `term_arg` has read the opcode of.
+    fn data_op(&mut self, op: u8, s: &mut Stream, f: &mut Frame) -> Result<AmlValue, AmlError> {
+        let source = self.operand(s, f)?;
+        let convert: DataHandler = match op {
+            CONCAT_OP => Self::concat,
+            CONCAT_RES_OP => Self::concat_res,
+            TO_BUFFER_OP | TO_DECIMAL_STRING_OP | TO_HEX_STRING_OP | TO_INTEGER_OP => Self::convert,
+            TO_STRING_OP => Self::to_string,
+            _ => Self::mid,
+        };
+        let result = convert(self, op, source, s, f)?;
+        self.store_result(s, f, result)
+    }
+
+    fn concat(
+        &mut self,
+        _op: u8,
+        source: AmlValue,
+        s: &mut Stream,
+        f: &mut Frame,
+    ) -> Result<AmlValue, AmlError> {
+        let other = self.operand(s, f)?;
+        let result = match source {
+            AmlValue::Integer(value) => {
+                let width = (self.integer_bits / 8) as usize;
+                let mut bytes = value.to_le_bytes()[..width].to_vec();
+                bytes.extend_from_slice(&other.as_integer()?.to_le_bytes()[..width]);
+                AmlValue::Buffer(bytes)
+            }
+            AmlValue::String(mut string) => {
+                string.push_str(&other.as_string()?);
+                AmlValue::String(string)
+            }
+            source => {
+                let mut bytes = source.as_buffer()?;
+                bytes.extend_from_slice(&other.as_buffer()?);
+                AmlValue::Buffer(bytes)
+            }
+        };
+        Ok(result)
+    }
+
+    /// Join two resource templates, each but the result without its end tag.
+    fn concat_res(
+        &mut self,
+        _op: u8,
+        source: AmlValue,
+        s: &mut Stream,
+        f: &mut Frame,
+    ) -> Result<AmlValue, AmlError> {
+        let without_end_tag = |mut bytes: Vec<u8>| {
+            if bytes.len() >= 2 && bytes[bytes.len() - 2] == 0x79 {
+                bytes.truncate(bytes.len() - 2);
+            }
+            bytes
+        };
+        let mut bytes = without_end_tag(source.as_buffer()?);
+        bytes.extend(without_end_tag(self.operand(s, f)?.as_buffer()?));
+        bytes.extend_from_slice(&[0x79, 0]);
+        Ok(AmlValue::Buffer(bytes))
+    }
+
+    /// `ToBuffer`, `ToDecimalString`, `ToHexString` and `ToInteger`.
+    fn convert(
+        &mut self,
+        op: u8,
+        source: AmlValue,
+        _s: &mut Stream,
+        _f: &mut Frame,
+    ) -> Result<AmlValue, AmlError> {
+        let result = match op {
+            TO_BUFFER_OP => match source {
+                AmlValue::String(string) => {
+                    let mut bytes = string.into_bytes();
+                    bytes.push(0);
+                    AmlValue::Buffer(bytes)
+                }
+                source => AmlValue::Buffer(source.as_buffer()?),
+            },
+            TO_DECIMAL_STRING_OP => match source {
+                AmlValue::Integer(value) => AmlValue::String(alloc::format!("{}", value)),
+                AmlValue::Buffer(bytes) => {
+                    let digits: Vec<String> = bytes
+                        .iter()
+                        .map(|byte| alloc::format!("{}", byte))
+                        .collect();
+                    AmlValue::String(digits.join(","))
+                }
+                source => AmlValue::String(source.as_string()?),
+            },
+            TO_HEX_STRING_OP => AmlValue::String(source.as_string()?),
+            _ => match source {
+                AmlValue::String(string) => {
+                    let value = match string
+                        .strip_prefix("0x")
+                        .or_else(|| string.strip_prefix("0X"))
+                    {
+                        Some(hex) => u64::from_str_radix(hex, 16),
+                        None => string.parse(),
+                    };
+                    AmlValue::Integer(value.map_err(|_| AmlError::InvalidString)?)
+                }
+                source => AmlValue::Integer(source.as_integer()?),
+            },
+        };
+        Ok(result)
+    }
+
+    fn to_string(
+        &mut self,
+        _op: u8,
+        source: AmlValue,
+        s: &mut Stream,
+        f: &mut Frame,
+    ) -> Result<AmlValue, AmlError> {
+        let bytes = source.as_buffer()?;
+        let length = self.integer_arg(s, f)?;
+        let end = bytes
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(bytes.len());
+        let end = end.min(usize::try_from(length).unwrap_or(usize::MAX));
+        let string = core::str::from_utf8(&bytes[..end]).map_err(|_| AmlError::InvalidString)?;
+        Ok(AmlValue::String(String::from(string)))
+    }
+
+    fn mid(
+        &mut self,
+        _op: u8,
+        source: AmlValue,
+        s: &mut Stream,
+        f: &mut Frame,
+    ) -> Result<AmlValue, AmlError> {
+        let index = self.integer_arg(s, f)? as usize;
+        let length = self.integer_arg(s, f)? as usize;
+        let result = match source {
+            AmlValue::String(string) => {
+                let start = index.min(string.len());
+                let end = start.saturating_add(length).min(string.len());
+                AmlValue::String(String::from(string.get(start..end).unwrap_or("")))
+            }
+            source => {
+                let bytes = source.as_buffer()?;
+                let start = index.min(bytes.len());
+                let end = start.saturating_add(length).min(bytes.len());
+                AmlValue::Buffer(bytes[start..end].to_vec())
+            }
+        };
+        Ok(result)
+    }
+
+    /// Store an operator's result to the Target operand that follows, and return it.
+    fn store_result(
+        &mut self,
+        s: &mut Stream,
+        f: &mut Frame,
+        result: AmlValue,
+    ) -> Result<AmlValue, AmlError> {
+        let target = self.target(s, f)?;
+        self.store(&target, result.clone(), f)?;
+        Ok(result)
+    }
+
+    /// Compare two operands for `LEqual`, `LGreater` or `LLess`, going by the first one's type.
+    fn compare(&mut self, op: u8, s: &mut Stream, f: &mut Frame) -> Result<bool, AmlError> {
+        let a = self.operand(s, f)?;
+        let b = self.operand(s, f)?;
+        let ordering = match a {
+            AmlValue::Integer(a) => a.cmp(&b.as_integer()?),
+            AmlValue::String(a) => a.as_bytes().cmp(b.as_string()?.as_bytes()),
+            a => a.as_buffer()?.cmp(&b.as_buffer()?),
+        };
+        Ok(match op {
+            LEQUAL_OP => ordering == Ordering::Equal,
+            LGREATER_OP => ordering == Ordering::Greater,
+            _ => ordering == Ordering::Less,
+        })
+    }
+
+    /// `Match`: the index of the first integer in a package, from a start index, that passes
+    /// two comparisons, or Ones.
+    fn match_op(&mut self, _op: u8, s: &mut Stream, f: &mut Frame) -> Result<AmlValue, AmlError> {
+        let package = self.operand(s, f)?;
+        let op1 = s.byte()?;
+        let operand1 = self.integer_arg(s, f)?;
+        let op2 = s.byte()?;
+        let operand2 = self.integer_arg(s, f)?;
+        let start = self.integer_arg(s, f)? as usize;
+
+        let passes = |op: u8, element: u64, operand: u64| match op {
+            0 => true,
+            1 => element == operand,
+            2 => element <= operand,
+            3 => element < operand,
+            4 => element >= operand,
+            _ => element > operand,
+        };
+        let found = package
+            .as_package()?
+            .iter()
+            .enumerate()
+            .skip(start)
+            .find(|(_, element)| match element.as_integer() {
+                Ok(element) => passes(op1, element, operand1) && passes(op2, element, operand2),
+                Err(_) => false,
+            });
+        Ok(AmlValue::Integer(
+            found.map_or(self.ones(), |(index, _)| index as u64),
+        ))
+    }
+
+    /// The elements of a package, padded with uninitialized ones up to `count`. Names in a
+    /// package aren't evaluated; they're left as references to the objects.
+    fn package(
+        &mut self,
+        s: &mut Stream,
+        count: usize,
+        f: &mut Frame,
+    ) -> Result<AmlValue, AmlError> {
+        let mut elements = Vec::new();
+        while !s.at_end() {
+            let element = if is_name_start(s.peek()?) {
+                let name = s.name_string()?;
+                // firmware refers to objects declared further on in the table
+                let name = self
+                    .search(&name, &f.scope)
+                    .or_else(|_| name.resolve(&f.scope))?;
+                AmlValue::Reference(Reference::Name(name))
+            } else {
+                self.term_arg(s, f)?
+            };
+            elements.push(element);
+        }
+        if elements.len() < count {
+            elements.resize(count, AmlValue::Uninitialized);
+        }
+        Ok(AmlValue::Package(elements))
+    }
+
+    /// A name in a TermArg: call the method there with the arguments that follow, or read the
+    /// object.
+    fn name_term(&mut self, _op: u8, s: &mut Stream, f: &mut Frame) -> Result<AmlValue, AmlError> {
+        let name = s.name_string()?;
+        let name = self.search(&name, &f.scope)?;
+        let name = self.follow_alias(&name);
+        match self.get(&name) {
+            Some(AmlValue::Method(method)) => {
+                let method = method.clone();
+                let args = (0..method.arg_count)
+                    .map(|_| self.term_arg(s, f))
+                    .collect::<Result<Vec<_>, _>>()?;
+                self.call(&name, &method, args, f.depth)
+            }
+            _ => self.read_name(&name, f),
+        }
+    }
+
+    /// A SuperName: somewhere to store to or take a reference of.
+    fn super_name(&mut self, s: &mut Stream, f: &mut Frame) -> Result<Reference, AmlError> {
+        let op = s.peek()?;
+        match op {
+            LOCAL0_OP..=LOCAL7_OP => {
+                s.byte()?;
+                Ok(Reference::Local(usize::from(op - LOCAL0_OP)))
+            }
+            ARG0_OP..=ARG6_OP => {
+                s.byte()?;
+                Ok(Reference::Arg(usize::from(op - ARG0_OP)))
+            }
+            EXT_OP_PREFIX if s.peek_at(1) == Some(DEBUG_OP) => {
+                s.bytes(2)?;
+                Ok(Reference::Debug)
+            }
+            REF_OF_OP | DEREF_OF_OP | INDEX_OP => match self.term_arg(s, f)? {
+                AmlValue::Reference(reference) => Ok(reference),
+                _ => Err(AmlError::WrongType("Reference")),
+            },
+            op if is_name_start(op) => {
+                let name = s.name_string()?;
+                Ok(Reference::Name(self.search(&name, &f.scope)?))
+            }
+            _ => Err(AmlError::UnknownOpcode(u16::from(op))),
+        }
+    }
+
+    /// A Target: a SuperName, or the null name for nowhere.
+    fn target(&mut self, s: &mut Stream, f: &mut Frame) -> Result<Reference, AmlError> {
+        if s.peek()? == ZERO_OP {
+            s.byte()?;
+            return Ok(Reference::Null);
+        }
+        self.super_name(s, f)
+    }
+
+    /// The buffer or package operand of `Index` and `CreateField`. Names, locals and arguments
+    /// are referred to rather than copied, so changes through the result reach them.
+    fn source_reference(&mut self, s: &mut Stream, f: &mut Frame) -> Result<Reference, AmlError> {
+        let op = s.peek()?;
+        match op {
+            LOCAL0_OP..=LOCAL7_OP | ARG0_OP..=ARG6_OP => self.super_name(s, f),
+            op if is_name_start(op) => {
+                let name = s.name_string()?;
+                let name = self.follow_alias(&self.search(&name, &f.scope)?);
+                match self.get(&name) {
+                    Some(AmlValue::Buffer(_) | AmlValue::Package(_) | AmlValue::String(_)) => {
+                        Ok(Reference::Name(name))
+                    }
+                    _ => Ok(Reference::Value(Box::new(self.read_name(&name, f)?))),
+                }
+            }
+            _ => match self.term_arg(s, f)? {
+                AmlValue::Reference(reference) => Ok(reference),
+                value => Ok(Reference::Value(Box::new(value))),
+            },
+        }
+    }
+
+    /// Read the object at `name`: run methods without arguments, and read fields.
+    fn read_name(&mut self, name: &AmlName, f: &mut Frame) -> Result<AmlValue, AmlError> {
+        let name = self.follow_alias(name);
+        let object = self
+            .get(&name)
+            .ok_or_else(|| AmlError::NameNotFound(name.clone()))?
+            .clone();
+        match object {
+            AmlValue::Method(method) => self.call(&name, &method, Vec::new(), f.depth),
+            AmlValue::Field(field) => self.read_field(&field, f.depth),
+            AmlValue::BufferField {
+                buffer,
+                bit_offset,
+                bit_length,
+            } => {
+                let bytes = self.read_ref(&buffer, f)?.as_buffer()?;
+                let bits = region::get_bits(&bytes, bit_offset, bit_length)?;
+                Ok(self.bits_value(bits, bit_length))
+            }
+            object => Ok(object),
+        }
+    }
+
+    fn read_ref(&mut self, reference: &Reference, f: &mut Frame) -> Result<AmlValue, AmlError> {
+        match reference {
+            Reference::Name(name) => self.read_name(name, f),
+            Reference::Local(i) => Ok(f.locals[*i].clone()),
+            Reference::Arg(i) => Ok(f.args.get(*i).cloned().unwrap_or(AmlValue::Uninitialized)),
+            Reference::Value(value) => Ok((**value).clone()),
+            Reference::Index(base, index) => {
+                let base = match self.read_ref(base, f)? {
+                    AmlValue::Reference(inner) => self.read_ref(&inner, f)?,
+                    base => base,
+                };
+                match base {
+                    AmlValue::Package(elements) => {
+                        elements.get(*index).cloned().ok_or(AmlError::OutOfBounds)
+                    }
+                    AmlValue::Buffer(bytes) => bytes
+                        .get(*index)
+                        .map(|&byte| AmlValue::Integer(u64::from(byte)))
+                        .ok_or(AmlError::OutOfBounds),
+                    AmlValue::String(string) => string
+                        .as_bytes()
+                        .get(*index)
+                        .map(|&byte| AmlValue::Integer(u64::from(byte)))
+                        .ok_or(AmlError::OutOfBounds),
+                    _ => Err(AmlError::WrongType("Buffer, String or Package")),
+                }
+            }
+            Reference::Debug | Reference::Null => Err(AmlError::WrongType("readable object")),
+        }
+    }
+
+    /// A local or argument holding a reference stands for what it refers to as the base of
+    /// an `Index`.
+    fn deref_base(&self, reference: &Reference, f: &Frame) -> Reference {
+        let value = match reference {
+            Reference::Local(i) => f.locals.get(*i),
+            Reference::Arg(i) => f.args.get(*i),
+            _ => None,
+        };
+        match value {
+            Some(AmlValue::Reference(inner)) => inner.clone(),
+            _ => reference.clone(),
+        }
+    }
+
+    /// Run `op` on the object `reference` refers to, in place.
+    fn with_ref_mut(
+        &mut self,
+        reference: &Reference,
+        f: &mut Frame,
+        op: &mut dyn FnMut(&mut AmlValue) -> Result<(), AmlError>,
+    ) -> Result<(), AmlError> {
+        match reference {
+            Reference::Name(name) => {
+                let name = self.follow_alias(name);
+                let object = self
+                    .get_mut(&name)
+                    .ok_or_else(|| AmlError::NameNotFound(name.clone()))?;
+                op(object)
+            }
+            Reference::Local(i) => op(&mut f.locals[*i]),
+            Reference::Arg(i) => op(f.args.get_mut(*i).ok_or(AmlError::OutOfBounds)?),
+            // a temporary: the change has nowhere to go
+            Reference::Value(value) => op(&mut (**value).clone()),
+            Reference::Index(base, index) => {
+                let base = self.deref_base(base, f);
+                let index = *index;
+                self.with_ref_mut(&base, f, &mut |object| match object {
+                    AmlValue::Package(elements) => {
+                        op(elements.get_mut(index).ok_or(AmlError::OutOfBounds)?)
+                    }
+                    _ => Err(AmlError::WrongType("Package")),
+                })
+            }
+            Reference::Debug | Reference::Null => Err(AmlError::WrongType("writable object")),
+        }
+    }
+
+    /// `Store`: write `value` to `target`, converting it to the type of a named integer, string
+    /// or buffer already there.
+    fn store(
+        &mut self,
+        target: &Reference,
+        value: AmlValue,
+        f: &mut Frame,
+    ) -> Result<(), AmlError> {
+        let value = match (target, value) {
+            (Reference::Name(_) | Reference::Index(..), AmlValue::Reference(reference)) => {
+                self.read_ref(&reference, f)?
+            }
+            (_, value) => value,
+        };
+        match target {
+            Reference::Null | Reference::Value(_) => Ok(()),
+            Reference::Debug => {
+                serial_println!("AML debug: {:?}", value);
+                Ok(())
+            }
+            Reference::Local(i) => {
+                f.locals[*i] = value;
+                Ok(())
+            }
+            Reference::Arg(i) => match f.args.get(*i) {
+                // an argument passed by reference
+                Some(AmlValue::Reference(reference)) => {
+                    let reference = reference.clone();
+                    self.store(&reference, value, f)
+                }
+                Some(_) => {
+                    f.args[*i] = value;
+                    Ok(())
+                }
+                None => Err(AmlError::OutOfBounds),
+            },
+            Reference::Name(name) => self.store_name(name, value, f),
+            Reference::Index(base, index) => {
+                let base = self.deref_base(base, f);
+                let index = *index;
+                let mut value = Some(value);
+                self.with_ref_mut(&base, f, &mut |object| {
+                    let value = value.take().ok_or(AmlError::OutOfBounds)?;
+                    set_element(object, index, value)
+                })
+            }
+        }
+    }
+
+    fn store_name(
+        &mut self,
+        name: &AmlName,
+        value: AmlValue,
+        f: &mut Frame,
+    ) -> Result<(), AmlError> {
+        let name = self.follow_alias(name);
+        let object = self
+            .get(&name)
+            .ok_or_else(|| AmlError::NameNotFound(name.clone()))?
+            .clone();
+        let value = match object {
+            AmlValue::Field(field) => return self.write_field(&field, &value, f.depth),
+            AmlValue::BufferField {
+                buffer,
+                bit_offset,
+                bit_length,
+            } => {
+                let bits = value_bits(&value, bit_length)?;
+                let buffer = self.deref_base(&buffer, f);
+                return self.with_ref_mut(&buffer, f, &mut |object| match object {
+                    AmlValue::Buffer(bytes) => {
+                        region::set_bits(bytes, bit_offset, bit_length, &bits)
+                    }
+                    _ => Err(AmlError::WrongType("Buffer")),
+                });
+            }
+            AmlValue::Integer(_) => AmlValue::Integer(self.truncate(value.as_integer()?)),
+            AmlValue::String(_) => AmlValue::String(value.as_string()?),
+            AmlValue::Buffer(old) => {
+                let mut bytes = value.as_buffer()?;
+                bytes.resize(old.len(), 0);
+                AmlValue::Buffer(bytes)
+            }
+            _ => value,
+        };
+        self.set(&name, value)
+    }
+
+    /// `CopyObject`: replace what's at `target` with `value`, without conversion.
+    fn copy_object(
+        &mut self,
+        target: &Reference,
+        value: AmlValue,
+        f: &mut Frame,
+    ) -> Result<(), AmlError> {
+        match target {
+            Reference::Name(name) => {
+                let name = self.follow_alias(name);
+                self.set(&name, value)
+            }
+            Reference::Local(i) => {
+                f.locals[*i] = value;
+                Ok(())
+            }
+            target => self.store(target, value, f),
+        }
+    }
+
+    /// Bits of a field as an integer if they fit in one, and as a buffer otherwise.
+    fn bits_value(&self, bits: Vec<u8>, bit_length: u64) -> AmlValue {
+        if bit_length <= u64::from(self.integer_bits) {
+            AmlValue::Buffer(bits)
+                .as_integer()
+                .map_or(AmlValue::Uninitialized, AmlValue::Integer)
+        } else {
+            AmlValue::Buffer(bits)
+        }
+    }
+
+    fn read_field(&mut self, field: &Field, depth: usize) -> Result<AmlValue, AmlError> {
+        let width = region::access_width(field.flags, field.bit_offset, field.bit_length);
+        let bits = region::read_bits(
+            field.bit_offset,
+            field.bit_length,
+            width,
+            &mut |offset, _| self.field_access(&field.kind, offset, width, None, depth),
+        )?;
+        Ok(self.bits_value(bits, field.bit_length))
+    }
+
+    fn write_field(
+        &mut self,
+        field: &Field,
+        value: &AmlValue,
+        depth: usize,
+    ) -> Result<(), AmlError> {
+        let width = region::access_width(field.flags, field.bit_offset, field.bit_length);
+        let bits = value_bits(value, field.bit_length)?;
+        region::write_bits(
+            field.bit_offset,
+            field.bit_length,
+            width,
+            field.flags,
+            &bits,
+            &mut |offset, write| self.field_access(&field.kind, offset, width, write, depth),
+        )
+    }
+
+    /// Read, or write if `write` is given, the `width`-bit unit at byte `offset` of a field's
+    /// region.
+    fn field_access(
+        &mut self,
+        kind: &FieldKind,
+        offset: u64,
+        width: u32,
+        write: Option<u64>,
+        depth: usize,
+    ) -> Result<u64, AmlError> {
+        let mut frame = Frame::new(AmlName::root(), Vec::new(), depth);
+        let region = match kind {
+            FieldKind::Region(region) => region,
+            FieldKind::Index { index, data } => {
+                self.store_name(index, AmlValue::Integer(offset), &mut frame)?;
+                return match write {
+                    Some(value) => self
+                        .store_name(data, AmlValue::Integer(value), &mut frame)
+                        .map(|()| 0),
+                    None => self.read_name(data, &mut frame)?.as_integer(),
+                };
+            }
+            FieldKind::Bank {
+                region,
+                bank,
+                value,
+            } => {
+                self.store_name(bank, AmlValue::Integer(*value), &mut frame)?;
+                region
+            }
+        };
+        let region = match self.get(region) {
+            Some(AmlValue::OpRegion(region)) => region.clone(),
+            _ => return Err(AmlError::WrongType("OperationRegion")),
+        };
+        let pci = match region.space {
+            region::PCI_CONFIG => Some(self.pci_address(&region.scope, depth)?),
+            _ => None,
+        };
+        match write {
+            Some(value) => region::write(&region, pci, offset, width, value).map(|()| 0),
+            None => region::read(&region, pci, offset, width),
+        }
+    }
+
+    /// The PCI function whose config space a region declared in `scope` is: the `_ADR` of the
+    /// nearest device, on the bus the enclosing root bridge's `_BBN` gives.
+    fn pci_address(&mut self, scope: &AmlName, depth: usize) -> Result<PciAddress, AmlError> {
+        let mut address = None;
+        let mut current = Some(scope.clone());
+        while let Some(name) = current {
+            if let Some(AmlValue::Device) = self.get(&name) {
+                if address.is_none() {
+                    if let Some(adr) = self.evaluate_child(&name, b"_ADR", depth)? {
+                        address = Some(adr.as_integer()?);
+                    }
+                }
+                let ids = self.hardware_ids_at(&name, depth)?;
+                if ids.iter().any(|id| id == "PNP0A03" || id == "PNP0A08") {
+                    let bus = match self.evaluate_child(&name, b"_BBN", depth)? {
+                        Some(bbn) => bbn.as_integer()?,
+                        None => 0,
+                    };
+                    let address = address.unwrap_or(0);
+                    return Ok(PciAddress {
+                        bus: bus as u8,
+                        device: (address >> 16) as u8,
+                        function: address as u8,
+                    });
+                }
+            }
+            current = name.parent();
+        }
+        Err(AmlError::Unsupported(
+            "PCI config region outside a PCI root bridge",
+        ))
+    }
+}
+
+/// `value`'s bits, little-endian, padded or cut to `bit_length`.
+fn value_bits(value: &AmlValue, bit_length: u64) -> Result<Vec<u8>, AmlError> {
+    let mut bits = value.as_buffer()?;
+    bits.resize(bit_length.div_ceil(8) as usize, 0);
+    Ok(bits)
+}
+
+fn set_element(object: &mut AmlValue, index: usize, value: AmlValue) -> Result<(), AmlError> {
+    match object {
+        AmlValue::Package(elements) => {
+            *elements.get_mut(index).ok_or(AmlError::OutOfBounds)? = value;
+        }
+        AmlValue::Buffer(bytes) => {
+            *bytes.get_mut(index).ok_or(AmlError::OutOfBounds)? = value.as_integer()? as u8;
+        }
+        _ => return Err(AmlError::WrongType("Buffer or Package")),
+    }
+    Ok(())
+}
+
+/// The object a `Device`, `ThermalZone`, `Processor` or `PowerResource` declares, from the
+/// fields after its name.
+fn scoped_object(op: u8, body: &mut Stream) -> Result<AmlValue, AmlError> {
+    let value = match op {
+        DEVICE_OP => AmlValue::Device,
+        THERMAL_ZONE_OP => AmlValue::ThermalZone,
+        PROCESSOR_OP => AmlValue::Processor {
+            id: body.byte()?,
+            pblk_address: body.u32()?,
+            pblk_length: body.byte()?,
+        },
+        _ => AmlValue::PowerResource {
+            system_level: body.byte()?,
+            resource_order: body.u16()?,
+        },
+    };
+    Ok(value)
+}
+
+fn from_bcd(mut value: u64) -> u64 {
+    let (mut result, mut scale) = (0, 1);
+    while value != 0 {
+        result += (value & 0xF) * scale;
+        scale *= 10;
+        value >>= 4;
+    }
+    result
+}
+
+fn to_bcd(mut value: u64) -> u64 {
+    let (mut result, mut shift) = (0, 0);
+    while value != 0 && shift < 64 {
+        result |= (value % 10) << shift;
+        shift += 4;
+        value /= 10;
+    }
+    result
+}
@@ -0,0 +1,268 @@
+//! # AML
+//! An interpreter for the ACPI Machine Language in the DSDT and SSDTs. `init` loads the tables
+//! into one namespace, and `evaluate` reads the objects in it and runs its methods: `\_S5` for
+//! the sleep type to power off with, `_PRT` for PCI interrupt routing, `_HID` and `_STA` to find
+//! devices, and so on.
+//!
+//! Methods run one at a time, with the namespace locked, so AML mutexes and events do nothing.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use crate::serial_println;
+
+use super::fadt::{Fadt, FADT};
+use super::sdt::Sdt;
+use super::{find_sdt, get_sdt};
+
+mod interp;
+mod name;
+mod namespace;
+mod region;
+mod stream;
+mod value;
+
+pub use self::name::{AmlName, NameSeg};
+pub use self::namespace::Namespace;
+pub use self::value::{AmlValue, Field, FieldKind, Method, MethodCode, OpRegion, Reference};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AmlError {
+    UnexpectedEnd,
+    InvalidName,
+    InvalidString,
+    UnknownOpcode(u16),
+    NameNotFound(AmlName),
+    AlreadyExists(AmlName),
+    /// An operand wasn't of, or convertible to, the type named.
+    WrongType(&'static str),
+    OutOfBounds,
+    DivideByZero,
+    /// A field in an operation region of this address space, which we can't access.
+    UnsupportedRegion(u8),
+    Unsupported(&'static str),
+    /// Terms nested, or methods called each other, too deep.
+    TooDeep,
+    /// A `While` went on too long.
+    LoopLimit,
+    /// The AML executed `Fatal`.
+    Fatal {
+        kind: u8,
+        code: u32,
+        arg: u64,
+    },
+    /// No tables have been loaded.
+    NotLoaded,
+}
+
+pub static NAMESPACE: Mutex<Option<Namespace>> = Mutex::new(None);
+
+/// Load the DSDT the FADT points to and every SSDT into the namespace. Needs the FADT.
+pub fn init() {
+    let mut namespace = Namespace::new();
+    let dsdt = FADT.get().and_then(Fadt::dsdt_address);
+    match dsdt.map(|address| get_sdt(address as usize)) {
+        Some(Ok(dsdt)) => {
+            // integers are 32 bits wide in ACPI 1.0 tables
+            if dsdt.revision < 2 {
+                namespace.integer_bits = 32;
+            }
+            load(&mut namespace, dsdt);
+        }
+        Some(Err(error)) => serial_println!("  AML: bad DSDT: {:?}", error),
+        None => serial_println!("  AML: no DSDT"),
+    }
+    for ssdt in find_sdt("SSDT") {
+        load(&mut namespace, ssdt);
+    }
+    serial_println!("  AML: {} objects", namespace.iter().count());
+    *NAMESPACE.lock() = Some(namespace);
+}
+
+fn load(namespace: &mut Namespace, sdt: &'static Sdt) {
+    if let Err(error) = namespace.load(sdt.data()) {
+        // what was declared before the error stays
+        let signature = core::str::from_utf8(&sdt.signature).unwrap_or("????");
+        serial_println!("  {}: AML error {:?}", signature, error);
+    }
+}
+
+/// Evaluate the object at `path`, like `\_SB.PCI0._PRT`: run it with `args` if it's a method,
+/// and read it otherwise.
+pub fn evaluate(path: &str, args: Vec<AmlValue>) -> Result<AmlValue, AmlError> {
+    let name = AmlName::from_path(path).ok_or(AmlError::InvalidName)?;
+    let mut namespace = NAMESPACE.lock();
+    namespace
+        .as_mut()
+        .ok_or(AmlError::NotLoaded)?
+        .evaluate(&name, args)
+}
+
+/// Every device with `id` as its `_HID` or one of its `_CID`s, like `PNP0A03` for PCI root
+/// bridges.
+pub fn find_devices(id: &str) -> Vec<AmlName> {
+    let mut namespace = NAMESPACE.lock();
+    let namespace = match namespace.as_mut() {
+        Some(namespace) => namespace,
+        None => return Vec::new(),
+    };
+    let devices: Vec<AmlName> = namespace
+        .iter()
+        .filter(|(_, object)| matches!(object, AmlValue::Device))
+        .map(|(name, _)| name.clone())
+        .collect();
+    devices
+        .into_iter()
+        .filter(|device| {
+            namespace
+                .hardware_ids(device)
+                .map_or(false, |ids| ids.iter().any(|device_id| device_id == id))
+        })
+        .collect()
+}
+
+/// Decode a compressed EISA ID, e.g. 0x030AD041 to `PNP0A03`.
+pub fn eisa_id_to_string(id: u32) -> String {
+    let id = id.swap_bytes();
+    let letter = |shift: u32| char::from(((id >> shift) & 0x1F) as u8 + b'@');
+    alloc::format!(
+        "{}{}{}{:04X}",
+        letter(26),
+        letter(21),
+        letter(16),
+        id & 0xFFFF
+    )
+}
+
+/// Hand-assembled AML:
+///
+/// ```text
+/// Method (ADD2, 2) { Return (Arg0 + Arg1) }
+/// Name (PKG0, Package () { 1, 2, 3, 4 })
+/// Method (SUMP, 1) {
+///     Local0 = 0
+///     Local1 = 0
+///     While (Local1 < Arg0) { Local0 += DerefOf (PKG0[Local1]); Local1++ }
+///     Return (Local0)
+/// }
+/// Name (BUF0, Buffer (4) { 0x10, 0x20, 0x30, 0x40 })
+/// CreateWordField (BUF0, 1, WRD0)
+/// Method (SETW, 1) { WRD0 = Arg0 }
+/// ```
+#[cfg(test)]
+static TEST_AML: [u8; 97] = [
+    0x14, 0x0B, b'A', b'D', b'D', b'2', 0x02, 0xA4, 0x72, 0x68, 0x69, 0x00, //
+    0x08, b'P', b'K', b'G', b'0', 0x12, 0x09, 0x04, 0x01, 0x0A, 0x02, 0x0A, 0x03, 0x0A,
+    0x04, //
+    0x14, 0x20, b'S', b'U', b'M', b'P', 0x01, 0x70, 0x00, 0x60, 0x70, 0x00, 0x61, 0xA2, 0x11, 0x95,
+    0x61, 0x68, 0x72, 0x60, 0x83, 0x88, b'P', b'K', b'G', b'0', 0x61, 0x00, 0x60, 0x75, 0x61, 0xA4,
+    0x60, //
+    0x08, b'B', b'U', b'F', b'0', 0x11, 0x07, 0x0A, 0x04, 0x10, 0x20, 0x30, 0x40, //
+    0x8B, b'B', b'U', b'F', b'0', 0x0A, 0x01, b'W', b'R', b'D', b'0', //
+    0x14, 0x0C, b'S', b'E', b'T', b'W', 0x01, 0x70, 0x68, b'W', b'R', b'D', b'0',
+];
+
+#[test_case]
+fn test_methods() {
+    use alloc::vec;
+
+    let path = |path| AmlName::from_path(path).unwrap();
+    let mut namespace = Namespace::new();
+    namespace.load(&TEST_AML).expect("test AML didn't load");
+    let args = vec![AmlValue::Integer(2), AmlValue::Integer(3)];
+    let sum = namespace.evaluate(&path("\\ADD2"), args).unwrap();
+    assert_eq!(sum.as_integer(), Ok(5));
+    let sum = namespace.evaluate(&path("\\SUMP"), vec![AmlValue::Integer(3)]);
+    assert_eq!(sum.unwrap().as_integer(), Ok(6));
+
+    let word = namespace.evaluate(&path("\\WRD0"), Vec::new()).unwrap();
+    assert_eq!(word.as_integer(), Ok(0x3020));
+    let set = namespace.evaluate(&path("\\SETW"), vec![AmlValue::Integer(0xABCD)]);
+    assert!(set.is_ok());
+    let buffer = namespace.evaluate(&path("\\BUF0"), Vec::new()).unwrap();
+    assert_eq!(buffer.as_buffer(), Ok(vec![0x10, 0xCD, 0xAB, 0x40]));
+}
+
+#[test_case]
+fn test_nesting_limit() {
+    let path = |path| AmlName::from_path(path).unwrap();
+    let mut namespace = Namespace::new();
+    // Method (RECR, 1) { Return (RECR (Arg0)) }
+    static RECURSE: [u8; 13] = [
+        0x14, 0x0C, b'R', b'E', b'C', b'R', 0x01, 0xA4, b'R', b'E', b'C', b'R', 0x68,
+    ];
+    namespace.load(&RECURSE).unwrap();
+    let result = namespace.evaluate(&path("\\RECR"), alloc::vec![AmlValue::Integer(0)]);
+    assert!(matches!(result, Err(AmlError::TooDeep)), "{:?}", result);
+
+    // Method (NEST) { Return (Add (Add (... One ...), One)) }, 40 deep
+    let mut code = alloc::vec![0x14, 0x41, 0x08, b'N', b'E', b'S', b'T', 0x00, 0xA4];
+    code.extend([0x72; 40]);
+    code.push(0x01);
+    for _ in 0..40 {
+        code.extend([0x01, 0x00]);
+    }
+    namespace.load(code.leak()).unwrap();
+    let result = namespace.evaluate(&path("\\NEST"), Vec::new());
+    assert!(matches!(result, Err(AmlError::TooDeep)), "{:?}", result);
+}
+
+#[test_case]
+fn test_shift_count() {
+    let path = |path| AmlName::from_path(path).unwrap();
+    let mut namespace = Namespace::new();
+    // Method (SHFT) { Return (ShiftLeft (One, 0x100000001)) }
+    static SHIFT: [u8; 20] = [
+        0x14, 0x13, b'S', b'H', b'F', b'T', 0x00, 0xA4, 0x79, 0x01, 0x0E, 0x01, 0x00, 0x00, 0x00,
+        0x01, 0x00, 0x00, 0x00, 0x00,
+    ];
+    namespace.load(&SHIFT).unwrap();
+    let result = namespace.evaluate(&path("\\SHFT"), Vec::new()).unwrap();
+    assert_eq!(result.as_integer(), Ok(0));
+}
+
+#[test_case]
+fn test_qemu_tables() {
+    use alloc::vec;
+
+    assert_eq!(eisa_id_to_string(0x030AD041), "PNP0A03");
+
+    let s5 = evaluate("\\_S5", Vec::new()).expect("no \\_S5");
+    assert!(s5.as_package().unwrap()[0].as_integer().is_ok());
+
+    let roots = find_devices("PNP0A03");
+    assert!(!roots.is_empty(), "no PCI root bridge");
+    let prt = evaluate(&alloc::format!("{}._PRT", roots[0]), Vec::new()).expect("_PRT failed");
+    for entry in prt.as_package().unwrap() {
+        let entry = entry.as_package().unwrap();
+        assert_eq!(entry.len(), 4);
+        // any function of the device
+        assert_eq!(entry[0].as_integer().unwrap() & 0xFFFF, 0xFFFF);
+    }
+
+    // 1 is the APIC mode ioapic::init already told the firmware about, so this changes nothing
+    if let Err(error) = evaluate("\\_PIC", vec![AmlValue::Integer(1)]) {
+        assert!(matches!(error, AmlError::NameNotFound(_)), "{:?}", error);
+    }
+}
+
+#[test_case]
+fn test_device_status() {
+    let mut namespace = NAMESPACE.lock();
+    let namespace = namespace.as_mut().expect("no namespace");
+    let devices: Vec<AmlName> = namespace
+        .iter()
+        .filter(|(_, object)| matches!(object, AmlValue::Device))
+        .map(|(name, _)| name.clone())
+        .collect();
+    assert!(!devices.is_empty());
+    for device in devices {
+        match namespace.evaluate_child(&device, b"_STA", 0) {
+            Ok(Some(status)) => assert!(status.as_integer().is_ok(), "{}: {:?}", device, status),
+            Ok(None) => (),
+            Err(error) => panic!("{}._STA: {:?}", device, error),
+        }
+    }
+}
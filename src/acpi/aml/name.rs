@@ -0,0 +1,133 @@
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::AmlError;
+
+/// One four-character segment of a name, padded with `_`.
+pub type NameSeg = [u8; 4];
+
+/// An absolute path in the namespace, e.g. `\_SB_.PCI0._PRT`. The root is the empty path.
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct AmlName(Vec<NameSeg>);
+
+impl AmlName {
+    pub fn root() -> AmlName {
+        AmlName(Vec::new())
+    }
+
+    /// Parse a path like `\_SB.PCI0._PRT`. The leading `\` is optional, and segments shorter
+    /// than four characters are padded with `_`.
+    pub fn from_path(path: &str) -> Option<AmlName> {
+        let path = path.strip_prefix('\\').unwrap_or(path);
+        if path.is_empty() {
+            return Some(AmlName::root());
+        }
+        let mut segments = Vec::new();
+        for part in path.split('.') {
+            if part.is_empty() || part.len() > 4 || !part.bytes().all(is_name_char) {
+                return None;
+            }
+            let mut segment = *b"____";
+            segment[..part.len()].copy_from_slice(part.as_bytes());
+            segments.push(segment);
+        }
+        Some(AmlName(segments))
+    }
+
+    pub fn segments(&self) -> &[NameSeg] {
+        &self.0
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn parent(&self) -> Option<AmlName> {
+        let (_, parent) = self.0.split_last()?;
+        Some(AmlName(parent.to_vec()))
+    }
+
+    pub fn child(&self, segment: NameSeg) -> AmlName {
+        let mut segments = self.0.clone();
+        segments.push(segment);
+        AmlName(segments)
+    }
+
+    /// The last segment, e.g. `_PRT` for `\_SB_.PCI0._PRT`.
+    pub fn last(&self) -> Option<NameSeg> {
+        self.0.last().copied()
+    }
+
+    /// Whether this is `scope` or somewhere below it.
+    pub fn starts_with(&self, scope: &AmlName) -> bool {
+        self.0.starts_with(&scope.0)
+    }
+}
+
+impl fmt::Display for AmlName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("\\")?;
+        for (i, segment) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(".")?;
+            }
+            for &c in segment {
+                write!(f, "{}", c as char)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for AmlName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// A name as it's written in AML: rooted, prefixed with `^`s, or relative to the current scope.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NameString {
+    pub rooted: bool,
+    /// Number of `^` prefixes.
+    pub parents: usize,
+    pub segments: Vec<NameSeg>,
+}
+
+impl NameString {
+    /// Whether this is the null name, which some operands use for "none".
+    pub fn is_null(&self) -> bool {
+        !self.rooted && self.parents == 0 && self.segments.is_empty()
+    }
+
+    /// Whether lookups of this name search the enclosing scopes: a single segment with no
+    /// prefix.
+    pub fn searchable(&self) -> bool {
+        !self.rooted && self.parents == 0 && self.segments.len() == 1
+    }
+
+    /// The absolute name this refers to from `scope`, without searching.
+    pub fn resolve(&self, scope: &AmlName) -> Result<AmlName, AmlError> {
+        let mut segments = if self.rooted {
+            Vec::new()
+        } else {
+            let depth = scope
+                .0
+                .len()
+                .checked_sub(self.parents)
+                .ok_or(AmlError::InvalidName)?;
+            scope.0[..depth].to_vec()
+        };
+        segments.extend_from_slice(&self.segments);
+        Ok(AmlName(segments))
+    }
+}
+
+pub(super) fn is_name_char(c: u8) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || c == b'_'
+}
+
+/// Whether `byte` can start a NameString.
+pub(super) fn is_name_start(byte: u8) -> bool {
+    byte.is_ascii_uppercase() || matches!(byte, b'_' | b'\\' | b'^' | 0x2E | 0x2F)
+}
@@ -0,0 +1,176 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::name::{AmlName, NameString};
+use super::value::{AmlValue, Method, MethodCode};
+use super::AmlError;
+
+/// Strings `\_OSI` answers true to: the Windows versions firmware tests for, like other OSes
+/// do, and the ACPI features we understand.
+const OSI_SUPPORTED: &[&str] = &[
+    "Windows 2000",
+    "Windows 2001",
+    "Windows 2001 SP1",
+    "Windows 2001.1",
+    "Windows 2006",
+    "Windows 2006.1",
+    "Windows 2009",
+    "Windows 2012",
+    "Windows 2013",
+    "Windows 2015",
+    "Module Device",
+    "Processor Device",
+];
+
+/// Every object the loaded tables declared, by absolute name.
+pub struct Namespace {
+    objects: BTreeMap<AmlName, AmlValue>,
+    /// 32 or 64, going by the DSDT's revision.
+    pub(super) integer_bits: u32,
+}
+
+impl Namespace {
+    /// An empty namespace, apart from the predefined scopes and objects.
+    pub fn new() -> Namespace {
+        let mut namespace = Namespace {
+            objects: BTreeMap::new(),
+            integer_bits: 64,
+        };
+        for scope in ["_GPE", "_PR", "_SB", "_SI", "_TZ"] {
+            namespace.insert_path(scope, AmlValue::Scope);
+        }
+        namespace.insert_path(
+            "_OS",
+            AmlValue::String(String::from("Microsoft Windows NT")),
+        );
+        namespace.insert_path("_REV", AmlValue::Integer(2));
+        namespace.insert_path(
+            "_OSI",
+            AmlValue::Method(Method {
+                arg_count: 1,
+                serialized: false,
+                code: MethodCode::Native(osi),
+            }),
+        );
+        namespace
+    }
+
+    fn insert_path(&mut self, path: &str, value: AmlValue) {
+        let name = AmlName::from_path(path).expect("bad predefined name");
+        self.objects.insert(name, value);
+    }
+
+    pub fn get(&self, name: &AmlName) -> Option<&AmlValue> {
+        self.objects.get(name)
+    }
+
+    pub fn get_mut(&mut self, name: &AmlName) -> Option<&mut AmlValue> {
+        self.objects.get_mut(name)
+    }
+
+    /// Declare a new object. Its parent has to exist, and the name has to be free.
+    pub fn add(&mut self, name: AmlName, value: AmlValue) -> Result<(), AmlError> {
+        let parent = name.parent().ok_or(AmlError::InvalidName)?;
+        if !parent.is_root() && !self.get(&parent).map_or(false, AmlValue::is_scope) {
+            return Err(AmlError::NameNotFound(parent));
+        }
+        if self.objects.contains_key(&name) {
+            return Err(AmlError::AlreadyExists(name));
+        }
+        self.objects.insert(name, value);
+        Ok(())
+    }
+
+    /// Replace the object at `name`, which has to exist.
+    pub fn set(&mut self, name: &AmlName, value: AmlValue) -> Result<(), AmlError> {
+        let object = self
+            .objects
+            .get_mut(name)
+            .ok_or_else(|| AmlError::NameNotFound(name.clone()))?;
+        *object = value;
+        Ok(())
+    }
+
+    /// Remove the object at `name` and everything below it.
+    pub fn remove(&mut self, name: &AmlName) {
+        let below: Vec<AmlName> = self
+            .descendants(name)
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in below {
+            self.objects.remove(&name);
+        }
+    }
+
+    /// Find the object `name` refers to from `scope`. A lone name segment is looked for in
+    /// `scope` and then in each scope above it, the way AML's search rules go.
+    pub fn search(&self, name: &NameString, scope: &AmlName) -> Result<AmlName, AmlError> {
+        let resolved = name.resolve(scope)?;
+        if resolved.is_root() || self.objects.contains_key(&resolved) {
+            return Ok(resolved);
+        }
+        if name.searchable() {
+            let mut scope = scope.parent();
+            while let Some(parent) = scope {
+                let candidate = parent.child(name.segments[0]);
+                if self.objects.contains_key(&candidate) {
+                    return Ok(candidate);
+                }
+                scope = parent.parent();
+            }
+        }
+        Err(AmlError::NameNotFound(resolved))
+    }
+
+    /// `scope` and every object below it, in name order.
+    pub fn descendants<'a>(
+        &'a self,
+        scope: &'a AmlName,
+    ) -> impl Iterator<Item = (&'a AmlName, &'a AmlValue)> + 'a {
+        self.objects
+            .range(scope.clone()..)
+            .take_while(move |(name, _)| name.starts_with(scope))
+    }
+
+    /// The objects directly below `scope`.
+    pub fn children<'a>(
+        &'a self,
+        scope: &'a AmlName,
+    ) -> impl Iterator<Item = (&'a AmlName, &'a AmlValue)> + 'a {
+        let depth = scope.segments().len() + 1;
+        self.descendants(scope)
+            .filter(move |(name, _)| name.segments().len() == depth)
+    }
+
+    /// Every object, in name order.
+    pub fn iter(&self) -> impl Iterator<Item = (&AmlName, &AmlValue)> {
+        self.objects.iter()
+    }
+
+    /// Truncate `value` to the width of an integer.
+    pub(super) fn truncate(&self, value: u64) -> u64 {
+        value & self.ones()
+    }
+
+    /// An integer with every bit set.
+    pub(super) fn ones(&self) -> u64 {
+        if self.integer_bits == 64 {
+            u64::MAX
+        } else {
+            u64::from(u32::MAX)
+        }
+    }
+}
+
+impl Default for Namespace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn osi(args: &[AmlValue]) -> Result<AmlValue, AmlError> {
+    let interface = args[0].as_string()?;
+    let supported = OSI_SUPPORTED.contains(&interface.as_str());
+    Ok(AmlValue::Integer(if supported { u64::MAX } else { 0 }))
+}
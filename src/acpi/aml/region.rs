@@ -0,0 +1,382 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::memory::{map_mmio, CacheMode, MmioRegion};
+use crate::pio::{Io, Pio};
+
+use super::value::OpRegion;
+use super::AmlError;
+
+pub const SYSTEM_MEMORY: u8 = 0;
+pub const SYSTEM_IO: u8 = 1;
+pub const PCI_CONFIG: u8 = 2;
+
+/// Configuration mechanism #1 ports.
+const PCI_CONFIG_ADDRESS: u16 = 0xCF8;
+const PCI_CONFIG_DATA: u16 = 0xCFC;
+/// Ports are 16 bits wide.
+const IO_SPACE_END: u64 = 0x1_0000;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Field update rules, from bits 5-6 of the field flags.
+const UPDATE_PRESERVE: u8 = 0;
+const UPDATE_WRITE_AS_ONES: u8 = 1;
+
+/// System memory pages fields have been accessed in. They stay mapped, so that an access
+/// doesn't map a page and then unmap it again with a TLB shootdown; firmware only uses a few.
+static MEMORY_PAGES: Mutex<Vec<MmioRegion>> = Mutex::new(Vec::new());
+
+/// The PCI function a PCI config region belongs to.
+#[derive(Clone, Copy, Debug)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+/// Read `width` bits at byte `offset` into `region`.
+pub fn read(
+    region: &OpRegion,
+    pci: Option<PciAddress>,
+    offset: u64,
+    width: u32,
+) -> Result<u64, AmlError> {
+    let address = check_bounds(region, offset, width)?;
+    match region.space {
+        SYSTEM_MEMORY => {
+            let virt = memory_address(address, width)?;
+            let value = unsafe {
+                match width {
+                    8 => u64::from(virt.as_ptr::<u8>().read_volatile()),
+                    16 => u64::from(virt.as_ptr::<u16>().read_volatile()),
+                    32 => u64::from(virt.as_ptr::<u32>().read_volatile()),
+                    _ => virt.as_ptr::<u64>().read_volatile(),
+                }
+            };
+            Ok(value)
+        }
+        SYSTEM_IO => {
+            let port = address as u16;
+            let value = match width {
+                8 => u64::from(Pio::<u8>::new(port).read()),
+                16 => u64::from(Pio::<u16>::new(port).read()),
+                32 => u64::from(Pio::<u32>::new(port).read()),
+                _ => {
+                    let low = u64::from(Pio::<u32>::new(port).read());
+                    low | u64::from(Pio::<u32>::new(port + 4).read()) << 32
+                }
+            };
+            Ok(value)
+        }
+        PCI_CONFIG => {
+            let pci = pci.ok_or(AmlError::Unsupported("PCI config region outside a device"))?;
+            if width == 64 {
+                let low = pci_read(pci, address, 32);
+                return Ok(low | pci_read(pci, address + 4, 32) << 32);
+            }
+            Ok(pci_read(pci, address, width))
+        }
+        _ => Err(AmlError::UnsupportedRegion(region.space)),
+    }
+}
+
+/// Write `width` bits at byte `offset` into `region`.
+pub fn write(
+    region: &OpRegion,
+    pci: Option<PciAddress>,
+    offset: u64,
+    width: u32,
+    value: u64,
+) -> Result<(), AmlError> {
+    let address = check_bounds(region, offset, width)?;
+    match region.space {
+        SYSTEM_MEMORY => {
+            let virt = memory_address(address, width)?;
+            unsafe {
+                match width {
+                    8 => virt.as_mut_ptr::<u8>().write_volatile(value as u8),
+                    16 => virt.as_mut_ptr::<u16>().write_volatile(value as u16),
+                    32 => virt.as_mut_ptr::<u32>().write_volatile(value as u32),
+                    _ => virt.as_mut_ptr::<u64>().write_volatile(value),
+                }
+            }
+        }
+        SYSTEM_IO => {
+            let port = address as u16;
+            match width {
+                8 => Pio::<u8>::new(port).write(value as u8),
+                16 => Pio::<u16>::new(port).write(value as u16),
+                32 => Pio::<u32>::new(port).write(value as u32),
+                _ => {
+                    Pio::<u32>::new(port).write(value as u32);
+                    Pio::<u32>::new(port + 4).write((value >> 32) as u32);
+                }
+            }
+        }
+        PCI_CONFIG => {
+            let pci = pci.ok_or(AmlError::Unsupported("PCI config region outside a device"))?;
+            if width == 64 {
+                pci_write(pci, address, 32, value);
+                pci_write(pci, address + 4, 32, value >> 32);
+            } else {
+                pci_write(pci, address, width, value);
+            }
+        }
+        _ => return Err(AmlError::UnsupportedRegion(region.space)),
+    }
+    Ok(())
+}
+
+/// Where the `width`-bit unit at physical `address` is mapped, mapping its pages on first use.
+fn memory_address(address: u64, width: u32) -> Result<VirtAddr, AmlError> {
+    let end = address + u64::from(width / 8);
+    // AML may name any address, including ones no physical address can have
+    PhysAddr::try_new(end - 1).map_err(|_| AmlError::OutOfBounds)?;
+    let mut pages = MEMORY_PAGES.lock();
+    let covers = |mapping: &MmioRegion| {
+        let start = mapping.phys_addr().as_u64();
+        start <= address && end <= start + mapping.len() as u64
+    };
+    let index = match pages.iter().position(covers) {
+        Some(index) => index,
+        None => {
+            let start = address & !(PAGE_SIZE - 1);
+            let len = (end - start).div_ceil(PAGE_SIZE) * PAGE_SIZE;
+            pages.push(map_mmio(
+                PhysAddr::new(start),
+                len as usize,
+                CacheMode::Uncached,
+            ));
+            pages.len() - 1
+        }
+    };
+    let mapping = &pages[index];
+    Ok(mapping.virt_addr() + (address - mapping.phys_addr().as_u64()))
+}
+
+/// Check that the `width`-bit unit at byte `offset` is inside `region`, and return its address.
+fn check_bounds(region: &OpRegion, offset: u64, width: u32) -> Result<u64, AmlError> {
+    let bytes = u64::from(width / 8);
+    let end = offset.checked_add(bytes).ok_or(AmlError::OutOfBounds)?;
+    let address = region.offset.checked_add(offset);
+    let address_end = address.and_then(|address| address.checked_add(bytes));
+    let limit = match region.space {
+        SYSTEM_IO => IO_SPACE_END,
+        _ => u64::MAX,
+    };
+    match (address, address_end) {
+        (Some(address), Some(address_end)) if end <= region.length && address_end <= limit => {
+            Ok(address)
+        }
+        _ => Err(AmlError::OutOfBounds),
+    }
+}
+
+/// The end of bits `bit_offset..bit_offset + bit_length`, which come from AML and may be
+/// anything.
+fn bit_end(bit_offset: u64, bit_length: u64) -> Result<u64, AmlError> {
+    bit_offset
+        .checked_add(bit_length)
+        .ok_or(AmlError::OutOfBounds)
+}
+
+fn pci_select(pci: PciAddress, offset: u64) -> u16 {
+    let address = 1 << 31
+        | u32::from(pci.bus) << 16
+        | u32::from(pci.device & 0x1F) << 11
+        | u32::from(pci.function & 0x7) << 8
+        | (offset as u32 & 0xFC);
+    Pio::<u32>::new(PCI_CONFIG_ADDRESS).write(address);
+    PCI_CONFIG_DATA + (offset as u16 & 0x3)
+}
+
+fn pci_read(pci: PciAddress, offset: u64, width: u32) -> u64 {
+    let port = pci_select(pci, offset);
+    match width {
+        8 => u64::from(Pio::<u8>::new(port).read()),
+        16 => u64::from(Pio::<u16>::new(port).read()),
+        _ => u64::from(Pio::<u32>::new(port).read()),
+    }
+}
+
+fn pci_write(pci: PciAddress, offset: u64, width: u32, value: u64) {
+    let port = pci_select(pci, offset);
+    match width {
+        8 => Pio::<u8>::new(port).write(value as u8),
+        16 => Pio::<u16>::new(port).write(value as u16),
+        _ => Pio::<u32>::new(port).write(value as u32),
+    }
+}
+
+/// Width in bits of the accesses a field with `flags` makes. For `AnyAcc`, the narrowest one
+/// that reaches the whole field at once, or bytes if none does.
+pub fn access_width(flags: u8, bit_offset: u64, bit_length: u64) -> u32 {
+    let last_bit = bit_offset.saturating_add(bit_length.max(1) - 1);
+    match flags & 0xF {
+        2 => 16,
+        3 => 32,
+        4 => 64,
+        0 => [8, 16, 32, 64]
+            .into_iter()
+            .find(|&width| {
+                let width = u64::from(width);
+                bit_offset / width == last_bit / width
+            })
+            .unwrap_or(8),
+        _ => 8,
+    }
+}
+
+/// Read the bits of a field through accesses `width` bits wide, and return them as
+/// little-endian bytes. `access(offset, None)` reads the unit at byte `offset`.
+pub fn read_bits(
+    bit_offset: u64,
+    bit_length: u64,
+    width: u32,
+    access: &mut dyn FnMut(u64, Option<u64>) -> Result<u64, AmlError>,
+) -> Result<Vec<u8>, AmlError> {
+    let width = u64::from(width);
+    let end = bit_end(bit_offset, bit_length)?;
+    let mut bits = vec![0; bit_length.div_ceil(8) as usize];
+    let mut unit = bit_offset / width * width;
+    while unit < end {
+        let value = access(unit / 8, None)?;
+        for bit in unit.max(bit_offset)..(unit + width).min(end) {
+            if value >> (bit - unit) & 1 != 0 {
+                let out = bit - bit_offset;
+                bits[(out / 8) as usize] |= 1 << (out % 8);
+            }
+        }
+        unit += width;
+    }
+    Ok(bits)
+}
+
+/// Write `bits`, little-endian, to a field through accesses `width` bits wide. Bits of a unit
+/// outside the field are kept, set or cleared as the update rule in `flags` says.
+/// `access(offset, Some(value))` writes the unit at byte `offset`.
+pub fn write_bits(
+    bit_offset: u64,
+    bit_length: u64,
+    width: u32,
+    flags: u8,
+    bits: &[u8],
+    access: &mut dyn FnMut(u64, Option<u64>) -> Result<u64, AmlError>,
+) -> Result<(), AmlError> {
+    let width = u64::from(width);
+    let unit_mask = if width == 64 {
+        u64::MAX
+    } else {
+        (1 << width) - 1
+    };
+    let end = bit_end(bit_offset, bit_length)?;
+    let mut unit = bit_offset / width * width;
+    while unit < end {
+        let (low, high) = (unit.max(bit_offset), (unit + width).min(end));
+        let mut value = if low == unit && high == unit + width {
+            0
+        } else {
+            match flags >> 5 & 0x3 {
+                UPDATE_PRESERVE => access(unit / 8, None)?,
+                UPDATE_WRITE_AS_ONES => unit_mask,
+                _ => 0,
+            }
+        };
+        for bit in low..high {
+            let from = bit - bit_offset;
+            let set = bits
+                .get((from / 8) as usize)
+                .map_or(false, |byte| byte >> (from % 8) & 1 != 0);
+            if set {
+                value |= 1 << (bit - unit);
+            } else {
+                value &= !(1 << (bit - unit));
+            }
+        }
+        access(unit / 8, Some(value))?;
+        unit += width;
+    }
+    Ok(())
+}
+
+/// Bits `bit_offset..bit_offset + bit_length` of `bytes`, shifted down to bit 0.
+pub fn get_bits(bytes: &[u8], bit_offset: u64, bit_length: u64) -> Result<Vec<u8>, AmlError> {
+    if bit_end(bit_offset, bit_length)? > bytes.len() as u64 * 8 {
+        return Err(AmlError::OutOfBounds);
+    }
+    let mut bits = vec![0; bit_length.div_ceil(8) as usize];
+    for i in 0..bit_length {
+        let bit = bit_offset + i;
+        if bytes[(bit / 8) as usize] >> (bit % 8) & 1 != 0 {
+            bits[(i / 8) as usize] |= 1 << (i % 8);
+        }
+    }
+    Ok(bits)
+}
+
+/// Overwrite bits `bit_offset..bit_offset + bit_length` of `bytes` with the low bits of `bits`.
+pub fn set_bits(
+    bytes: &mut [u8],
+    bit_offset: u64,
+    bit_length: u64,
+    bits: &[u8],
+) -> Result<(), AmlError> {
+    if bit_end(bit_offset, bit_length)? > bytes.len() as u64 * 8 {
+        return Err(AmlError::OutOfBounds);
+    }
+    for i in 0..bit_length {
+        let bit = bit_offset + i;
+        let set = bits
+            .get((i / 8) as usize)
+            .map_or(false, |byte| byte >> (i % 8) & 1 != 0);
+        let byte = &mut bytes[(bit / 8) as usize];
+        if set {
+            *byte |= 1 << (bit % 8);
+        } else {
+            *byte &= !(1 << (bit % 8));
+        }
+    }
+    Ok(())
+}
+
+#[test_case]
+fn test_bounds_overflow() {
+    let mut bytes = [0xA5; 4];
+    assert_eq!(get_bits(&bytes, 4, 8), Ok(vec![0x5A]));
+    assert_eq!(get_bits(&bytes, u64::MAX, 2), Err(AmlError::OutOfBounds));
+    assert_eq!(
+        set_bits(&mut bytes, u64::MAX - 1, 8, &[0]),
+        Err(AmlError::OutOfBounds)
+    );
+
+    let region = OpRegion {
+        space: SYSTEM_MEMORY,
+        offset: u64::MAX - 2,
+        length: 8,
+        scope: super::AmlName::root(),
+    };
+    assert_eq!(check_bounds(&region, 0, 32), Err(AmlError::OutOfBounds));
+    assert_eq!(
+        check_bounds(&region, u64::MAX, 8),
+        Err(AmlError::OutOfBounds)
+    );
+
+    // past the widest physical address, so it must not get as far as mapping it
+    let region = OpRegion {
+        offset: 1 << 60,
+        ..region
+    };
+    assert_eq!(read(&region, None, 0, 8), Err(AmlError::OutOfBounds));
+
+    let region = OpRegion {
+        space: SYSTEM_IO,
+        offset: 0xFFFE,
+        ..region
+    };
+    assert_eq!(check_bounds(&region, 0, 16), Ok(0xFFFE));
+    assert_eq!(check_bounds(&region, 0, 32), Err(AmlError::OutOfBounds));
+}
@@ -0,0 +1,168 @@
+use alloc::vec::Vec;
+
+use super::name::{is_name_char, NameSeg, NameString};
+use super::AmlError;
+
+const DUAL_NAME_PREFIX: u8 = 0x2E;
+const MULTI_NAME_PREFIX: u8 = 0x2F;
+
+/// A cursor over AML bytecode. The code comes from ACPI tables mapped for good, so it and
+/// anything sliced from it live forever, and methods can keep their bodies around.
+pub(super) struct Stream {
+    code: &'static [u8],
+    pos: usize,
+}
+
+impl Stream {
+    pub fn new(code: &'static [u8]) -> Stream {
+        Stream { code, pos: 0 }
+    }
+
+    pub fn at_end(&self) -> bool {
+        self.pos >= self.code.len()
+    }
+
+    pub fn peek(&self) -> Result<u8, AmlError> {
+        self.code
+            .get(self.pos)
+            .copied()
+            .ok_or(AmlError::UnexpectedEnd)
+    }
+
+    pub fn peek_at(&self, offset: usize) -> Option<u8> {
+        self.code.get(self.pos + offset).copied()
+    }
+
+    pub fn byte(&mut self) -> Result<u8, AmlError> {
+        let byte = self.peek()?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    pub fn bytes(&mut self, count: usize) -> Result<&'static [u8], AmlError> {
+        let bytes = self
+            .code
+            .get(self.pos..self.pos + count)
+            .ok_or(AmlError::UnexpectedEnd)?;
+        self.pos += count;
+        Ok(bytes)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, AmlError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, AmlError> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, AmlError> {
+        let low = u64::from(self.u32()?);
+        let high = u64::from(self.u32()?);
+        Ok(high << 32 | low)
+    }
+
+    /// Read a PkgLength and return where the package ends. The length counts the PkgLength
+    /// encoding itself.
+    pub fn pkg_end(&mut self) -> Result<usize, AmlError> {
+        let start = self.pos;
+        let end = start + self.pkg_length()?;
+        if end > self.code.len() || end < self.pos {
+            return Err(AmlError::UnexpectedEnd);
+        }
+        Ok(end)
+    }
+
+    /// Read a PkgLength as a plain number, the way field lists use it for bit counts.
+    pub fn pkg_length(&mut self) -> Result<usize, AmlError> {
+        let lead = self.byte()?;
+        let following = usize::from(lead >> 6);
+        let length = if following == 0 {
+            usize::from(lead & 0x3F)
+        } else {
+            let mut length = usize::from(lead & 0x0F);
+            for i in 0..following {
+                length |= usize::from(self.byte()?) << (4 + 8 * i);
+            }
+            length
+        };
+        Ok(length)
+    }
+
+    /// Split off the code up to `end` as its own stream, and move past it.
+    pub fn take_until(&mut self, end: usize) -> Stream {
+        let code = &self.code[self.pos..end];
+        self.pos = end;
+        Stream::new(code)
+    }
+
+    /// Everything from here up to `end`, which the stream moves past.
+    pub fn slice_until(&mut self, end: usize) -> &'static [u8] {
+        let code = &self.code[self.pos..end];
+        self.pos = end;
+        code
+    }
+
+    /// Everything left, which the stream moves past.
+    pub fn rest(&mut self) -> &'static [u8] {
+        self.slice_until(self.code.len())
+    }
+
+    pub fn name_seg(&mut self) -> Result<NameSeg, AmlError> {
+        let bytes = self.bytes(4)?;
+        if !bytes.iter().all(|&c| is_name_char(c)) {
+            return Err(AmlError::InvalidName);
+        }
+        Ok([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    pub fn name_string(&mut self) -> Result<NameString, AmlError> {
+        let mut name = NameString::default();
+        match self.peek()? {
+            b'\\' => {
+                self.pos += 1;
+                name.rooted = true;
+            }
+            b'^' => {
+                while self.peek()? == b'^' {
+                    self.pos += 1;
+                    name.parents += 1;
+                }
+            }
+            _ => (),
+        }
+        let count = match self.peek()? {
+            0x00 => {
+                self.pos += 1;
+                0
+            }
+            DUAL_NAME_PREFIX => {
+                self.pos += 1;
+                2
+            }
+            MULTI_NAME_PREFIX => {
+                self.pos += 1;
+                usize::from(self.byte()?)
+            }
+            _ => 1,
+        };
+        name.segments = (0..count)
+            .map(|_| self.name_seg())
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(name)
+    }
+
+    /// A NUL-terminated ASCII string.
+    pub fn string(&mut self) -> Result<&'static str, AmlError> {
+        let rest = &self.code[self.pos..];
+        let len = rest
+            .iter()
+            .position(|&c| c == 0)
+            .ok_or(AmlError::UnexpectedEnd)?;
+        let string = core::str::from_utf8(&rest[..len]).map_err(|_| AmlError::InvalidString)?;
+        self.pos += len + 1;
+        Ok(string)
+    }
+}
@@ -0,0 +1,222 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::name::AmlName;
+use super::AmlError;
+
+/// An object in the namespace, or a value computed while running a method.
+#[derive(Clone, Debug)]
+pub enum AmlValue {
+    Uninitialized,
+    Integer(u64),
+    String(String),
+    Buffer(Vec<u8>),
+    Package(Vec<AmlValue>),
+    Method(Method),
+    OpRegion(OpRegion),
+    Field(Field),
+    /// Bits of a buffer, made by `CreateField` and friends.
+    BufferField {
+        buffer: Reference,
+        bit_offset: u64,
+        bit_length: u64,
+    },
+    /// A scope with nothing else to it, like `\_SB`.
+    Scope,
+    Device,
+    Processor {
+        id: u8,
+        pblk_address: u32,
+        pblk_length: u8,
+    },
+    PowerResource {
+        system_level: u8,
+        resource_order: u16,
+    },
+    ThermalZone,
+    Mutex {
+        sync_level: u8,
+    },
+    Event,
+    /// Another name for the object at this name.
+    Alias(AmlName),
+    Reference(Reference),
+}
+
+impl AmlValue {
+    /// The ACPI object type code, as `ObjectType` returns it.
+    pub fn type_code(&self) -> u64 {
+        match self {
+            AmlValue::Uninitialized => 0,
+            AmlValue::Integer(_) => 1,
+            AmlValue::String(_) => 2,
+            AmlValue::Buffer(_) => 3,
+            AmlValue::Package(_) => 4,
+            AmlValue::Field(_) => 5,
+            AmlValue::Device => 6,
+            AmlValue::Event => 7,
+            AmlValue::Method(_) => 8,
+            AmlValue::Mutex { .. } => 9,
+            AmlValue::OpRegion(_) => 10,
+            AmlValue::PowerResource { .. } => 11,
+            AmlValue::Processor { .. } => 12,
+            AmlValue::ThermalZone => 13,
+            AmlValue::BufferField { .. } => 14,
+            AmlValue::Reference(_) => 20,
+            AmlValue::Scope | AmlValue::Alias(_) => 0,
+        }
+    }
+
+    /// Whether names can be declared under this object.
+    pub fn is_scope(&self) -> bool {
+        matches!(
+            self,
+            AmlValue::Scope
+                | AmlValue::Device
+                | AmlValue::Processor { .. }
+                | AmlValue::PowerResource { .. }
+                | AmlValue::ThermalZone
+                | AmlValue::Method(_)
+        )
+    }
+
+    /// The value as an integer: integers as they are, buffers read little-endian, and strings
+    /// parsed as hex.
+    pub fn as_integer(&self) -> Result<u64, AmlError> {
+        match self {
+            AmlValue::Integer(value) => Ok(*value),
+            AmlValue::Buffer(bytes) => Ok(bytes
+                .iter()
+                .take(8)
+                .rev()
+                .fold(0, |value, &byte| value << 8 | u64::from(byte))),
+            AmlValue::String(string) => {
+                let digits = string.trim_start_matches("0x").trim_start_matches("0X");
+                let digits = digits
+                    .find(|c: char| !c.is_ascii_hexdigit())
+                    .map_or(digits, |end| &digits[..end]);
+                Ok(u64::from_str_radix(digits, 16).unwrap_or(0))
+            }
+            _ => Err(AmlError::WrongType("Integer")),
+        }
+    }
+
+    /// The value as a buffer: integers as eight little-endian bytes, and strings as their
+    /// characters.
+    pub fn as_buffer(&self) -> Result<Vec<u8>, AmlError> {
+        match self {
+            AmlValue::Buffer(bytes) => Ok(bytes.clone()),
+            AmlValue::Integer(value) => Ok(value.to_le_bytes().to_vec()),
+            AmlValue::String(string) => Ok(string.as_bytes().to_vec()),
+            _ => Err(AmlError::WrongType("Buffer")),
+        }
+    }
+
+    /// The value as a string: integers in hex, and buffers as a list of hex bytes.
+    pub fn as_string(&self) -> Result<String, AmlError> {
+        use core::fmt::Write;
+
+        match self {
+            AmlValue::String(string) => Ok(string.clone()),
+            AmlValue::Integer(value) => Ok(alloc::format!("{:016X}", value)),
+            AmlValue::Buffer(bytes) => {
+                let mut string = String::new();
+                for (i, byte) in bytes.iter().enumerate() {
+                    let separator = if i == 0 { "" } else { "," };
+                    let _ = write!(string, "{}0x{:02X}", separator, byte);
+                }
+                Ok(string)
+            }
+            _ => Err(AmlError::WrongType("String")),
+        }
+    }
+
+    pub fn as_package(&self) -> Result<&[AmlValue], AmlError> {
+        match self {
+            AmlValue::Package(elements) => Ok(elements),
+            _ => Err(AmlError::WrongType("Package")),
+        }
+    }
+}
+
+/// Somewhere a value can be read from or stored to.
+#[derive(Clone, Debug)]
+pub enum Reference {
+    Name(AmlName),
+    Local(usize),
+    Arg(usize),
+    /// Element `index` of the package, buffer or string `Reference` points at.
+    Index(Box<Reference>, usize),
+    /// A value that isn't stored anywhere, like `Index` of a package literal. Stores to it are
+    /// lost.
+    Value(Box<AmlValue>),
+    /// The debug object, which prints what's stored to it.
+    Debug,
+    /// Nowhere; stores to it are dropped.
+    Null,
+}
+
+#[derive(Clone)]
+pub struct Method {
+    pub arg_count: u8,
+    pub serialized: bool,
+    pub code: MethodCode,
+}
+
+#[derive(Clone)]
+pub enum MethodCode {
+    /// AML, run with the method's own name as the scope.
+    Aml(&'static [u8]),
+    /// A method the OS provides, like `\_OSI`.
+    Native(fn(&[AmlValue]) -> Result<AmlValue, AmlError>),
+}
+
+impl fmt::Debug for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = match self.code {
+            MethodCode::Aml(code) => code.len(),
+            MethodCode::Native(_) => 0,
+        };
+        f.debug_struct("Method")
+            .field("arg_count", &self.arg_count)
+            .field("serialized", &self.serialized)
+            .field("code_len", &code)
+            .finish()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct OpRegion {
+    pub space: u8,
+    pub offset: u64,
+    pub length: u64,
+    /// Scope the region was declared in, which PCI config regions take the device from.
+    pub scope: AmlName,
+}
+
+/// A field unit: some bits of an operation region, reached directly, through an index and a
+/// data register, or after selecting a bank.
+#[derive(Clone, Debug)]
+pub struct Field {
+    pub kind: FieldKind,
+    pub bit_offset: u64,
+    pub bit_length: u64,
+    /// FieldFlags: access type in bits 0-3, lock rule in bit 4, update rule in bits 5-6.
+    pub flags: u8,
+}
+
+#[derive(Clone, Debug)]
+pub enum FieldKind {
+    Region(AmlName),
+    Index {
+        index: AmlName,
+        data: AmlName,
+    },
+    Bank {
+        region: AmlName,
+        bank: AmlName,
+        value: u64,
+    },
+}
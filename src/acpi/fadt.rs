@@ -0,0 +1,204 @@
+use core::{mem, ptr};
+
+use spin::Once;
+
+use crate::serial_println;
+
+use super::find_sdt;
+use super::gas::{GenericAddressStructure, Register};
+use super::sdt::Sdt;
+
+/// `flags` bit saying the reset register is supported.
+pub const FLAG_RESET_REG_SUP: u32 = 1 << 10;
+
+/// The Fixed ACPI Description Table, up to the extended fields of ACPI 2.0. Fields past the
+/// end of an older, shorter table read as zero.
+#[repr(packed)]
+#[derive(Clone, Copy, Debug)]
+pub struct Fadt {
+    pub header: Sdt,
+    pub firmware_ctrl: u32,
+    pub dsdt: u32,
+
+    // field used in ACPI 1.0; no longer in use, for compatibility only
+    reserved: u8,
+
+    pub preferred_power_managament: u8,
+    pub sci_interrupt: u16,
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub s4_bios_req: u8,
+    pub pstate_control: u8,
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm2_control_block: u32,
+    pub pm_timer_block: u32,
+    pub gpe0_block: u32,
+    pub gpe1_block: u32,
+    pub pm1_event_length: u8,
+    pub pm1_control_length: u8,
+    pub pm2_control_length: u8,
+    pub pm_timer_length: u8,
+    pub gpe0_length: u8,
+    pub gpe1_length: u8,
+    pub gpe1_base: u8,
+    pub c_state_control: u8,
+    pub worst_c2_latency: u16,
+    pub worst_c3_latency: u16,
+    pub flush_size: u16,
+    pub flush_stride: u16,
+    pub duty_offset: u8,
+    pub duty_width: u8,
+    pub day_alarm: u8,
+    pub month_alarm: u8,
+    /// CMOS register holding the century, or 0 if the RTC doesn't have one.
+    pub century: u8,
+
+    // reserved in ACPI 1.0; used since ACPI 2.0+
+    pub boot_architecture_flags: u16,
+
+    reserved2: u8,
+    pub flags: u32,
+
+    // ACPI 2.0+
+    pub reset_reg: GenericAddressStructure,
+    pub reset_value: u8,
+    pub arm_boot_architecture_flags: u16,
+    pub minor_version: u8,
+    pub x_firmware_control: u64,
+    pub x_dsdt: u64,
+    pub x_pm1a_event_block: GenericAddressStructure,
+    pub x_pm1b_event_block: GenericAddressStructure,
+    pub x_pm1a_control_block: GenericAddressStructure,
+    pub x_pm1b_control_block: GenericAddressStructure,
+    pub x_pm2_control_block: GenericAddressStructure,
+    pub x_pm_timer_block: GenericAddressStructure,
+    pub x_gpe0_block: GenericAddressStructure,
+    pub x_gpe1_block: GenericAddressStructure,
+}
+const _: () = assert!(mem::size_of::<Fadt>() == 244);
+
+/// Length of the ACPI 1.0 table, the shortest one there is.
+const ACPI_1_LENGTH: usize = 116;
+
+pub static FADT: Once<Fadt> = Once::new();
+
+/// The fixed hardware registers in the FADT, mapped once by `Fadt::init`.
+#[derive(Clone, Copy, Debug)]
+pub struct FixedRegisters {
+    pub pm1a_control: Option<Register>,
+    pub pm1b_control: Option<Register>,
+    /// The reset register and the value to write to it.
+    pub reset: Option<(Register, u8)>,
+}
+
+pub static REGISTERS: Once<FixedRegisters> = Once::new();
+
+impl Fadt {
+    pub fn init() {
+        let fadt_sdt = find_sdt("FACP");
+        let fadt = if fadt_sdt.len() == 1 {
+            Fadt::new(fadt_sdt[0])
+        } else {
+            serial_println!("Unable to find FADT");
+            return;
+        };
+
+        if let Some(fadt) = fadt {
+            serial_println!(
+                "  FACP: revision {}, SCI {}, century register {:#x}, reset register {}",
+                { fadt.header.revision },
+                { fadt.sci_interrupt },
+                fadt.century,
+                fadt.reset_register().is_some()
+            );
+            FADT.call_once(|| fadt);
+            REGISTERS.call_once(|| FixedRegisters {
+                pm1a_control: fadt.pm1a_control_block().and_then(|block| block.register()),
+                pm1b_control: fadt.pm1b_control_block().and_then(|block| block.register()),
+                reset: fadt
+                    .reset_register()
+                    .and_then(|(register, value)| Some((register.register()?, value))),
+            });
+        }
+    }
+
+    pub fn new(sdt: &'static Sdt) -> Option<Fadt> {
+        let length = sdt.length as usize;
+        if &sdt.signature != b"FACP" || length < ACPI_1_LENGTH {
+            return None;
+        }
+        // SAFETY: every field is plain data, for which all zeroes is valid
+        let mut fadt: Fadt = unsafe { mem::zeroed() };
+        unsafe {
+            ptr::copy_nonoverlapping(
+                sdt as *const Sdt as *const u8,
+                &mut fadt as *mut Fadt as *mut u8,
+                length.min(mem::size_of::<Fadt>()),
+            )
+        };
+        Some(fadt)
+    }
+
+    /// Physical address of the DSDT.
+    pub fn dsdt_address(&self) -> Option<u64> {
+        match (self.x_dsdt, self.dsdt) {
+            (0, 0) => None,
+            (0, dsdt) => Some(u64::from(dsdt)),
+            (x_dsdt, _) => Some(x_dsdt),
+        }
+    }
+
+    /// The PM1a control register block, holding SCI_EN and the sleep type and enable bits.
+    pub fn pm1a_control_block(&self) -> Option<GenericAddressStructure> {
+        extended(self.x_pm1a_control_block, self.pm1a_control_block, 16)
+    }
+
+    /// The PM1b control register block, on machines that split PM1 in two.
+    pub fn pm1b_control_block(&self) -> Option<GenericAddressStructure> {
+        extended(self.x_pm1b_control_block, self.pm1b_control_block, 16)
+    }
+
+    /// The register to write, and the value to write to it, to reset the machine.
+    pub fn reset_register(&self) -> Option<(GenericAddressStructure, u8)> {
+        let reset_reg = self.reset_reg;
+        if self.flags & FLAG_RESET_REG_SUP != 0 && reset_reg.is_present() {
+            Some((reset_reg, self.reset_value))
+        } else {
+            None
+        }
+    }
+
+    /// CMOS register holding the century, if the RTC has one.
+    pub fn century_register(&self) -> Option<u8> {
+        Some(self.century).filter(|&register| register != 0)
+    }
+}
+
+/// An ACPI 2.0 register block, or the ACPI 1.0 I/O port one if that's all there is.
+fn extended(
+    x_block: GenericAddressStructure,
+    port: u32,
+    bit_width: u8,
+) -> Option<GenericAddressStructure> {
+    if x_block.is_present() {
+        Some(x_block)
+    } else if port != 0 {
+        Some(GenericAddressStructure::io(port, bit_width))
+    } else {
+        None
+    }
+}
+
+#[test_case]
+fn test_fadt_registers() {
+    let fadt = FADT.get().expect("no FADT");
+    assert_ne!({ fadt.sci_interrupt }, 0);
+    let registers = REGISTERS.get().expect("FADT registers not mapped");
+    let pm1a = registers.pm1a_control.expect("no PM1a control block");
+    assert!(pm1a.read().is_some());
+    assert!(fadt.dsdt_address().is_some());
+}
@@ -0,0 +1,159 @@
+//! # Generic Address Structure
+//! How ACPI tables point at registers that may live in memory or in I/O space.
+
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::memory::{map_mmio, CacheMode};
+use crate::pio::{Io, Pio};
+
+/// `address_space` of a structure describing system memory.
+pub const ADDRESS_SPACE_MEMORY: u8 = 0;
+/// `address_space` of a structure describing I/O ports.
+pub const ADDRESS_SPACE_IO: u8 = 1;
+/// Ports are 16 bits wide.
+const IO_SPACE_END: u64 = 0x1_0000;
+
+#[repr(packed)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GenericAddressStructure {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    /// 1-4 for byte, word, dword and qword accesses, or 0 to go by `bit_width`.
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddressStructure {
+    /// An I/O port register, the way ACPI 1.0 tables give them.
+    pub fn io(port: u32, bit_width: u8) -> GenericAddressStructure {
+        GenericAddressStructure {
+            address_space: ADDRESS_SPACE_IO,
+            bit_width,
+            bit_offset: 0,
+            access_size: 0,
+            address: u64::from(port),
+        }
+    }
+
+    /// Whether this points anywhere; unused fields are all zero.
+    pub fn is_present(&self) -> bool {
+        let address = self.address;
+        address != 0
+    }
+
+    /// Map `len` bytes of registers at this address for the rest of the kernel's life. `None`
+    /// if they aren't in system memory, e.g. in I/O space, or the address can't be physical.
+    pub fn map(&self, len: usize) -> Option<VirtAddr> {
+        if self.address_space != ADDRESS_SPACE_MEMORY {
+            return None;
+        }
+        let address = PhysAddr::try_new(self.address).ok()?;
+        let region = map_mmio(address, len, CacheMode::Uncached);
+        Some(region.leak())
+    }
+
+    /// Width of one access, in bits.
+    fn access_bits(&self) -> u8 {
+        match self.access_size {
+            1 => 8,
+            2 => 16,
+            3 => 32,
+            4 => 64,
+            _ => self.bit_width.next_power_of_two().clamp(8, 64),
+        }
+    }
+
+    /// The register this points at, mapped now if it's in system memory so that reading and
+    /// writing it later maps nothing. `None` if its address is out of range, or it doesn't
+    /// start at bit 0 of the access, which no fixed hardware register needs.
+    pub fn register(&self) -> Option<Register> {
+        let bytes = usize::from(self.access_bits() / 8);
+        if self.bit_offset != 0 {
+            return None;
+        }
+        let virt = match self.address_space {
+            ADDRESS_SPACE_MEMORY => Some(self.map(bytes)?),
+            ADDRESS_SPACE_IO if self.address > IO_SPACE_END - bytes as u64 => return None,
+            _ => None,
+        };
+        Some(Register { gas: *self, virt })
+    }
+}
+
+/// A register from a `GenericAddressStructure`, ready to be accessed without taking any locks.
+#[derive(Clone, Copy, Debug)]
+pub struct Register {
+    gas: GenericAddressStructure,
+    /// Where the register is mapped, if it's in system memory.
+    virt: Option<VirtAddr>,
+}
+
+impl Register {
+    /// Read the register. `None` if it's in an address space other than memory or I/O.
+    pub fn read(&self) -> Option<u64> {
+        let bits = self.gas.access_bits();
+        if let Some(virt) = self.virt {
+            let value = unsafe {
+                match bits {
+                    8 => u64::from(virt.as_ptr::<u8>().read_volatile()),
+                    16 => u64::from(virt.as_ptr::<u16>().read_volatile()),
+                    32 => u64::from(virt.as_ptr::<u32>().read_volatile()),
+                    _ => virt.as_ptr::<u64>().read_volatile(),
+                }
+            };
+            return Some(value);
+        }
+        if self.gas.address_space != ADDRESS_SPACE_IO {
+            return None;
+        }
+        let port = self.gas.address as u16;
+        let value = match bits {
+            8 => u64::from(Pio::<u8>::new(port).read()),
+            16 => u64::from(Pio::<u16>::new(port).read()),
+            _ => u64::from(Pio::<u32>::new(port).read()),
+        };
+        Some(value)
+    }
+
+    /// Write the register. Returns false if it's in an address space other than memory or I/O.
+    pub fn write(&self, value: u64) -> bool {
+        let bits = self.gas.access_bits();
+        if let Some(virt) = self.virt {
+            unsafe {
+                match bits {
+                    8 => virt.as_mut_ptr::<u8>().write_volatile(value as u8),
+                    16 => virt.as_mut_ptr::<u16>().write_volatile(value as u16),
+                    32 => virt.as_mut_ptr::<u32>().write_volatile(value as u32),
+                    _ => virt.as_mut_ptr::<u64>().write_volatile(value),
+                }
+            }
+            return true;
+        }
+        if self.gas.address_space != ADDRESS_SPACE_IO {
+            return false;
+        }
+        let port = self.gas.address as u16;
+        match bits {
+            8 => Pio::<u8>::new(port).write(value as u8),
+            16 => Pio::<u16>::new(port).write(value as u16),
+            _ => Pio::<u32>::new(port).write(value as u32),
+        }
+        true
+    }
+}
+
+#[test_case]
+fn test_register_rejects_bad_addresses() {
+    let gas = |address_space, bit_offset, address| GenericAddressStructure {
+        address_space,
+        bit_width: 16,
+        bit_offset,
+        access_size: 0,
+        address,
+    };
+    assert!(gas(ADDRESS_SPACE_IO, 0, 0x404).register().is_some());
+    assert!(gas(ADDRESS_SPACE_IO, 4, 0x404).register().is_none());
+    assert!(gas(ADDRESS_SPACE_IO, 0, 0xFFFF).register().is_none());
+    assert!(gas(ADDRESS_SPACE_MEMORY, 0, 1 << 60).register().is_none());
+}
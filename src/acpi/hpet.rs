@@ -1,23 +1,10 @@
 use core::{mem, ptr};
 
-use core::intrinsics::{volatile_load, volatile_store};
-
-// use crate::memory::Frame;
-// use crate::paging::{ActivePageTable, Page, PageFlags, PhysicalAddress, VirtualAddress};
 use crate::serial_println;
 
+use super::find_sdt;
+use super::gas::GenericAddressStructure;
 use super::sdt::Sdt;
-use super::{find_sdt, ACPI_TABLE};
-
-#[repr(packed)]
-#[derive(Clone, Copy, Debug, Default)]
-pub struct GenericAddressStructure {
-    address_space: u8,
-    bit_width: u8,
-    bit_offset: u8,
-    access_size: u8,
-    pub address: u64,
-}
 
 #[repr(packed)]
 #[derive(Clone, Copy, Debug)]
@@ -36,10 +23,11 @@ pub struct Hpet {
 }
 
 impl Hpet {
-    pub fn init(active_table: &mut ActivePageTable) {
+    /// Find the HPET table and hand it to the HPET driver.
+    pub fn init() {
         let hpet_sdt = find_sdt("HPET");
         let hpet = if hpet_sdt.len() == 1 {
-            Hpet::new(hpet_sdt[0], active_table)
+            Hpet::new(hpet_sdt[0])
         } else {
             serial_println!("Unable to find HPET");
             return;
@@ -48,35 +36,17 @@ impl Hpet {
         if let Some(hpet) = hpet {
             serial_println!("  HPET: {:X}", hpet.hpet_number);
 
-            let mut hpet_t = ACPI_TABLE.hpet.write();
-            *hpet_t = Some(hpet);
+            if !unsafe { crate::device::hpet::init(&hpet) } {
+                serial_println!("  HPET unusable");
+            }
         }
     }
 
-    pub fn new(sdt: &'static Sdt, active_table: &mut ActivePageTable) -> Option<Hpet> {
+    pub fn new(sdt: &'static Sdt) -> Option<Hpet> {
         if &sdt.signature == b"HPET" && sdt.length as usize >= mem::size_of::<Hpet>() {
-            let s = unsafe { ptr::read((sdt as *const Sdt) as *const Hpet) };
-            unsafe { s.base_address.init(active_table) };
-            Some(s)
+            Some(unsafe { ptr::read((sdt as *const Sdt) as *const Hpet) })
         } else {
             None
         }
     }
 }
-
-impl GenericAddressStructure {
-    pub unsafe fn init(&self, active_table: &mut ActivePageTable) {
-        let page = Page::containing_address(VirtualAddress::new(self.address as usize));
-        let frame = Frame::containing_address(PhysicalAddress::new(self.address as usize));
-        let result = active_table.map_to(page, frame, PageFlags::new().write(true));
-        result.flush();
-    }
-
-    pub unsafe fn read_u64(&self, offset: usize) -> u64 {
-        volatile_load((self.address as usize + offset) as *const u64)
-    }
-
-    pub unsafe fn write_u64(&mut self, offset: usize, value: u64) {
-        volatile_store((self.address as usize + offset) as *mut u64, value);
-    }
-}
@@ -1,5 +1,6 @@
 use core::mem;
 use core::ops::DerefMut;
+use core::time::Duration;
 
 // use crate::memory::{allocate_frames, Frame};
 // use crate::paging::{ActivePageTable, Page, PageFlags, PhysicalAddress, VirtualAddress};
@@ -15,11 +16,15 @@ use super::sdt::Sdt;
 use core::intrinsics::{atomic_load_seqcst as atomic_load, atomic_store_seqcst as atomic_store};
 use core::sync::atomic::Ordering;
 
-use crate::device::local_apic::LOCAL_APIC;
+use crate::device::local_apic::{LocalApic, LOCAL_APIC};
 // use crate::interrupt;
-use crate::ap_init::{AP_READY, CPU_COUNT};
+use crate::ap_init::{is_recorded, record_ap, ApStatus, AP_READY, CPU_COUNT};
 use crate::kstart_ap;
+use crate::memory::stack::KernelStack;
 use crate::memory::FRAME_ALLOC;
+use crate::percpu;
+use crate::smp;
+use crate::time;
 use crate::{serial_print, serial_println};
 
 /// The Multiple APIC Descriptor Table
@@ -31,6 +36,14 @@ pub struct Madt {
 }
 
 const TRAMPOLINE: u64 = 0x8000; // must match value in trampoline.asm
+/// How long to hold INIT before the first startup IPI, and to wait after each startup IPI, as
+/// the MP spec asks.
+const INIT_DELAY: Duration = Duration::from_millis(10);
+const SIPI_DELAY: Duration = Duration::from_micros(200);
+/// How long an AP gets to reach the trampoline after the startup IPIs, and then `kstart_ap`'s
+/// ready flag.
+const AP_TRAMPOLINE_TIMEOUT: Duration = Duration::from_millis(100);
+const AP_READY_TIMEOUT: Duration = Duration::from_secs(1);
 static TRAMPOLINE_DATA: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/trampoline"));
 
 pub static mut MADT: Option<Madt> = None;
@@ -50,10 +63,10 @@ impl Madt {
             // safe because no APs have been started yet.
             unsafe { MADT = Some(madt) };
 
-            serial_println!("  APIC: {:>08X}: {}", madt.local_address, madt.flags);
+            serial_println!("  APIC: {:>08X}: {}", madt.local_apic_address(), madt.flags);
 
             let local_apic = unsafe { &mut LOCAL_APIC };
-            let me = local_apic.id() as u8;
+            let me = local_apic.id();
 
             if local_apic.x2 {
                 serial_println!("    X2APIC {}", me);
@@ -90,89 +103,12 @@ impl Madt {
                     serial_println!("      {:?}", madt_entry);
                     match madt_entry {
                         MadtEntry::LocalApic(ap_local_apic) => {
-                            if ap_local_apic.id == me {
-                                serial_println!("        This is my local APIC");
-                            } else {
-                                if ap_local_apic.flags & 1 == 1 {
-                                    // Increase CPU ID
-                                    CPU_COUNT.fetch_add(1, Ordering::SeqCst);
-
-                                    // Allocate a stack
-                                    let stack_start =
-                                        { FRAME_ALLOC.lock().allocate_contiguous_frames(64) }
-                                            .expect("no more frames in acpi stack_start")
-                                            .as_u64()
-                                            + crate::PHYS_OFFSET;
-                                    let stack_end = stack_start + 64 * 4096;
-
-                                    let ap_ready = (TRAMPOLINE + 8) as *mut u64;
-                                    let ap_cpu_id = unsafe { ap_ready.offset(1) };
-                                    let ap_page_table = unsafe { ap_ready.offset(2) };
-                                    let ap_stack_start = unsafe { ap_ready.offset(3) };
-                                    let ap_stack_end = unsafe { ap_ready.offset(4) };
-                                    let ap_code = unsafe { ap_ready.offset(5) };
-
-                                    // Set the ap_ready to 0, volatile
-                                    unsafe { atomic_store(ap_ready, 0) };
-                                    unsafe { atomic_store(ap_cpu_id, ap_local_apic.id as u64) };
-                                    unsafe {
-                                        atomic_store(
-                                            ap_page_table,
-                                            crate::memory::phys_addr_of(active_table).as_u64(),
-                                        )
-                                    };
-                                    unsafe { atomic_store(ap_stack_start, stack_start as u64) };
-                                    unsafe { atomic_store(ap_stack_end, stack_end as u64) };
-                                    unsafe { atomic_store(ap_code, kstart_ap as u64) };
-                                    AP_READY.store(false, Ordering::SeqCst);
-
-                                    serial_print!("        AP {}:", ap_local_apic.id);
-
-                                    // Send INIT IPI
-                                    {
-                                        let mut icr = 0x4500;
-                                        if local_apic.x2 {
-                                            icr |= (ap_local_apic.id as u64) << 32;
-                                        } else {
-                                            icr |= (ap_local_apic.id as u64) << 56;
-                                        }
-                                        serial_print!(" IPI...");
-                                        local_apic.set_icr(icr);
-                                    }
-
-                                    // Send START IPI
-                                    {
-                                        //Start at 0x0800:0000 => 0x8000. Hopefully the bootloader code is still there
-                                        let ap_segment = (TRAMPOLINE >> 12) & 0xFF;
-                                        let mut icr = 0x4600 | ap_segment as u64;
-
-                                        if local_apic.x2 {
-                                            icr |= (ap_local_apic.id as u64) << 32;
-                                        } else {
-                                            icr |= (ap_local_apic.id as u64) << 56;
-                                        }
-
-                                        serial_print!(" SIPI...");
-                                        local_apic.set_icr(icr);
-                                    }
-
-                                    // Wait for trampoline ready
-                                    serial_print!(" Wait...");
-                                    while unsafe { atomic_load(ap_ready) } == 0 {
-                                        unsafe { core::arch::x86_64::_mm_pause() };
-                                    }
-                                    serial_print!(" Trampoline...");
-                                    while !AP_READY.load(Ordering::SeqCst) {
-                                        unsafe { core::arch::x86_64::_mm_pause() };
-                                    }
-                                    serial_println!(" Ready");
-
-                                    // active_table.flush_all();
-                                    x86_64::instructions::tlb::flush_all();
-                                } else {
-                                    serial_println!("        CPU Disabled");
-                                }
-                            }
+                            let (id, flags) = (u32::from(ap_local_apic.id), ap_local_apic.flags);
+                            maybe_start_ap(active_table, local_apic, me, id, flags);
+                        }
+                        MadtEntry::LocalX2Apic(ap_local_x2apic) => {
+                            let (id, flags) = (ap_local_x2apic.id, ap_local_x2apic.flags);
+                            maybe_start_ap(active_table, local_apic, me, id, flags);
                         }
                         _ => (),
                     }
@@ -180,7 +116,8 @@ impl Madt {
 
                 // Unmap trampoline
                 let (_frame, result) = active_table.unmap(trampoline_page).unwrap();
-                result.flush();
+                result.ignore();
+                smp::flush_tlb_range(trampoline_page.start_address(), 1);
             }
         }
     }
@@ -201,6 +138,18 @@ impl Madt {
         }
     }
 
+    /// Physical address of the local APICs, taking a Local APIC Address Override into account.
+    pub fn local_apic_address(&self) -> u64 {
+        self.iter()
+            .find_map(|entry| match entry {
+                MadtEntry::LocalApicAddressOverride(address_override) => {
+                    Some(address_override.address)
+                }
+                _ => None,
+            })
+            .unwrap_or_else(|| u64::from(self.local_address))
+    }
+
     pub fn iter(&self) -> MadtIter {
         MadtIter {
             sdt: self.sdt,
@@ -209,6 +158,132 @@ impl Madt {
     }
 }
 
+/// Start the processor behind a Processor Local APIC or x2APIC entry, unless it's this one,
+/// disabled, or was already started through another entry.
+fn maybe_start_ap(
+    active_table: &mut OffsetPageTable,
+    local_apic: &mut LocalApic,
+    me: u32,
+    apic_id: u32,
+    flags: u32,
+) {
+    if apic_id == me {
+        serial_println!("        This is my local APIC");
+    } else if flags & 1 != 1 {
+        serial_println!("        CPU Disabled");
+    } else if is_recorded(apic_id) {
+        // firmware may list a CPU both as a local APIC and as a local x2APIC
+        serial_println!("        Already started");
+    } else if apic_id > 0xFF && !local_apic.x2 {
+        // the xAPIC ICR only has 8 bits of destination
+        serial_println!("        APIC ID out of xAPIC range");
+    } else if CPU_COUNT.load(Ordering::SeqCst) >= percpu::MAX_CPUS {
+        serial_println!("        Over the limit of {} CPUs", percpu::MAX_CPUS);
+    } else {
+        let status = start_ap(active_table, local_apic, apic_id);
+        record_ap(apic_id, status);
+    }
+}
+
+/// Start the AP with APIC ID `apic_id` through the trampoline, with the INIT-SIPI-SIPI sequence
+/// of the MP spec. An AP that doesn't make it in time is put back in INIT, and its CPU index
+/// goes to the next one.
+fn start_ap(
+    active_table: &mut OffsetPageTable,
+    local_apic: &mut LocalApic,
+    apic_id: u32,
+) -> ApStatus {
+    let cpu_index = CPU_COUNT.fetch_add(1, Ordering::SeqCst);
+    percpu::allocate_ap(cpu_index, apic_id);
+
+    // Allocate a stack, owned by the AP once it's up. The trampoline and `kstart_ap` run on it
+    // before the AP has an IDT, so it can't be demand-paged.
+    let stack = KernelStack::new_mapped(64).expect("no more memory for AP stack");
+
+    let ap_ready = (TRAMPOLINE + 8) as *mut u64;
+    let ap_cpu_id = unsafe { ap_ready.offset(1) };
+    let ap_page_table = unsafe { ap_ready.offset(2) };
+    let ap_stack_start = unsafe { ap_ready.offset(3) };
+    let ap_stack_end = unsafe { ap_ready.offset(4) };
+    let ap_code = unsafe { ap_ready.offset(5) };
+
+    // Set the ap_ready to 0, volatile
+    unsafe { atomic_store(ap_ready, 0) };
+    unsafe { atomic_store(ap_cpu_id, cpu_index as u64) };
+    unsafe {
+        atomic_store(
+            ap_page_table,
+            crate::memory::phys_addr_of(active_table).as_u64(),
+        )
+    };
+    unsafe { atomic_store(ap_stack_start, stack.bottom().as_u64()) };
+    unsafe { atomic_store(ap_stack_end, stack.top().as_u64()) };
+    unsafe { atomic_store(ap_code, kstart_ap as u64) };
+    AP_READY.store(false, Ordering::SeqCst);
+
+    serial_print!("        AP {}:", apic_id);
+
+    // clear errors left over from earlier IPIs
+    unsafe { local_apic.esr() };
+
+    serial_print!(" INIT...");
+    local_apic.ipi_init(apic_id);
+    time::sleep_for(INIT_DELAY);
+
+    // Start at 0x0800:0000 => 0x8000. Hopefully the bootloader code is still there
+    let ap_page = (TRAMPOLINE >> 12) as u8;
+    let trampoline_reached = || unsafe { atomic_load(ap_ready) } != 0;
+    serial_print!(" SIPI...");
+    local_apic.ipi_startup(apic_id, ap_page);
+    if !time::poll_until(SIPI_DELAY, trampoline_reached) {
+        // the second SIPI is for CPUs that missed the first
+        serial_print!(" SIPI...");
+        local_apic.ipi_startup(apic_id, ap_page);
+    }
+
+    let esr = unsafe { local_apic.esr() };
+    if esr != 0 {
+        serial_print!(" ESR {:#x}...", esr);
+    }
+
+    // Wait for trampoline ready
+    serial_print!(" Wait...");
+    let status = if !time::poll_until(AP_TRAMPOLINE_TIMEOUT, trampoline_reached) {
+        ApStatus::NoResponse
+    } else {
+        serial_print!(" Trampoline...");
+        if time::poll_until(AP_READY_TIMEOUT, || AP_READY.load(Ordering::SeqCst)) {
+            ApStatus::Online(cpu_index)
+        } else {
+            ApStatus::Stuck(cpu_index)
+        }
+    };
+
+    match status {
+        ApStatus::Online(_) => {
+            serial_println!(" Ready");
+            // the AP runs on it from here on
+            mem::forget(stack);
+        }
+        ApStatus::Stuck(_) => {
+            local_apic.ipi_init(apic_id);
+            serial_println!(" Stuck");
+            // it got past the trampoline and may have its stack and per-CPU block in use, so
+            // neither can go to the next AP
+            mem::forget(stack);
+        }
+        ApStatus::NoResponse => {
+            // hold it in INIT so it can't wake up later with the next AP's arguments
+            local_apic.ipi_init(apic_id);
+            serial_println!(" No response");
+            unsafe { percpu::release_ap(cpu_index) };
+            CPU_COUNT.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    status
+}
+
 /// MADT Local APIC
 #[derive(Clone, Copy, Debug)]
 #[repr(packed)]
@@ -249,6 +324,66 @@ pub struct MadtIntSrcOverride {
     pub flags: u16,
 }
 
+/// MADT Non-Maskable Interrupt Source
+#[derive(Clone, Copy, Debug)]
+#[repr(packed)]
+pub struct MadtNmiSource {
+    /// Flags, polarity and trigger mode as in `MadtIntSrcOverride`
+    pub flags: u16,
+    /// Global system interrupt the NMI is connected to
+    pub gsi: u32,
+}
+
+/// MADT Local APIC NMI
+#[derive(Clone, Copy, Debug)]
+#[repr(packed)]
+pub struct MadtLocalApicNmi {
+    /// ACPI processor UID, or 0xFF for all processors
+    pub processor: u8,
+    /// Flags, polarity and trigger mode as in `MadtIntSrcOverride`
+    pub flags: u16,
+    /// Local APIC LINT pin the NMI is connected to
+    pub lint: u8,
+}
+
+/// MADT Local APIC Address Override
+#[derive(Clone, Copy, Debug)]
+#[repr(packed)]
+pub struct MadtLocalApicAddressOverride {
+    /// reserved
+    reserved: u16,
+    /// 64-bit physical address of the local APIC, replacing the one in the MADT header
+    pub address: u64,
+}
+
+/// MADT Processor Local x2APIC
+#[derive(Clone, Copy, Debug)]
+#[repr(packed)]
+pub struct MadtLocalX2Apic {
+    /// reserved
+    reserved: u16,
+    /// x2APIC ID
+    pub id: u32,
+    /// Flags. 1 means that the processor is enabled
+    pub flags: u32,
+    /// ACPI processor UID
+    pub processor: u32,
+}
+
+/// MADT Local x2APIC NMI
+#[derive(Clone, Copy, Debug)]
+#[repr(packed)]
+pub struct MadtLocalX2ApicNmi {
+    /// Flags, polarity and trigger mode as in `MadtIntSrcOverride`
+    pub flags: u16,
+    /// ACPI processor UID, or 0xFFFF_FFFF for all processors
+    pub processor: u32,
+    /// Local x2APIC LINT pin the NMI is connected to
+    pub lint: u8,
+    /// reserved
+    reserved: [u8; 3],
+}
+
 /// MADT Entries
 #[derive(Debug)]
 pub enum MadtEntry {
@@ -258,6 +393,16 @@ pub enum MadtEntry {
     InvalidIoApic(usize),
     IntSrcOverride(&'static MadtIntSrcOverride),
     InvalidIntSrcOverride(usize),
+    NmiSource(&'static MadtNmiSource),
+    InvalidNmiSource(usize),
+    LocalApicNmi(&'static MadtLocalApicNmi),
+    InvalidLocalApicNmi(usize),
+    LocalApicAddressOverride(&'static MadtLocalApicAddressOverride),
+    InvalidLocalApicAddressOverride(usize),
+    LocalX2Apic(&'static MadtLocalX2Apic),
+    InvalidLocalX2Apic(usize),
+    LocalX2ApicNmi(&'static MadtLocalX2ApicNmi),
+    InvalidLocalX2ApicNmi(usize),
     Unknown(u8),
 }
 
@@ -274,7 +419,8 @@ impl Iterator for MadtIter {
             let entry_len =
                 unsafe { *(self.sdt.data_address() as *const u8).add(self.i + 1) } as usize;
 
-            if self.i + entry_len <= self.sdt.data_len() {
+            // an entry shorter than its own header would never get us past it
+            if entry_len >= 2 && self.i + entry_len <= self.sdt.data_len() {
                 let item = match entry_type {
                     0 => {
                         if entry_len == mem::size_of::<MadtLocalApic>() + 2 {
@@ -304,6 +450,54 @@ impl Iterator for MadtIter {
                             MadtEntry::InvalidIntSrcOverride(entry_len)
                         }
                     }
+                    3 => {
+                        if entry_len == mem::size_of::<MadtNmiSource>() + 2 {
+                            MadtEntry::NmiSource(unsafe {
+                                &*((self.sdt.data_address() + self.i + 2) as *const MadtNmiSource)
+                            })
+                        } else {
+                            MadtEntry::InvalidNmiSource(entry_len)
+                        }
+                    }
+                    4 => {
+                        if entry_len == mem::size_of::<MadtLocalApicNmi>() + 2 {
+                            MadtEntry::LocalApicNmi(unsafe {
+                                &*((self.sdt.data_address() + self.i + 2)
+                                    as *const MadtLocalApicNmi)
+                            })
+                        } else {
+                            MadtEntry::InvalidLocalApicNmi(entry_len)
+                        }
+                    }
+                    5 => {
+                        if entry_len == mem::size_of::<MadtLocalApicAddressOverride>() + 2 {
+                            MadtEntry::LocalApicAddressOverride(unsafe {
+                                &*((self.sdt.data_address() + self.i + 2)
+                                    as *const MadtLocalApicAddressOverride)
+                            })
+                        } else {
+                            MadtEntry::InvalidLocalApicAddressOverride(entry_len)
+                        }
+                    }
+                    9 => {
+                        if entry_len == mem::size_of::<MadtLocalX2Apic>() + 2 {
+                            MadtEntry::LocalX2Apic(unsafe {
+                                &*((self.sdt.data_address() + self.i + 2) as *const MadtLocalX2Apic)
+                            })
+                        } else {
+                            MadtEntry::InvalidLocalX2Apic(entry_len)
+                        }
+                    }
+                    0xA => {
+                        if entry_len == mem::size_of::<MadtLocalX2ApicNmi>() + 2 {
+                            MadtEntry::LocalX2ApicNmi(unsafe {
+                                &*((self.sdt.data_address() + self.i + 2)
+                                    as *const MadtLocalX2ApicNmi)
+                            })
+                        } else {
+                            MadtEntry::InvalidLocalX2ApicNmi(entry_len)
+                        }
+                    }
                     _ => MadtEntry::Unknown(entry_type),
                 };
 
@@ -318,3 +512,20 @@ impl Iterator for MadtIter {
         }
     }
 }
+
+#[test_case]
+fn test_madt_lists_every_online_cpu() {
+    let madt = unsafe { MADT }.expect("no MADT");
+    let listed = |apic_id: u32| {
+        madt.iter().any(|entry| match entry {
+            MadtEntry::LocalApic(local_apic) => u32::from(local_apic.id) == apic_id,
+            MadtEntry::LocalX2Apic(local_x2apic) => local_x2apic.id == apic_id,
+            _ => false,
+        })
+    };
+    let cpus = CPU_COUNT.load(Ordering::SeqCst);
+    for cpu_index in 0..cpus {
+        let apic_id = percpu::get(cpu_index).unwrap().apic_id;
+        assert!(listed(apic_id), "APIC {} missing from the MADT", apic_id);
+    }
+}
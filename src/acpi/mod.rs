@@ -1,7 +1,8 @@
 //! # ACPI
 //! Code to parse the ACPI tables
 
-use core::ops::DerefMut;
+use core::mem;
+use core::slice;
 
 use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
@@ -10,17 +11,16 @@ use alloc::vec;
 use alloc::vec::Vec;
 
 use spin::{Once, RwLock};
-use x86_64::structures::paging::{
-    Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame, Size4KiB,
-};
-use x86_64::{PhysAddr, VirtAddr};
+use x86_64::structures::paging::OffsetPageTable;
+use x86_64::PhysAddr;
 
-use crate::memory::FRAME_ALLOC;
+use crate::memory::{map_mmio, CacheMode};
 // use crate::memory::Frame;
 // use crate::paging::{ActivePageTable, Page, PageFlags, PhysAddr, VirtAddr};
 use crate::{serial_print, serial_println};
 
-// use self::hpet::Hpet;
+use self::fadt::Fadt;
+use self::hpet::Hpet;
 use self::madt::Madt;
 use self::rsdp::RSDP;
 use self::rsdt::Rsdt;
@@ -28,7 +28,10 @@ use self::rxsdt::Rxsdt;
 use self::sdt::Sdt;
 use self::xsdt::Xsdt;
 
-// pub mod hpet;
+pub mod aml;
+pub mod fadt;
+pub mod gas;
+pub mod hpet;
 pub mod madt;
 mod rsdp;
 mod rsdt;
@@ -36,54 +39,52 @@ mod rxsdt;
 pub mod sdt;
 mod xsdt;
 
-pub fn get_sdt(sdt_address: usize, active_table: &mut OffsetPageTable) -> &'static Sdt {
-    {
-        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(sdt_address as u64));
-        if active_table.translate_page(page).is_err() {
-            let frame = PhysFrame::containing_address(PhysAddr::new(page.start_address().as_u64()));
-            let result = unsafe {
-                active_table
-                    .map_to(
-                        page,
-                        frame,
-                        PageTableFlags::PRESENT,
-                        FRAME_ALLOC.lock().deref_mut(),
-                    )
-                    .unwrap()
-            };
-            result.flush();
-        }
-    }
+/// Longest table we map. DSDTs with all their AML are well below this.
+const MAX_SDT_LENGTH: usize = 16 << 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AcpiError {
+    /// No RSDP with a valid checksum, neither from the bootloader nor in the BIOS area.
+    NoRsdp,
+    /// The RSDP points to a table that's neither an RSDT nor an XSDT.
+    UnknownRootTable([u8; 4]),
+    /// The table with this signature doesn't add up to zero.
+    BadChecksum([u8; 4]),
+    /// The table with this signature claims a length shorter than its header or longer than
+    /// `MAX_SDT_LENGTH`.
+    BadLength([u8; 4], u32),
+    /// `init` already ran.
+    AlreadyInitialized,
+}
 
-    let sdt = unsafe { &*(sdt_address as *const Sdt) };
+/// Whether `bytes` add up to zero, as every ACPI checksum has them do.
+pub(crate) fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
 
-    // Map extra SDT frames if required
-    {
-        let start_page =
-            Page::<Size4KiB>::containing_address(VirtAddr::new(sdt_address as u64 + 4096));
-        let end_page = Page::<Size4KiB>::containing_address(VirtAddr::new(
-            sdt_address as u64 + sdt.length as u64,
-        ));
-        for page in Page::range_inclusive(start_page, end_page) {
-            if active_table.translate_page(page).is_err() {
-                let frame =
-                    PhysFrame::containing_address(PhysAddr::new(page.start_address().as_u64()));
-                let result = unsafe {
-                    active_table
-                        .map_to(
-                            page,
-                            frame,
-                            PageTableFlags::PRESENT,
-                            FRAME_ALLOC.lock().deref_mut(),
-                        )
-                        .unwrap()
-                };
-                result.flush();
-            }
-        }
+/// Map the SDT at physical address `sdt_address` for the rest of the kernel's life, after
+/// checking its length and checksum.
+pub fn get_sdt(sdt_address: usize) -> Result<&'static Sdt, AcpiError> {
+    let phys = PhysAddr::new(sdt_address as u64);
+
+    // map the header first to learn the length of the whole table
+    let header = map_mmio(phys, mem::size_of::<Sdt>(), CacheMode::WriteBack);
+    let (signature, length) = {
+        let sdt = unsafe { &*header.as_ptr::<Sdt>() };
+        (sdt.signature, sdt.length)
+    };
+    drop(header);
+    if (length as usize) < mem::size_of::<Sdt>() || length as usize > MAX_SDT_LENGTH {
+        return Err(AcpiError::BadLength(signature, length));
     }
 
-    sdt
+    let table = map_mmio(phys, length as usize, CacheMode::WriteBack);
+    let bytes = unsafe { slice::from_raw_parts(table.as_ptr::<u8>(), length as usize) };
+    if !checksum_ok(bytes) {
+        return Err(AcpiError::BadChecksum(signature));
+    }
+
+    Ok(unsafe { &*table.leak().as_ptr::<Sdt>() })
 }
 
 pub enum RxsdtEnum {
@@ -101,77 +102,68 @@ impl Rxsdt for RxsdtEnum {
 
 pub static RXSDT_ENUM: Once<RxsdtEnum> = Once::new();
 
-/// Parse the ACPI tables to gather CPU, interrupt, and timer information
-pub unsafe fn init(active_table: &mut OffsetPageTable, already_supplied_rsdps: Option<(u64, u64)>) {
+/// Parse the ACPI tables to gather CPU, interrupt, and timer information. The RSDP is taken
+/// from `rsdp_address` if the bootloader passed one, and searched for otherwise.
+pub unsafe fn init(
+    active_table: &mut OffsetPageTable,
+    rsdp_address: Option<u64>,
+) -> Result<(), AcpiError> {
+    // before anything is reset or mapped, so a second call leaves the tables we have alone
+    if RXSDT_ENUM.is_completed() {
+        return Err(AcpiError::AlreadyInitialized);
+    }
     {
         let mut sdt_ptrs = SDT_POINTERS.write();
         *sdt_ptrs = Some(BTreeMap::new());
     }
 
     // Search for RSDP
-    if let Some(rsdp) = RSDP::get_rsdp(active_table, already_supplied_rsdps) {
-        serial_println!("RSDP: {:?}", rsdp);
-        let rxsdt = get_sdt(rsdp.sdt_address(), active_table);
-
-        for &c in rxsdt.signature.iter() {
-            serial_print!("{}", c as char);
-        }
-        serial_println!(":");
+    let rsdp = RSDP::get_rsdp(rsdp_address).ok_or(AcpiError::NoRsdp)?;
+    serial_println!("RSDP: {:?}", rsdp);
+    let rxsdt = get_sdt(rsdp.sdt_address())?;
 
-        let rxsdt = if let Some(rsdt) = Rsdt::new(rxsdt) {
-            let mut initialized = false;
-
-            let rsdt = RXSDT_ENUM.call_once(|| {
-                initialized = true;
-
-                RxsdtEnum::Rsdt(rsdt)
-            });
-
-            if !initialized {
-                serial_println!("ERROR: RXSDT_ENUM already initialized");
-            }
-
-            rsdt
-        } else if let Some(xsdt) = Xsdt::new(rxsdt) {
-            let mut initialized = false;
-
-            let xsdt = RXSDT_ENUM.call_once(|| {
-                initialized = true;
+    for &c in rxsdt.signature.iter() {
+        serial_print!("{}", c as char);
+    }
+    serial_println!(":");
 
-                RxsdtEnum::Xsdt(xsdt)
-            });
-            if !initialized {
-                serial_println!("ERROR: RXSDT_ENUM already initialized");
+    let rxsdt = if let Some(rsdt) = Rsdt::new(rxsdt) {
+        RxsdtEnum::Rsdt(rsdt)
+    } else if let Some(xsdt) = Xsdt::new(rxsdt) {
+        RxsdtEnum::Xsdt(xsdt)
+    } else {
+        return Err(AcpiError::UnknownRootTable(rxsdt.signature));
+    };
+    let rxsdt = RXSDT_ENUM.call_once(|| rxsdt);
+
+    // TODO: Don't touch ACPI tables in kernel?
+
+    for sdt_address in rxsdt.iter() {
+        let sdt = match get_sdt(sdt_address) {
+            Ok(sdt) => sdt,
+            Err(error) => {
+                // the other tables may still be fine
+                serial_println!("  Skipping table at {:#X}: {:?}", sdt_address, error);
+                continue;
             }
-
-            xsdt
-        } else {
-            serial_println!("UNKNOWN RSDT OR XSDT SIGNATURE");
-            return;
         };
 
-        // TODO: Don't touch ACPI tables in kernel?
-
-        rxsdt.map_all(active_table);
-
-        for sdt_address in rxsdt.iter() {
-            let sdt = &*(sdt_address as *const Sdt);
-
-            let signature = get_sdt_signature(sdt);
-            if let Some(ref mut ptrs) = *(SDT_POINTERS.write()) {
-                ptrs.insert(signature, sdt);
-            }
+        let signature = get_sdt_signature(sdt);
+        if let Some(ref mut ptrs) = *(SDT_POINTERS.write()) {
+            ptrs.insert(signature, sdt);
         }
-
-        // TODO: Enumerate processors in userspace, and then provide an ACPI-independent interface
-        // to initialize enumerated processors to userspace?
-        Madt::init(active_table);
-        // TODO: Let userspace setup HPET, and then provide an interface to specify which timer to
-        // use?
-        // Hpet::init(active_table);
-    } else {
-        serial_println!("NO RSDP FOUND");
     }
+
+    // TODO: Enumerate processors in userspace, and then provide an ACPI-independent interface
+    // to initialize enumerated processors to userspace?
+    Madt::init(active_table);
+    Fadt::init();
+    aml::init();
+    // TODO: Let userspace setup HPET, and then provide an interface to specify which timer to
+    // use?
+    Hpet::init();
+
+    Ok(())
 }
 
 pub type SdtSignature = (String, [u8; 6], [u8; 8]);
@@ -192,8 +184,8 @@ pub fn find_sdt(name: &str) -> Vec<&'static Sdt> {
 }
 
 pub fn get_sdt_signature(sdt: &'static Sdt) -> SdtSignature {
-    let signature =
-        String::from_utf8(sdt.signature.to_vec()).expect("Error converting signature to string");
+    // checksummed tables can still have junk in their signature
+    let signature = String::from_utf8_lossy(&sdt.signature).into_owned();
     (signature, sdt.oem_id, sdt.oem_table_id)
 }
 
@@ -206,3 +198,15 @@ pub fn get_sdt_signature(sdt: &'static Sdt) -> SdtSignature {
 //     hpet: RwLock::new(None),
 //     next_ctx: RwLock::new(0),
 // };
+
+#[test_case]
+fn test_tables_checksum() {
+    let ptrs = SDT_POINTERS.read();
+    let ptrs = ptrs.as_ref().expect("ACPI not initialized");
+    assert!(!ptrs.is_empty());
+    for (signature, sdt) in ptrs {
+        let bytes =
+            unsafe { slice::from_raw_parts(*sdt as *const Sdt as *const u8, sdt.length as usize) };
+        assert!(checksum_ok(bytes), "{} has a bad checksum", signature.0);
+    }
+}
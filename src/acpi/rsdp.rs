@@ -1,17 +1,26 @@
 use core::convert::TryFrom;
 use core::mem;
-use core::ops::DerefMut;
 
-use x86_64::structures::paging::{
-    Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame, Size4KiB,
-};
-use x86_64::{PhysAddr, VirtAddr};
+use x86_64::PhysAddr;
 
-use crate::memory::FRAME_ALLOC;
+use crate::memory::{map_mmio, CacheMode};
+
+use super::checksum_ok;
 
 // use crate::memory::Frame;
 // use crate::paging::{ActivePageTable, Page, PageFlags, PhysicalAddress, VirtualAddress};
 
+/// Physical address of the BDA word holding the EBDA's segment, and how much of the EBDA the
+/// RSDP can be in.
+const EBDA_POINTER: u64 = 0x40E;
+const EBDA_SEARCH_LENGTH: u64 = 1024;
+/// Read-only BIOS area, the other place the RSDP can be on BIOS systems.
+const BIOS_AREA_START: u64 = 0xE_0000;
+const BIOS_AREA_END: u64 = 0xF_FFFF;
+
+/// Length of the ACPI 1.0 part of the RSDP, which the first checksum covers.
+const RSDP_V1_LENGTH: usize = 20;
+
 /// RSDP
 #[derive(Copy, Clone, Debug)]
 #[repr(packed)]
@@ -28,118 +37,85 @@ pub struct RSDP {
 }
 
 impl RSDP {
-    fn is_acpi_1_0(&self) -> bool {
-        self.revision == 0
-    }
-    fn is_acpi_2_0(&self) -> bool {
-        self.revision == 2
+    /// The RSDP at physical address `rsdp_address` if there is a valid one there, or else the
+    /// one found by searching the EBDA and the BIOS area.
+    pub fn get_rsdp(rsdp_address: Option<u64>) -> Option<RSDP> {
+        rsdp_address
+            .and_then(RSDP::get_rsdp_at)
+            .or_else(Self::get_rsdp_by_searching)
     }
-    fn get_already_supplied_rsdps(area: &[u8]) -> Option<RSDP> {
-        // the bootloader has already checked all the checksums for us, but we still need to
-        // double-check.
-        struct Iter<'a> {
-            buf: &'a [u8],
-        }
-        impl<'a> Iterator for Iter<'a> {
-            type Item = &'a [u8];
-
-            fn next(&mut self) -> Option<Self::Item> {
-                if self.buf.len() < 4 {
-                    return None;
-                }
 
-                let length_bytes = <[u8; 4]>::try_from(&self.buf[..4]).ok()?;
-                let length = u32::from_ne_bytes(length_bytes) as usize;
-
-                if (4 + length as usize) > self.buf.len() {
-                    return None;
-                }
+    fn get_rsdp_at(rsdp_address: u64) -> Option<RSDP> {
+        let area = map_mmio(
+            PhysAddr::new(rsdp_address),
+            mem::size_of::<RSDP>(),
+            CacheMode::WriteBack,
+        );
+        RSDP::from_bytes(unsafe { core::slice::from_raw_parts(area.as_ptr::<u8>(), area.len()) })
+    }
 
-                let buf = &self.buf[4..4 + length];
-                self.buf = &self.buf[4 + length..];
+    /// Search for the RSDP in the first KiB of the EBDA, then in the BIOS area
+    pub fn get_rsdp_by_searching() -> Option<RSDP> {
+        Self::search_ebda().or_else(|| Self::search_area(BIOS_AREA_START, BIOS_AREA_END))
+    }
 
-                Some(buf)
-            }
-        }
-        fn slice_to_rsdp(slice: &[u8]) -> Option<&RSDP> {
-            let ptr = slice.as_ptr() as usize;
-
-            if slice.len() >= mem::size_of::<RSDP>() && ptr & (!0x3) == ptr {
-                let rsdp = unsafe { &*(slice.as_ptr() as *const RSDP) };
-                // TODO: Validate
-                Some(rsdp)
-            } else {
-                None
-            }
+    fn search_ebda() -> Option<RSDP> {
+        let pointer = map_mmio(PhysAddr::new(EBDA_POINTER), 2, CacheMode::WriteBack);
+        let segment = unsafe { pointer.as_ptr::<u16>().read_unaligned() };
+        let start = u64::from(segment) << 4;
+        // no EBDA, or a pointer that makes no sense
+        if !(0x8_0000..BIOS_AREA_START).contains(&start) {
+            return None;
         }
+        Self::search_area(start, start + EBDA_SEARCH_LENGTH - 1)
+    }
 
-        // first, find an RSDP for ACPI 2.0
-        if let Some(rsdp_2_0) = (Iter { buf: area }
-            .filter_map(slice_to_rsdp)
-            .find(|rsdp| rsdp.is_acpi_2_0()))
-        {
-            return Some(*rsdp_2_0);
-        }
+    fn search_area(start_addr: u64, end_addr: u64) -> Option<RSDP> {
+        let area = map_mmio(
+            PhysAddr::new(start_addr),
+            (end_addr + 1 - start_addr) as usize,
+            CacheMode::WriteBack,
+        );
 
-        // secondly, find an RSDP for ACPI 1.0
-        if let Some(rsdp_1_0) = (Iter { buf: area }
-            .filter_map(slice_to_rsdp)
-            .find(|rsdp| rsdp.is_acpi_1_0()))
-        {
-            return Some(*rsdp_1_0);
-        }
+        RSDP::search(unsafe { core::slice::from_raw_parts(area.as_ptr::<u8>(), area.len()) })
+    }
 
-        None
+    /// Find the first valid RSDP on a 16-byte boundary in `area`.
+    fn search(area: &[u8]) -> Option<RSDP> {
+        (0..area.len())
+            .step_by(16)
+            .find_map(|offset| RSDP::from_bytes(&area[offset..]))
     }
-    pub fn get_rsdp(
-        active_table: &mut OffsetPageTable,
-        already_supplied_rsdps: Option<(u64, u64)>,
-    ) -> Option<RSDP> {
-        if let Some((base, size)) = already_supplied_rsdps {
-            let area =
-                unsafe { core::slice::from_raw_parts(base as usize as *const u8, size as usize) };
-            Self::get_already_supplied_rsdps(area)
-                .or_else(|| Self::get_rsdp_by_searching(active_table))
-        } else {
-            Self::get_rsdp_by_searching(active_table)
+
+    /// Read the RSDP at the start of `bytes`, if it has the signature and valid checksums: the
+    /// one over the ACPI 1.0 fields, and for ACPI 2.0 and later the extended one over all
+    /// `length` bytes.
+    fn from_bytes(bytes: &[u8]) -> Option<RSDP> {
+        if bytes.len() < RSDP_V1_LENGTH || &bytes[..8] != b"RSD PTR " {
+            return None;
         }
-    }
-    /// Search for the RSDP
-    pub fn get_rsdp_by_searching(active_table: &mut OffsetPageTable) -> Option<RSDP> {
-        let start_addr = 0xE_0000;
-        let end_addr = 0xF_FFFF;
-
-        // Map all of the ACPI RSDP space
-        {
-            let start_frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(start_addr));
-            let end_frame = PhysFrame::containing_address(PhysAddr::new(end_addr));
-            for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
-                let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
-                let result = unsafe {
-                    active_table
-                        .map_to(
-                            page,
-                            frame,
-                            PageTableFlags::PRESENT,
-                            FRAME_ALLOC.lock().deref_mut(),
-                        )
-                        .unwrap()
-                };
-                result.flush();
-            }
+        if !checksum_ok(&bytes[..RSDP_V1_LENGTH]) {
+            return None;
         }
 
-        RSDP::search(start_addr as usize, end_addr as usize)
-    }
-
-    fn search(start_addr: usize, end_addr: usize) -> Option<RSDP> {
-        for i in 0..(end_addr + 1 - start_addr) / 16 {
-            let rsdp = unsafe { &*((start_addr + i * 16) as *const RSDP) };
-            if &rsdp.signature == b"RSD PTR " {
-                return Some(*rsdp);
+        // an ACPI 1.0 RSDP has nothing after the RSDT address, so leave the rest zeroed
+        let mut raw = [0; mem::size_of::<RSDP>()];
+        let revision = bytes[15];
+        let length = if revision >= 2 {
+            let length = u32::from_le_bytes(<[u8; 4]>::try_from(bytes.get(20..24)?).ok()?) as usize;
+            if length < mem::size_of::<RSDP>() || length > bytes.len() {
+                return None;
             }
-        }
-        None
+            if !checksum_ok(&bytes[..length]) {
+                return None;
+            }
+            mem::size_of::<RSDP>()
+        } else {
+            RSDP_V1_LENGTH
+        };
+        raw[..length].copy_from_slice(&bytes[..length]);
+
+        Some(unsafe { (raw.as_ptr() as *const RSDP).read_unaligned() })
     }
 
     /// Get the RSDT or XSDT address
@@ -151,3 +127,40 @@ impl RSDP {
         }
     }
 }
+
+#[test_case]
+fn test_rsdp_checksums() {
+    let mut bytes = [0u8; 36];
+    bytes[..8].copy_from_slice(b"RSD PTR ");
+    bytes[15] = 2; // revision
+    bytes[16..20].copy_from_slice(&0x1234_5678u32.to_le_bytes());
+    bytes[20..24].copy_from_slice(&36u32.to_le_bytes());
+    bytes[24..32].copy_from_slice(&0x9_0000_0000u64.to_le_bytes());
+    let fix = |bytes: &mut [u8], at: usize| {
+        bytes[at] = 0;
+        bytes[at] = 0u8.wrapping_sub(bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)));
+    };
+    fix(&mut bytes[..20], 8);
+    fix(&mut bytes, 32);
+
+    let rsdp = RSDP::from_bytes(&bytes).expect("valid RSDP rejected");
+    assert_eq!(rsdp.sdt_address(), 0x9_0000_0000);
+
+    // broken extended checksum
+    bytes[30] ^= 1;
+    assert!(RSDP::from_bytes(&bytes).is_none());
+    // as ACPI 1.0, only the first checksum counts
+    bytes[15] = 0;
+    fix(&mut bytes[..20], 8);
+    assert_eq!(RSDP::from_bytes(&bytes).unwrap().sdt_address(), 0x1234_5678);
+    bytes[17] ^= 1;
+    assert!(RSDP::from_bytes(&bytes).is_none());
+}
+
+#[test_case]
+fn test_rsdp_fallback() {
+    // nothing at 0, so this finds the one SeaBIOS leaves in the BIOS area
+    let found = RSDP::get_rsdp(Some(0)).expect("no RSDP found by searching");
+    let searched = RSDP::get_rsdp_by_searching().unwrap();
+    assert_eq!(found.sdt_address(), searched.sdt_address());
+}
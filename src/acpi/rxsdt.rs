@@ -1,5 +1,4 @@
 use alloc::boxed::Box;
-use x86_64::structures::paging::OffsetPageTable;
 
 // use crate::paging::ActivePageTable;
 
@@ -9,12 +8,6 @@ use super::sdt::Sdt;
 pub trait Rxsdt {
     fn iter(&self) -> Box<dyn Iterator<Item = usize>>;
 
-    fn map_all(&self, active_table: &mut OffsetPageTable) {
-        for sdt in self.iter() {
-            get_sdt(sdt, active_table);
-        }
-    }
-
     fn find(
         &self,
         signature: [u8; 4],
@@ -22,7 +15,10 @@ pub trait Rxsdt {
         oem_table_id: [u8; 8],
     ) -> Option<&'static Sdt> {
         for sdt in self.iter() {
-            let sdt = unsafe { &*(sdt as *const Sdt) };
+            let sdt = match get_sdt(sdt) {
+                Ok(sdt) => sdt,
+                Err(_) => continue,
+            };
 
             if sdt.match_pattern(signature, oem_id, oem_table_id) {
                 return Some(sdt);
@@ -0,0 +1,255 @@
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::ops::DerefMut;
+use core::ptr::{null_mut, NonNull};
+use linked_list_allocator::Heap;
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags,
+        Size4KiB,
+    },
+    VirtAddr,
+};
+
+use crate::memory::{self, FRAME_ALLOC};
+
+use self::slab::SlabAllocator;
+
+pub mod slab;
+
+pub const HEAP_START: usize = 0x_4444_4444_0000;
+/// Size mapped by `init_heap`.
+pub const HEAP_INITIAL_SIZE: usize = 100 * 1024; // 100 KiB
+/// Default ceiling the heap may grow to, see `set_max_size`.
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024; // 64 MiB
+/// The heap never grows by less than this at once.
+const HEAP_GROWTH_STEP: usize = 64 * 1024;
+const PAGE_SIZE: usize = 4096;
+
+#[global_allocator]
+static ALLOCATOR: SlabAllocator = SlabAllocator::new(KernelHeap::empty());
+
+/// A first-fit heap that maps more pages at its top when it runs out of space.
+///
+/// This is the page-level allocator behind the slab allocator: it provides the slab pages and
+/// serves allocations too large for a size class.
+pub struct KernelHeap(Mutex<GrowableHeap>);
+
+struct GrowableHeap {
+    heap: Heap,
+    max_size: usize,
+    high_water_mark: usize,
+    expansions: usize,
+}
+
+/// A snapshot of the kernel heap's bookkeeping.
+#[derive(Clone, Copy, Debug)]
+pub struct HeapStats {
+    /// Bytes currently mapped for the heap.
+    pub size: usize,
+    /// Bytes currently handed out.
+    pub used: usize,
+    /// Largest value `used` has reached.
+    pub high_water_mark: usize,
+    /// Number of times the heap has mapped more pages.
+    pub expansions: usize,
+    /// Ceiling `size` may grow to.
+    pub max_size: usize,
+}
+
+pub fn init_heap(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<(), MapToError<Size4KiB>> {
+    map_heap_pages(mapper, frame_allocator, HEAP_START, HEAP_INITIAL_SIZE)?;
+
+    unsafe {
+        ALLOCATOR
+            .backing()
+            .0
+            .lock()
+            .heap
+            .init(HEAP_START, HEAP_INITIAL_SIZE);
+    }
+
+    Ok(())
+}
+
+/// Returns the current heap statistics.
+///
+/// Slab pages count as used for as long as the slab allocator holds them.
+pub fn stats() -> HeapStats {
+    let inner = ALLOCATOR.backing().0.lock();
+    HeapStats {
+        size: inner.heap.size(),
+        used: inner.heap.used(),
+        high_water_mark: inner.high_water_mark,
+        expansions: inner.expansions,
+        max_size: inner.max_size,
+    }
+}
+
+/// Set the ceiling the heap may grow to. Pages already mapped stay mapped.
+pub fn set_max_size(max_size: usize) {
+    ALLOCATOR.backing().0.lock().max_size = max_size;
+}
+
+/// Map `size` bytes from `start`. On failure the pages this mapped are unmapped and freed
+/// again, so a failed `grow` leaves nothing behind above the heap top.
+fn map_heap_pages(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+    start: usize,
+    size: usize,
+) -> Result<(), MapToError<Size4KiB>> {
+    let page_range = {
+        let heap_start = VirtAddr::new(start as u64);
+        let heap_end = heap_start + size - 1u64;
+        let heap_start_page = Page::containing_address(heap_start);
+        let heap_end_page = Page::containing_address(heap_end);
+        Page::range_inclusive(heap_start_page, heap_end_page)
+    };
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    for (mapped, page) in page_range.enumerate() {
+        let result = match frame_allocator.allocate_frame() {
+            Some(frame) => unsafe {
+                mapper
+                    .map_to(page, frame, flags, frame_allocator)
+                    .map_err(|error| {
+                        frame_allocator.deallocate_frame(frame);
+                        error
+                    })
+            },
+            None => Err(MapToError::FrameAllocationFailed),
+        };
+        match result {
+            Ok(flush) => flush.flush(),
+            Err(error) => {
+                // nothing has touched the pages but this CPU, so a local flush is enough
+                for page in page_range.take(mapped) {
+                    if let Ok((frame, flush)) = mapper.unmap(page) {
+                        flush.flush();
+                        unsafe { frame_allocator.deallocate_frame(frame) };
+                    }
+                }
+                return Err(error);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+impl KernelHeap {
+    pub const fn empty() -> Self {
+        KernelHeap(Mutex::new(GrowableHeap {
+            heap: Heap::empty(),
+            max_size: HEAP_MAX_SIZE,
+            high_water_mark: 0,
+            expansions: 0,
+        }))
+    }
+}
+
+impl GrowableHeap {
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let ptr = match self.heap.allocate_first_fit(layout) {
+            Ok(ptr) => ptr,
+            Err(()) => {
+                if !self.grow(layout) {
+                    return null_mut();
+                }
+                match self.heap.allocate_first_fit(layout) {
+                    Ok(ptr) => ptr,
+                    Err(()) => return null_mut(),
+                }
+            }
+        };
+        self.high_water_mark = self.high_water_mark.max(self.heap.used());
+        ptr.as_ptr()
+    }
+
+    /// Map enough pages above the heap top to satisfy `layout`. Returns false if the ceiling or
+    /// physical memory was hit.
+    fn grow(&mut self, layout: Layout) -> bool {
+        if self.heap.size() == 0 {
+            // not initialized yet
+            return false;
+        }
+        // worst case the allocation lands after a fresh hole that has to absorb the alignment
+        let needed = layout.size() + layout.align();
+        let by = align_up(needed.max(HEAP_GROWTH_STEP), PAGE_SIZE);
+        if self.heap.size() + by > self.max_size {
+            return false;
+        }
+
+        let top = self.heap.top();
+        let mapped = memory::with_active_table(|table| {
+            map_heap_pages(table, FRAME_ALLOC.lock().deref_mut(), top, by)
+        });
+        match mapped {
+            Ok(()) => {
+                unsafe { self.heap.extend(by) };
+                self.expansions += 1;
+                true
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.0.lock().allocate(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0
+            .lock()
+            .heap
+            .deallocate(NonNull::new_unchecked(ptr), layout)
+    }
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+pub struct Dummy;
+
+unsafe impl GlobalAlloc for Dummy {
+    unsafe fn alloc(&self, _layout: Layout) -> *mut u8 {
+        null_mut()
+    }
+
+    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
+        panic!("dealloc should be never called")
+    }
+}
+
+#[test_case]
+fn test_large_allocation_grows_heap() {
+    use alloc::vec::Vec;
+
+    let before = stats();
+    let big: Vec<u8> = alloc::vec![0xAB; 2 * HEAP_INITIAL_SIZE];
+    assert!(big.iter().all(|&b| b == 0xAB));
+
+    let after = stats();
+    assert!(after.size >= before.size + 2 * HEAP_INITIAL_SIZE);
+    assert!(after.expansions > before.expansions);
+    assert!(after.high_water_mark >= 2 * HEAP_INITIAL_SIZE);
+    drop(big);
+    assert!(stats().used < after.used);
+}
+
+#[test_case]
+fn test_small_allocations_use_slabs() {
+    use alloc::boxed::Box;
+
+    let pages = |allocator: &SlabAllocator| allocator.slab_pages().iter().sum::<usize>();
+    let objects: alloc::vec::Vec<Box<[u8; 24]>> = (0..1024).map(|_| Box::new([7; 24])).collect();
+    assert!(pages(&ALLOCATOR) >= 1024 * 32 / 4096);
+    assert!(objects.iter().all(|o| o.iter().all(|&b| b == 7)));
+}
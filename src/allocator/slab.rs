@@ -0,0 +1,266 @@
+//! # Slab allocator
+//! Size-class allocator used as the kernel's `#[global_allocator]`.
+//!
+//! Small objects (up to `MAX_CLASS_SIZE` bytes) are served from power-of-two size classes. Each
+//! CPU caches free objects of every class in a magazine, so the common alloc/free path only takes
+//! a lock nobody else contends for. Magazines are refilled from, and flushed to, a per-class
+//! depot in batches. The depot carves fresh objects out of pages taken from the growable
+//! `KernelHeap`, which also serves every allocation too large for a size class.
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::ptr::null_mut;
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::KernelHeap;
+
+/// Smallest size class, large enough to hold the free list link.
+const MIN_CLASS_SIZE: usize = 16;
+/// Largest size class. Bigger (or more strictly aligned) layouts go to the backing heap.
+pub const MAX_CLASS_SIZE: usize = 2048;
+const NUM_CLASSES: usize = 8; // 16, 32, ..., 2048
+
+/// Size of the pages the depot carves into objects.
+const SLAB_SIZE: usize = 4096;
+
+/// Objects cached per CPU per class.
+const MAGAZINE_SIZE: usize = 32;
+/// Number of CPUs with their own magazines. CPUs beyond this share them.
+const MAX_CPUS: usize = 64;
+
+pub struct SlabAllocator {
+    depots: [Mutex<Depot>; NUM_CLASSES],
+    magazines: [[Mutex<Magazine>; NUM_CLASSES]; MAX_CPUS],
+    backing: KernelHeap,
+}
+
+/// Global pool of free objects of one size class.
+struct Depot {
+    /// Intrusive singly linked list of free objects.
+    free: *mut FreeObject,
+    slabs: usize,
+}
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// A CPU-local stack of free objects of one size class.
+struct Magazine {
+    objects: [*mut u8; MAGAZINE_SIZE],
+    len: usize,
+}
+
+// SAFETY: the raw pointers are owned free objects, only touched with the surrounding lock held.
+unsafe impl Send for Depot {}
+unsafe impl Send for Magazine {}
+
+const EMPTY_DEPOT: Mutex<Depot> = Mutex::new(Depot {
+    free: null_mut(),
+    slabs: 0,
+});
+const EMPTY_MAGAZINE: Mutex<Magazine> = Mutex::new(Magazine {
+    objects: [null_mut(); MAGAZINE_SIZE],
+    len: 0,
+});
+const EMPTY_MAGAZINES: [Mutex<Magazine>; NUM_CLASSES] = [EMPTY_MAGAZINE; NUM_CLASSES];
+
+impl SlabAllocator {
+    pub const fn new(backing: KernelHeap) -> Self {
+        SlabAllocator {
+            depots: [EMPTY_DEPOT; NUM_CLASSES],
+            magazines: [EMPTY_MAGAZINES; MAX_CPUS],
+            backing,
+        }
+    }
+
+    /// The page-level heap behind the size classes.
+    pub fn backing(&self) -> &KernelHeap {
+        &self.backing
+    }
+
+    /// Number of slab pages taken from the backing heap, per size class.
+    pub fn slab_pages(&self) -> [usize; NUM_CLASSES] {
+        let mut pages = [0; NUM_CLASSES];
+        for (count, depot) in pages.iter_mut().zip(self.depots.iter()) {
+            *count = depot.lock().slabs;
+        }
+        pages
+    }
+
+    fn magazine(&self, class: usize) -> &Mutex<Magazine> {
+        &self.magazines[cpu_slot() % MAX_CPUS][class]
+    }
+
+    /// Move up to half a magazine of objects from the depot into `magazine`.
+    fn refill(&self, class: usize, magazine: &mut Magazine) {
+        let mut depot = self.depots[class].lock();
+        while magazine.len < MAGAZINE_SIZE / 2 {
+            if depot.free.is_null() && !self.grow_depot(class, &mut depot) {
+                break;
+            }
+            let object = depot.free;
+            depot.free = unsafe { (*object).next };
+            magazine.objects[magazine.len] = object as *mut u8;
+            magazine.len += 1;
+        }
+    }
+
+    /// Move half of a full magazine back to the depot.
+    fn flush(&self, class: usize, magazine: &mut Magazine) {
+        let mut depot = self.depots[class].lock();
+        while magazine.len > MAGAZINE_SIZE / 2 {
+            magazine.len -= 1;
+            let object = magazine.objects[magazine.len] as *mut FreeObject;
+            unsafe { (*object).next = depot.free };
+            depot.free = object;
+        }
+    }
+
+    /// Carve a fresh page from the backing heap into objects of `class`.
+    fn grow_depot(&self, class: usize, depot: &mut Depot) -> bool {
+        let layout = Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap();
+        let slab = unsafe { self.backing.alloc(layout) };
+        if slab.is_null() {
+            return false;
+        }
+        let size = class_size(class);
+        for offset in (0..SLAB_SIZE).step_by(size).rev() {
+            let object = unsafe { slab.add(offset) } as *mut FreeObject;
+            unsafe { (*object).next = depot.free };
+            depot.free = object;
+        }
+        depot.slabs += 1;
+        true
+    }
+}
+
+unsafe impl GlobalAlloc for SlabAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let class = match size_class(layout) {
+            Some(class) => class,
+            None => return self.backing.alloc(layout),
+        };
+
+        // interrupts stay off so a handler on this CPU can't find its own magazine locked
+        interrupts::without_interrupts(|| {
+            let mut magazine = self.magazine(class).lock();
+            if magazine.len == 0 {
+                self.refill(class, &mut magazine);
+                if magazine.len == 0 {
+                    return null_mut();
+                }
+            }
+            magazine.len -= 1;
+            magazine.objects[magazine.len]
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let class = match size_class(layout) {
+            Some(class) => class,
+            None => return self.backing.dealloc(ptr, layout),
+        };
+
+        interrupts::without_interrupts(|| {
+            let mut magazine = self.magazine(class).lock();
+            if magazine.len == MAGAZINE_SIZE {
+                self.flush(class, &mut magazine);
+            }
+            let len = magazine.len;
+            magazine.objects[len] = ptr;
+            magazine.len = len + 1;
+        })
+    }
+}
+
+/// The size class serving `layout`, or `None` if it has to go to the backing heap.
+fn size_class(layout: Layout) -> Option<usize> {
+    let size = layout
+        .size()
+        .max(layout.align())
+        .max(MIN_CLASS_SIZE)
+        .next_power_of_two();
+    if size > MAX_CLASS_SIZE {
+        None
+    } else {
+        Some((size.trailing_zeros() - MIN_CLASS_SIZE.trailing_zeros()) as usize)
+    }
+}
+
+fn class_size(class: usize) -> usize {
+    MIN_CLASS_SIZE << class
+}
+
+/// Index of the magazine set used by the current CPU.
+fn cpu_slot() -> usize {
+    crate::percpu!(cpu_index)
+}
+
+#[test_case]
+fn test_size_classes() {
+    let class = |size, align| size_class(Layout::from_size_align(size, align).unwrap());
+    assert_eq!(class(1, 1), Some(0));
+    assert_eq!(class(16, 8), Some(0));
+    assert_eq!(class(17, 8), Some(1));
+    assert_eq!(class(8, 64), Some(2));
+    assert_eq!(class(MAX_CLASS_SIZE, 8), Some(NUM_CLASSES - 1));
+    assert_eq!(class(MAX_CLASS_SIZE + 1, 8), None);
+    assert_eq!(class(8, 4096), None);
+}
+
+#[cfg(test)]
+fn test_stress_all_cpus() {
+    use alloc::{boxed::Box, vec::Vec};
+
+    fn stress() {
+        let cpu = cpu_slot() as u8;
+        let mut live: Vec<Box<[u8]>> = Vec::new();
+        for round in 0..2000usize {
+            let size = 1 + (round * 37) % (2 * MAX_CLASS_SIZE);
+            live.push(alloc::vec![cpu; size].into_boxed_slice());
+            if live.len() > 64 {
+                let victim = live.swap_remove(round % live.len());
+                assert!(victim.iter().all(|&b| b == cpu), "slab object corrupted");
+            }
+        }
+        for object in live {
+            assert!(object.iter().all(|&b| b == cpu), "slab object corrupted");
+        }
+    }
+
+    crate::run_on_all_cpus(stress);
+}
+crate::smp_test!(test_stress_all_cpus, 4);
+
+#[cfg(test)]
+fn test_free_on_other_cpu() {
+    use alloc::{boxed::Box, vec::Vec};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Objects left behind by the last CPU to run `exchange`, and that CPU's slot.
+    static HANDOFF: Mutex<(usize, Vec<Box<[u64; 8]>>)> = Mutex::new((usize::MAX, Vec::new()));
+    static FREED_ELSEWHERE: AtomicUsize = AtomicUsize::new(0);
+
+    fn exchange() {
+        // free whatever another CPU left behind, then leave our own objects for someone else
+        let cpu = cpu_slot();
+        let mine: Vec<Box<[u64; 8]>> = (0..256).map(|i| Box::new([i; 8])).collect();
+        let (from, theirs) = core::mem::replace(&mut *HANDOFF.lock(), (cpu, mine));
+        for (i, object) in theirs.into_iter().enumerate() {
+            assert_eq!(*object, [i as u64; 8]);
+        }
+        if from != usize::MAX {
+            // every CPU runs this once, so they came from somewhere else
+            assert_ne!(from, cpu);
+            FREED_ELSEWHERE.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let cpus = crate::smp::online_count();
+    crate::run_on_all_cpus(exchange);
+    assert_eq!(FREED_ELSEWHERE.load(Ordering::SeqCst), cpus - 1);
+    HANDOFF.lock().1.clear();
+}
+crate::smp_test!(test_free_on_other_cpu, 2);
@@ -1,20 +1,86 @@
+use alloc::vec::Vec;
 use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
+use spin::Mutex;
 use x86_64::structures::paging::OffsetPageTable;
 
+use crate::serial_println;
+
 pub static CPU_COUNT: AtomicUsize = AtomicUsize::new(0);
 pub static AP_READY: AtomicBool = AtomicBool::new(false);
 pub static BSP_READY: AtomicBool = AtomicBool::new(false);
 
-pub fn init_aps(active_table: &mut OffsetPageTable) {
+/// How starting an AP went.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ApStatus {
+    /// Running, with the given logical CPU index.
+    Online(usize),
+    /// Never reached the trampoline after the startup IPIs.
+    NoResponse,
+    /// Reached the trampoline but not `kstart_ap`'s ready flag. It may already be using the
+    /// given CPU index, its stack and per-CPU block, so those stay allocated to it.
+    Stuck(usize),
+}
+
+/// APIC ID and outcome of every AP the BSP tried to start, in MADT order.
+static AP_STATUS: Mutex<Vec<(u32, ApStatus)>> = Mutex::new(Vec::new());
+
+pub fn init_aps(active_table: &mut OffsetPageTable, rsdp_address: Option<u64>) {
     unsafe {
-        crate::acpi::init(active_table, None);
+        if let Err(error) = crate::acpi::init(active_table, rsdp_address) {
+            serial_println!("ACPI: {:?}", error);
+        }
         crate::device::init_after_acpi(active_table);
     }
 
+    print_summary();
     BSP_READY.store(true, Ordering::SeqCst);
 }
 
+pub(crate) fn record_ap(apic_id: u32, status: ApStatus) {
+    AP_STATUS.lock().push((apic_id, status));
+}
+
+/// Whether the BSP already tried to start the AP with APIC ID `apic_id`.
+pub(crate) fn is_recorded(apic_id: u32) -> bool {
+    AP_STATUS.lock().iter().any(|&(id, _)| id == apic_id)
+}
+
+/// APIC ID and outcome of every AP the BSP tried to start.
+pub fn ap_status() -> Vec<(u32, ApStatus)> {
+    AP_STATUS.lock().clone()
+}
+
+fn print_summary() {
+    let status = AP_STATUS.lock();
+    serial_println!(
+        "SMP: {} CPU(s) online, {} AP(s) failed",
+        1 + status
+            .iter()
+            .filter(|(_, status)| matches!(status, ApStatus::Online(_)))
+            .count(),
+        status
+            .iter()
+            .filter(|(_, status)| !matches!(status, ApStatus::Online(_)))
+            .count()
+    );
+    for (apic_id, status) in status.iter() {
+        match status {
+            ApStatus::Online(cpu_index) => {
+                serial_println!("  APIC {}: online as CPU {}", apic_id, cpu_index)
+            }
+            ApStatus::NoResponse => serial_println!("  APIC {}: no response", apic_id),
+            ApStatus::Stuck(cpu_index) => {
+                serial_println!(
+                    "  APIC {}: stuck during start-up as CPU {}",
+                    apic_id,
+                    cpu_index
+                )
+            }
+        }
+    }
+}
+
 #[repr(packed)]
 pub struct KernelArgsAp {
     pub cpu_id: u64,
@@ -22,3 +88,48 @@ pub struct KernelArgsAp {
     pub stack_start: u64,
     pub stack_end: u64,
 }
+
+#[cfg(test)]
+fn test_ap_status_matches_cpu_count() {
+    let status = ap_status();
+    // each AP is started once, however many MADT entries list it
+    assert!(status
+        .iter()
+        .enumerate()
+        .all(|(i, (id, _))| status[..i].iter().all(|(other, _)| other != id)));
+    let started: Vec<usize> = status
+        .iter()
+        .filter_map(|(_, status)| match status {
+            ApStatus::Online(cpu_index) | ApStatus::Stuck(cpu_index) => Some(*cpu_index),
+            ApStatus::NoResponse => None,
+        })
+        .collect();
+    let cpus = CPU_COUNT.load(Ordering::SeqCst);
+    assert_eq!(started.len() + 1, cpus);
+    // APs that never responded hand their index on, the others keep it
+    assert!(started.iter().enumerate().all(|(i, &index)| index == i + 1));
+
+    let ran_on = crate::map_all_cpus(|| crate::percpu!(cpu_index));
+    let online: Vec<usize> = (0..cpus)
+        .filter(|&cpu| crate::smp::is_online(cpu))
+        .collect();
+    assert_eq!(ran_on, online);
+}
+crate::smp_test!(test_ap_status_matches_cpu_count, 4);
+
+#[cfg(test)]
+fn test_lock_contention() {
+    const ROUNDS: usize = 10_000;
+    let counter = Mutex::new(0usize);
+    crate::run_on_all_cpus(|| {
+        for _ in 0..ROUNDS {
+            let mut counter = counter.lock();
+            // a read-modify-write that would lose updates if two CPUs got in at once
+            let value = *counter;
+            core::hint::spin_loop();
+            *counter = value + 1;
+        }
+    });
+    assert_eq!(*counter.lock(), ROUNDS * crate::smp::online_count());
+}
+crate::smp_test!(test_lock_contention, 4);
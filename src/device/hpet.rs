@@ -1,4 +1,10 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
 use crate::acpi::hpet::Hpet;
+use crate::mmio::Mmio;
+use crate::pio::Io;
+use crate::serial_println;
+use crate::time::clocksource::{self, rating, ClockSource};
 
 static LEG_RT_CNF: u64 = 2;
 static ENABLE_CNF: u64 = 1;
@@ -13,89 +19,251 @@ static GENERAL_INTERRUPT_OFFSET: usize = 0x20;
 static MAIN_COUNTER_OFFSET: usize = 0xF0;
 // static NUM_TIMER_CAP_MASK: u64 = 0x0f00;
 static LEG_RT_CAP: u64 = 0x8000;
+/// The main counter is 64 bits wide, rather than 32.
+static COUNT_SIZE_CAP: u64 = 0x2000;
 static T0_CONFIG_CAPABILITY_OFFSET: usize = 0x100;
 static T0_COMPARATOR_OFFSET: usize = 0x108;
 
 static PER_INT_CAP: u64 = 0x10;
 
-pub unsafe fn init(hpet: &mut Hpet) -> bool {
-    println!("HPET Before Init");
-    debug(hpet);
+/// Size of the register block.
+const REGISTERS_SIZE: usize = 1024;
 
-    // Disable HPET
-    {
-        let mut config_word = hpet.base_address.read_u64(GENERAL_CONFIG_OFFSET);
-        config_word &= !(LEG_RT_CNF | ENABLE_CNF);
-        hpet.base_address.write_u64(GENERAL_CONFIG_OFFSET, config_word);
-    }
+/// Virtual address of the mapped registers, or 0 if there is no usable HPET.
+static BASE: AtomicU64 = AtomicU64::new(0);
+/// Main counter period, in femtoseconds.
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
 
-    let capability = hpet.base_address.read_u64(CAPABILITY_OFFSET);
-    if capability & LEG_RT_CAP == 0 {
-        log::warn!("HPET missing capability LEG_RT_CAP");
+/// Map the registers of the HPET described by `hpet` and start its main counter, without
+/// enabling any interrupts.
+pub unsafe fn init(hpet: &Hpet) -> bool {
+    let base = match hpet.base_address.map(REGISTERS_SIZE) {
+        Some(base) => base,
+        None => return false,
+    };
+    BASE.store(base.as_u64(), Ordering::SeqCst);
+
+    let capability = read_u64(CAPABILITY_OFFSET);
+    let period_fs = capability >> 32;
+    // the spec caps the period at 100 ns
+    if period_fs == 0 || period_fs > 100_000_000 {
+        serial_println!("HPET has bad counter period {} fs", period_fs);
+        BASE.store(0, Ordering::SeqCst);
         return false;
     }
+    PERIOD_FS.store(period_fs, Ordering::SeqCst);
+
+    let config_word = read_u64(GENERAL_CONFIG_OFFSET);
+    write_u64(GENERAL_CONFIG_OFFSET, config_word | ENABLE_CNF);
+
+    // a 32-bit counter wraps within minutes, which the clock would take for time going backwards
+    if capability & COUNT_SIZE_CAP != 0 {
+        clocksource::register(&HpetClock);
+    } else {
+        serial_println!("HPET counter is 32 bits wide, not using it as a clocksource");
+    }
+    true
+}
 
-    let counter_clk_period_fs = capability >> 32;
-    let desired_fs_period: u64 = 2_250_286 * 1_000_000;
+struct HpetClock;
 
-    let clk_periods_per_kernel_tick: u64 = desired_fs_period / counter_clk_period_fs;
+impl ClockSource for HpetClock {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn rating(&self) -> u32 {
+        rating::HPET
+    }
 
-    let t0_capabilities = hpet.base_address.read_u64(T0_CONFIG_CAPABILITY_OFFSET);
+    fn read_ns(&self) -> u64 {
+        nanos().unwrap_or(0)
+    }
+}
+
+/// Drive the system tick from comparator 0 instead of the BSP's local APIC timer, using the
+/// legacy replacement route to IRQ 0.
+pub unsafe fn enable_system_timer() -> bool {
+    use crate::time::{self, TICK_HZ};
+
+    if BASE.load(Ordering::SeqCst) == 0 {
+        return false;
+    }
+
+    let capability = read_u64(CAPABILITY_OFFSET);
+    if capability & LEG_RT_CAP == 0 {
+        serial_println!("HPET missing capability LEG_RT_CAP");
+        return false;
+    }
+
+    let t0_capabilities = read_u64(T0_CONFIG_CAPABILITY_OFFSET);
     if t0_capabilities & PER_INT_CAP == 0 {
-        log::warn!("HPET T0 missing capability PER_INT_CAP");
+        serial_println!("HPET T0 missing capability PER_INT_CAP");
         return false;
     }
 
-    let counter = hpet.base_address.read_u64(MAIN_COUNTER_OFFSET);
+    // Disable HPET
+    {
+        let mut config_word = read_u64(GENERAL_CONFIG_OFFSET);
+        config_word &= !(LEG_RT_CNF | ENABLE_CNF);
+        write_u64(GENERAL_CONFIG_OFFSET, config_word);
+    }
+
+    let desired_fs_period: u64 = 1_000_000_000_000_000 / u64::from(TICK_HZ);
+    let clk_periods_per_kernel_tick: u64 = desired_fs_period / PERIOD_FS.load(Ordering::SeqCst);
+
+    let counter = read_u64(MAIN_COUNTER_OFFSET);
 
     let t0_config_word: u64 = TN_VAL_SET_CNF | TN_TYPE_CNF | TN_INT_ENB_CNF;
-    hpet.base_address.write_u64(T0_CONFIG_CAPABILITY_OFFSET, t0_config_word);
+    write_u64(T0_CONFIG_CAPABILITY_OFFSET, t0_config_word);
     // set accumulator value
-    hpet.base_address.write_u64(T0_COMPARATOR_OFFSET, counter + clk_periods_per_kernel_tick);
+    write_u64(T0_COMPARATOR_OFFSET, counter + clk_periods_per_kernel_tick);
     // set interval
-    hpet.base_address.write_u64(T0_COMPARATOR_OFFSET, clk_periods_per_kernel_tick);
+    write_u64(T0_COMPARATOR_OFFSET, clk_periods_per_kernel_tick);
+
+    time::use_irq0_tick();
 
     // Enable interrupts from the HPET
     {
-        let mut config_word: u64 = hpet.base_address.read_u64(GENERAL_CONFIG_OFFSET);
+        let mut config_word: u64 = read_u64(GENERAL_CONFIG_OFFSET);
         config_word |= LEG_RT_CNF | ENABLE_CNF;
-        hpet.base_address.write_u64(GENERAL_CONFIG_OFFSET, config_word);
+        write_u64(GENERAL_CONFIG_OFFSET, config_word);
     }
 
-    println!("HPET After Init");
-    debug(hpet);
-
     true
 }
 
-pub unsafe fn debug(hpet: &mut Hpet) {
-    println!("HPET @ {:#x}", hpet.base_address.address);
+/// Undo `enable_system_timer`: stop comparator 0's interrupts and give IRQ 0 back to the PIT,
+/// and the BSP's tick back to its local APIC timer.
+pub unsafe fn disable_system_timer() {
+    use crate::time;
+
+    let t0_config_word = read_u64(T0_CONFIG_CAPABILITY_OFFSET);
+    write_u64(
+        T0_CONFIG_CAPABILITY_OFFSET,
+        t0_config_word & !TN_INT_ENB_CNF,
+    );
+    let config_word = read_u64(GENERAL_CONFIG_OFFSET);
+    write_u64(GENERAL_CONFIG_OFFSET, config_word & !LEG_RT_CNF);
+
+    time::use_lapic_tick();
+}
+
+/// The main counter, or `None` if there is no usable HPET. It counts up at a fixed rate from
+/// when `init` ran, so it works as a monotonic clock source on every CPU.
+pub fn counter() -> Option<u64> {
+    if BASE.load(Ordering::Relaxed) == 0 {
+        return None;
+    }
+    Some(unsafe { read_u64(MAIN_COUNTER_OFFSET) })
+}
+
+/// Main counter period, in femtoseconds.
+pub fn period_fs() -> Option<u64> {
+    match PERIOD_FS.load(Ordering::Relaxed) {
+        0 => None,
+        period => Some(period),
+    }
+}
+
+/// The main counter, in nanoseconds.
+pub fn nanos() -> Option<u64> {
+    let counter = counter()?;
+    Some((u128::from(counter) * u128::from(period_fs()?) / 1_000_000) as u64)
+}
+
+unsafe fn register(offset: usize) -> &'static mut Mmio<u64> {
+    &mut *((BASE.load(Ordering::Relaxed) as usize + offset) as *mut Mmio<u64>)
+}
 
-    let capability = hpet.base_address.read_u64(CAPABILITY_OFFSET);
+unsafe fn read_u64(offset: usize) -> u64 {
+    register(offset).read()
+}
+
+unsafe fn write_u64(offset: usize, value: u64) {
+    register(offset).write(value);
+}
+
+pub unsafe fn debug() {
+    serial_println!("HPET @ {:#x}", BASE.load(Ordering::SeqCst));
+
+    let capability = read_u64(CAPABILITY_OFFSET);
     {
-        println!("  caps: {:#x}", capability);
-        println!("    clock period: {}", (capability >> 32) as u32);
-        println!("    ID: {:#x}", (capability >> 16) as u16);
-        println!("    LEG_RT_CAP: {}", capability & (1 << 15) == (1 << 15));
-        println!("    COUNT_SIZE_CAP: {}", capability & (1 << 13) == (1 << 13));
-        println!("    timers: {}", (capability >> 8) as u8 & 0x1F);
-        println!("    revision: {}", capability as u8);
+        serial_println!("  caps: {:#x}", capability);
+        serial_println!("    clock period: {}", (capability >> 32) as u32);
+        serial_println!("    ID: {:#x}", (capability >> 16) as u16);
+        serial_println!("    LEG_RT_CAP: {}", capability & (1 << 15) == (1 << 15));
+        serial_println!("    COUNT_SIZE_CAP: {}", capability & COUNT_SIZE_CAP != 0);
+        serial_println!("    timers: {}", (capability >> 8) as u8 & 0x1F);
+        serial_println!("    revision: {}", capability as u8);
     }
 
-    let config_word = hpet.base_address.read_u64(GENERAL_CONFIG_OFFSET);
-    println!("  config: {:#x}", config_word);
+    let config_word = read_u64(GENERAL_CONFIG_OFFSET);
+    serial_println!("  config: {:#x}", config_word);
+
+    let interrupt_status = read_u64(GENERAL_INTERRUPT_OFFSET);
+    serial_println!("  interrupt status: {:#x}", interrupt_status);
 
-    let interrupt_status = hpet.base_address.read_u64(GENERAL_INTERRUPT_OFFSET);
-    println!("  interrupt status: {:#x}", interrupt_status);
+    let counter = read_u64(MAIN_COUNTER_OFFSET);
+    serial_println!("  counter: {:#x}", counter);
 
-    let counter = hpet.base_address.read_u64(MAIN_COUNTER_OFFSET);
-    println!("  counter: {:#x}", counter);
+    let t0_capabilities = read_u64(T0_CONFIG_CAPABILITY_OFFSET);
+    serial_println!("  T0 caps: {:#x}", t0_capabilities);
+    serial_println!(
+        "    interrupt routing: {:#x}",
+        (t0_capabilities >> 32) as u32
+    );
+    serial_println!("    flags: {:#x}", t0_capabilities as u16);
+
+    let t0_comparator = read_u64(T0_COMPARATOR_OFFSET);
+    serial_println!("  T0 comparator: {:#x}", t0_comparator);
+}
 
-    let t0_capabilities = hpet.base_address.read_u64(T0_CONFIG_CAPABILITY_OFFSET);
-    println!("  T0 caps: {:#x}", t0_capabilities);
-    println!("    interrupt routing: {:#x}", (t0_capabilities >> 32) as u32);
-    println!("    flags: {:#x}", t0_capabilities as u16);
+#[test_case]
+fn test_hpet_counter() {
+    use crate::device::pit;
+
+    // QEMU always emulates one
+    let start = counter().expect("no HPET");
+    unsafe { pit::wait_us(50_000) };
+    let elapsed = counter().unwrap() - start;
+
+    let elapsed_us = u128::from(elapsed) * u128::from(period_fs().unwrap()) / 1_000_000_000;
+    // within 2%
+    assert!(
+        (elapsed_us as u64).abs_diff(50_000) <= 1000,
+        "HPET counted {} us in 50 ms",
+        elapsed_us
+    );
+}
+
+// with hpet_timer, the tick already comes from comparator 0 and has nothing else to go back to
+#[cfg(not(feature = "hpet_timer"))]
+#[test_case]
+fn test_system_timer() {
+    use x86_64::instructions::interrupts;
+
+    use crate::device::{local_apic, pit};
+    use crate::time::{self, TICK_HZ};
+
+    const WINDOW_MS: u64 = 200;
+    assert!(local_apic::timer_calibrated());
+    assert!(unsafe { enable_system_timer() }, "HPET can't drive IRQ 0");
+    let start = time::ticks();
+    interrupts::enable();
+    for _ in 0..WINDOW_MS / 50 {
+        unsafe { pit::wait_us(50_000) };
+    }
+    interrupts::disable();
+    let measured = time::ticks() - start;
+    unsafe { disable_system_timer() };
 
-    let t0_comparator = hpet.base_address.read_u64(T0_COMPARATOR_OFFSET);
-    println!("  T0 comparator: {:#x}", t0_comparator);
+    let expected = WINDOW_MS * u64::from(TICK_HZ) / 1000;
+    assert!(
+        measured.abs_diff(expected) <= 2,
+        "{} IRQ 0 ticks in {} ms, expected {}",
+        measured,
+        WINDOW_MS,
+        expected
+    );
 }
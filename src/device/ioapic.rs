@@ -1,47 +1,38 @@
-use core::{fmt, ptr};
+use core::fmt;
 
 use alloc::vec::Vec;
 use spin::Mutex;
-use x86_64::structures::paging::OffsetPageTable;
 
 #[cfg(feature = "acpi")]
 use crate::acpi::madt::{self, Madt, MadtEntry, MadtIntSrcOverride, MadtIoApic};
 
-// use crate::arch::interrupt::irq;
+use crate::interrupts::irq::{self, IrqMethod};
 // use crate::memory::Frame;
 // use crate::paging::{ActivePageTable, Page, PageFlags, PhysicalAddress, VirtualAddress};
 // use crate::paging::entry::EntryFlags;
+use crate::mmio::Mmio;
+use crate::pio::Io;
 use crate::serial_println;
 
 use super::pic;
 
+/// The I/O APIC's register window: an index register, and a data register for the selected
+/// internal register.
+#[repr(C)]
 pub struct IoApicRegs {
-    pointer: *const u32,
+    ioregsel: Mmio<u32>,
+    _reserved: [u32; 3],
+    iowin: Mmio<u32>,
 }
+const _: () = assert!(core::mem::size_of::<IoApicRegs>() == 0x14);
 impl IoApicRegs {
-    fn ioregsel(&self) -> *const u32 {
-        self.pointer
-    }
-    fn iowin(&self) -> *const u32 {
-        // offset 0x10
-        unsafe { self.pointer.offset(4) }
-    }
-    fn write_ioregsel(&mut self, value: u32) {
-        unsafe { ptr::write_volatile::<u32>(self.ioregsel() as *mut u32, value) }
-    }
-    fn read_iowin(&self) -> u32 {
-        unsafe { ptr::read_volatile::<u32>(self.iowin()) }
-    }
-    fn write_iowin(&mut self, value: u32) {
-        unsafe { ptr::write_volatile::<u32>(self.iowin() as *mut u32, value) }
-    }
     fn read_reg(&mut self, reg: u8) -> u32 {
-        self.write_ioregsel(reg.into());
-        self.read_iowin()
+        self.ioregsel.write(reg.into());
+        self.iowin.read()
     }
     fn write_reg(&mut self, reg: u8, value: u32) {
-        self.write_ioregsel(reg.into());
-        self.write_iowin(value);
+        self.ioregsel.write(reg.into());
+        self.iowin.write(value);
     }
     pub fn read_ioapicid(&mut self) -> u32 {
         self.read_reg(0x00)
@@ -82,13 +73,12 @@ impl IoApicRegs {
     }
 }
 pub struct IoApic {
-    regs: Mutex<IoApicRegs>,
+    regs: Mutex<&'static mut IoApicRegs>,
     gsi_start: u32,
     count: u8,
 }
 impl IoApic {
-    pub fn new(regs_base: *const u32, gsi_start: u32) -> Self {
-        let mut regs = IoApicRegs { pointer: regs_base };
+    pub fn new(regs: &'static mut IoApicRegs, gsi_start: u32) -> Self {
         let count = regs.max_redirection_table_entries();
 
         Self {
@@ -170,7 +160,7 @@ impl MapInfo {
 
 impl fmt::Debug for IoApic {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        struct RedirTable<'a>(&'a Mutex<IoApicRegs>);
+        struct RedirTable<'a>(&'a Mutex<&'static mut IoApicRegs>);
 
         impl<'a> fmt::Debug for RedirTable<'a> {
             fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -221,6 +211,10 @@ static mut IOAPICS: Option<Vec<IoApic>> = None;
 // static mut for the same reason as above
 static mut SRC_OVERRIDES: Option<Vec<Override>> = None;
 
+/// What telling the firmware about APIC mode through `\_PIC` returned, once `init` did.
+#[cfg(feature = "acpi")]
+static PIC_RESULT: spin::Once<Result<(), crate::acpi::aml::AmlError>> = spin::Once::new();
+
 pub fn ioapics() -> &'static [IoApic] {
     unsafe { IOAPICS.as_ref().map_or(&[], |vector| &vector[..]) }
 }
@@ -229,44 +223,20 @@ pub fn src_overrides() -> &'static [Override] {
 }
 
 #[cfg(feature = "acpi")]
-pub unsafe fn handle_ioapic(active_table: &mut OffsetPageTable, madt_ioapic: &'static MadtIoApic) {
-    // map the I/O APIC registers
+pub unsafe fn handle_ioapic(madt_ioapic: &'static MadtIoApic) {
+    use x86_64::PhysAddr;
 
-    use core::ops::DerefMut;
+    use crate::memory::{map_mmio, CacheMode};
+    use crate::mmio::register_block;
 
-    use x86_64::{
-        structures::paging::{
-            mapper::TranslateError, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
-        },
-        PhysAddr, VirtAddr,
-    };
-
-    use crate::memory::FRAME_ALLOC;
-
-    let frame =
-        PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(madt_ioapic.address as u64));
-    let page = Page::containing_address(VirtAddr::new(
-        madt_ioapic.address as u64 + crate::PHYS_OFFSET,
-    ));
-
-    assert!(matches!(
-        active_table.translate_page(page),
-        Err(TranslateError::PageNotMapped)
-    ));
-
-    let result = unsafe {
-        active_table
-            .map_to(
-                page,
-                frame,
-                PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE,
-                FRAME_ALLOC.lock().deref_mut(),
-            )
-            .unwrap()
-    };
-    result.flush();
+    // map the I/O APIC registers
+    let region = map_mmio(
+        PhysAddr::new(madt_ioapic.address as u64),
+        core::mem::size_of::<IoApicRegs>(),
+        CacheMode::Uncached,
+    );
 
-    let ioapic_registers = page.start_address().as_u64() as *const u32;
+    let ioapic_registers = register_block::<IoApicRegs>(region.leak());
     let ioapic = IoApic::new(ioapic_registers, madt_ioapic.gsi_base);
     assert_eq!(
         ioapic.regs.lock().id(),
@@ -309,7 +279,7 @@ pub unsafe fn handle_src_override(src_override: &'static MadtIntSrcOverride) {
     SRC_OVERRIDES.get_or_insert_with(Vec::new).push(over);
 }
 
-pub unsafe fn init(active_table: &mut OffsetPageTable) {
+pub unsafe fn init() {
     let bsp_apic_id = x86::cpuid::CpuId::new()
         .get_feature_info()
         .unwrap()
@@ -323,19 +293,23 @@ pub unsafe fn init(active_table: &mut OffsetPageTable) {
             // TODO: Parse MP tables too.
             None => return,
         };
-        if madt.flags & madt::FLAG_PCAT != 0 {
-            pic::disable();
-        }
 
         // find all I/O APICs (usually one).
 
         for entry in madt.iter() {
             match entry {
-                MadtEntry::IoApic(ioapic) => handle_ioapic(active_table, ioapic),
+                MadtEntry::IoApic(ioapic) => handle_ioapic(ioapic),
                 MadtEntry::IntSrcOverride(src_override) => handle_src_override(src_override),
                 _ => (),
             }
         }
+        if ioapics().is_empty() {
+            // keep the legacy IRQs on the 8259
+            return;
+        }
+        if madt.flags & madt::FLAG_PCAT != 0 {
+            pic::disable();
+        }
     }
     serial_println!(
         "I/O APICs: {:?}, overrides: {:?}",
@@ -380,7 +354,8 @@ pub unsafe fn init(active_table: &mut OffsetPageTable) {
             dest: bsp_apic_id,
             dest_mode: DestinationMode::Physical,
             delivery_mode: DeliveryMode::Fixed,
-            mask: false,
+            // set_irq_method unmasks the IRQs that have handlers
+            mask: true,
             polarity: match polarity {
                 Polarity::ActiveHigh => ApicPolarity::ActiveHigh,
                 Polarity::ActiveLow => ApicPolarity::ActiveLow,
@@ -400,28 +375,20 @@ pub unsafe fn init(active_table: &mut OffsetPageTable) {
         ioapics(),
         src_overrides()
     );
-    // irq::set_irq_method(irq::IrqMethod::Apic);
+    irq::set_irq_method(IrqMethod::Apic);
 
     // tell the firmware that we're using APIC rather than the default 8259 PIC.
-
-    // FIXME: With ACPI moved to userspace, we should instead allow userspace to check whether the
-    // IOAPIC has been initialized, and then subsequently let some ACPI driver call the AML from
-    // userspace.
-
-    /*#[cfg(feature = "acpi")]
+    #[cfg(feature = "acpi")]
     {
-        let method = {
-            let namespace_guard = crate::acpi::ACPI_TABLE.namespace.read();
-            if let Some(value) = namespace_guard.as_ref().unwrap().get("\\_PIC") {
-                value.get_as_method().ok()
-            } else {
-                None
-            }
-        };
-        if let Some(m) = method {
-            m.execute("\\_PIC".into(), vec!(crate::acpi::aml::AmlValue::Integer(1)));
+        use crate::acpi::aml::{self, AmlError, AmlValue};
+
+        let result = aml::evaluate("\\_PIC", alloc::vec![AmlValue::Integer(1)]).map(|_| ());
+        match result {
+            Ok(()) | Err(AmlError::NameNotFound(_)) => (),
+            Err(ref error) => serial_println!("\\_PIC failed: {:?}", error),
         }
-    }*/
+        PIC_RESULT.call_once(|| result);
+    }
 }
 fn get_override(irq: u8) -> Option<&'static Override> {
     src_overrides().iter().find(|over| over.bus_irq == irq)
@@ -451,3 +418,16 @@ pub unsafe fn unmask(irq: u8) {
     };
     apic.set_mask(gsi, false);
 }
+
+#[cfg(feature = "acpi")]
+#[test_case]
+fn test_pic_evaluated_at_boot() {
+    use crate::acpi::aml::AmlError;
+
+    let result = PIC_RESULT.get().expect("init didn't evaluate \\_PIC");
+    assert!(
+        matches!(result, Ok(()) | Err(AmlError::NameNotFound(_))),
+        "{:?}",
+        result
+    );
+}
@@ -1,13 +1,20 @@
-use core::intrinsics::{volatile_load, volatile_store};
-use core::sync::atomic::{self, AtomicU64};
+use core::sync::atomic::{self, AtomicU32, AtomicU64};
 use x86::cpuid::CpuId;
 use x86::msr::*;
-use x86_64::structures::paging::{OffsetPageTable, Page, PhysFrame, Size4KiB};
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::OffsetPageTable;
 use x86_64::{PhysAddr, VirtAddr};
 
 // use crate::memory::Frame;
 // use crate::paging::{ActivePageTable, PhysAddr, Page, PageFlags, VirtAddr};
+use crate::device::{pit, tsc};
+use crate::interrupts::irq::{LAPIC_ERROR_VECTOR, LAPIC_TIMER_VECTOR, SPURIOUS_VECTOR};
+use crate::memory::{map_mmio, CacheMode};
+use crate::mmio::{register_block, Mmio};
+use crate::pio::{Io, ReadOnly, WriteOnly};
 use crate::serial_println;
+use crate::time::clocksource::{self, rating, ClockSource};
+use crate::time::{self, TICK_HZ, TICK_NS};
 
 pub static mut LOCAL_APIC: LocalApic = LocalApic {
     address: 0,
@@ -22,6 +29,45 @@ pub unsafe fn init_ap() {
     LOCAL_APIC.init_ap();
 }
 
+/// One xAPIC register. They are 32 bits wide, but sit 16 bytes apart.
+#[repr(C)]
+struct XapicRegister<R> {
+    value: R,
+    _reserved: [u32; 3],
+}
+
+/// A gap of `N` unused xAPIC registers.
+type XapicReserved<const N: usize> = [[u32; 4]; N];
+
+/// The xAPIC register page, up to the divide configuration register.
+#[repr(C)]
+struct XapicRegisters {
+    _reserved0: XapicReserved<2>,
+    id: XapicRegister<ReadOnly<Mmio<u32>>>,
+    version: XapicRegister<ReadOnly<Mmio<u32>>>,
+    _reserved1: XapicReserved<7>,
+    eoi: XapicRegister<WriteOnly<Mmio<u32>>>,
+    _reserved2: XapicReserved<3>,
+    spurious: XapicRegister<Mmio<u32>>,
+    _reserved3: XapicReserved<0x18>,
+    esr: XapicRegister<Mmio<u32>>,
+    _reserved4: XapicReserved<7>,
+    icr_low: XapicRegister<Mmio<u32>>,
+    icr_high: XapicRegister<Mmio<u32>>,
+    lvt_timer: XapicRegister<Mmio<u32>>,
+    _reserved5: XapicReserved<4>,
+    lvt_error: XapicRegister<Mmio<u32>>,
+    init_count: XapicRegister<Mmio<u32>>,
+    cur_count: XapicRegister<ReadOnly<Mmio<u32>>>,
+    _reserved6: XapicReserved<4>,
+    div_conf: XapicRegister<Mmio<u32>>,
+    _reserved7: XapicReserved<1>,
+}
+const _: () = assert!(core::mem::size_of::<XapicRegisters>() == 0x400);
+const _: () = assert!(core::mem::offset_of!(XapicRegisters, eoi) == 0xB0);
+const _: () = assert!(core::mem::offset_of!(XapicRegisters, icr_low) == 0x300);
+const _: () = assert!(core::mem::offset_of!(XapicRegisters, div_conf) == 0x3E0);
+
 /// Local APIC
 pub struct LocalApic {
     pub address: usize,
@@ -31,8 +77,24 @@ pub struct LocalApic {
 #[derive(Debug)]
 struct NoFreqInfo;
 
+/// Timer counts per millisecond at `TIMER_DIVIDE`, measured once on the BSP. Every local APIC
+/// timer runs off the same bus or core crystal clock, so APs reuse it.
+static TIMER_COUNTS_PER_MS: AtomicU32 = AtomicU32::new(0);
+
+/// Divide configuration value for divide by 16.
+const TIMER_DIVIDE: u32 = 0b0011;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_MODE_MASK: u32 = 0b11 << 17;
+/// How long to measure the timer against the PIT for.
+const CALIBRATION_MS: u32 = 10;
+
 static BSP_APIC_ID: AtomicU64 = AtomicU64::new(0xFFFF_FFFF_FFFF_FFFF);
 
+/// Whether the local APIC timer was calibrated, and so can be started.
+pub fn timer_calibrated() -> bool {
+    TIMER_COUNTS_PER_MS.load(atomic::Ordering::SeqCst) != 0
+}
+
 #[no_mangle]
 pub fn bsp_apic_id() -> Option<u32> {
     let value = BSP_APIC_ID.load(atomic::Ordering::SeqCst);
@@ -45,69 +107,52 @@ pub fn bsp_apic_id() -> Option<u32> {
 
 impl LocalApic {
     unsafe fn init(&mut self, _active_table: &mut OffsetPageTable) {
-        self.address = ((rdmsr(IA32_APIC_BASE) & 0xFFFF_0000) + crate::PHYS_OFFSET) as usize;
         self.x2 = CpuId::new().get_feature_info().unwrap().has_x2apic();
 
         if !self.x2 {
-            let _page = Page::<Size4KiB>::containing_address(VirtAddr::new(self.address as u64));
-            let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(
-                self.address as u64 - crate::PHYS_OFFSET,
-            ));
-            serial_println!("Detected xAPIC at {:#x}", frame.start_address().as_u64());
-            // TODO: our bootloader happens to identity map this region, we need to actually check and unmap the page if it doesn't.
-            // if active_table.translate_page(page).is_ok() {
-            //     // Unmap xAPIC page if already mapped
-            //     // let (result, _frame) = active_table.unmap_return(page, true);
-            //     let (_frame, result) = active_table.unmap(page).unwrap();
-            //     result.flush();
-            // }
-            // serial_println!(
-            //     "page now maps to {:?}",
-            //     active_table.translate(page.start_address())
-            // );
-            // let result = unsafe {
-            //     active_table
-            //         .map_to(
-            //             page,
-            //             frame,
-            //             PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
-            //             FRAME_ALLOC.lock().deref_mut(),
-            //         )
-            //         .unwrap()
-            // };
-            // result.flush();
+            let phys = PhysAddr::new(rdmsr(IA32_APIC_BASE) & 0xFFFF_0000);
+            serial_println!("Detected xAPIC at {:#x}", phys.as_u64());
+            self.address = map_mmio(phys, 4096, CacheMode::Uncached).leak().as_u64() as usize;
         } else {
             serial_println!("Detected x2APIC");
         }
 
+        // calibration goes through the x2APIC MSRs, which only work once x2APIC mode is on
+        self.enable();
+        self.calibrate_timer();
         self.init_ap();
         BSP_APIC_ID.store(u64::from(self.id()), atomic::Ordering::SeqCst);
     }
 
     unsafe fn init_ap(&mut self) {
+        self.enable();
+        self.setup_error_int();
+        self.setup_timer();
+    }
+
+    /// Switch to x2APIC mode if we use it, and software-enable the local APIC.
+    unsafe fn enable(&mut self) {
         if self.x2 {
             wrmsr(IA32_APIC_BASE, rdmsr(IA32_APIC_BASE) | 1 << 10);
-            wrmsr(IA32_X2APIC_SIVR, 0x100);
+            wrmsr(IA32_X2APIC_SIVR, 0x100 | u64::from(SPURIOUS_VECTOR));
         } else {
-            self.write(0xF0, 0x100);
+            self.regs()
+                .spurious
+                .value
+                .write(0x100 | u32::from(SPURIOUS_VECTOR));
         }
-        self.setup_error_int();
-        //self.setup_timer();
     }
 
-    unsafe fn read(&self, reg: u32) -> u32 {
-        volatile_load((self.address + reg as usize) as *const u32)
-    }
-
-    unsafe fn write(&mut self, reg: u32, value: u32) {
-        volatile_store((self.address + reg as usize) as *mut u32, value);
+    unsafe fn regs(&self) -> &'static mut XapicRegisters {
+        register_block(VirtAddr::new(self.address as u64))
     }
 
     pub fn id(&self) -> u32 {
         if self.x2 {
             unsafe { rdmsr(IA32_X2APIC_APICID) as u32 }
         } else {
-            unsafe { self.read(0x20) }
+            // the xAPIC ID sits in the top byte
+            unsafe { self.regs().id.value.read() >> 24 }
         }
     }
 
@@ -115,7 +160,7 @@ impl LocalApic {
         if self.x2 {
             unsafe { rdmsr(IA32_X2APIC_VERSION) as u32 }
         } else {
-            unsafe { self.read(0x30) }
+            unsafe { self.regs().version.value.read() }
         }
     }
 
@@ -123,7 +168,10 @@ impl LocalApic {
         if self.x2 {
             unsafe { rdmsr(IA32_X2APIC_ICR) }
         } else {
-            unsafe { (self.read(0x310) as u64) << 32 | self.read(0x300) as u64 }
+            unsafe {
+                (self.regs().icr_high.value.read() as u64) << 32
+                    | self.regs().icr_low.value.read() as u64
+            }
         }
     }
 
@@ -133,26 +181,29 @@ impl LocalApic {
                 wrmsr(IA32_X2APIC_ICR, value);
             }
         } else {
-            unsafe {
+            // the destination and the command are two writes; an IPI sent from an interrupt
+            // handler in between would take over the destination
+            interrupts::without_interrupts(|| unsafe {
                 const PENDING: u32 = 1 << 12;
-                while self.read(0x300) & PENDING == PENDING {
+                while self.regs().icr_low.value.read() & PENDING == PENDING {
                     core::hint::spin_loop();
                 }
-                self.write(0x310, (value >> 32) as u32);
-                self.write(0x300, value as u32);
-                while self.read(0x300) & PENDING == PENDING {
+                self.regs().icr_high.value.write((value >> 32) as u32);
+                self.regs().icr_low.value.write(value as u32);
+                while self.regs().icr_low.value.read() & PENDING == PENDING {
                     core::hint::spin_loop();
                 }
-            }
+            });
         }
     }
 
-    pub fn ipi(&mut self, apic_id: usize) {
-        let mut icr = 0x4040;
+    /// Send a fixed interrupt on `vector` to the CPU with APIC ID `apic_id`.
+    pub fn ipi(&mut self, apic_id: u32, vector: u8) {
+        let mut icr = 0x4000 | u64::from(vector);
         if self.x2 {
-            icr |= (apic_id as u64) << 32;
+            icr |= u64::from(apic_id) << 32;
         } else {
-            icr |= (apic_id as u64) << 56;
+            icr |= u64::from(apic_id) << 56;
         }
         self.set_icr(icr);
     }
@@ -162,11 +213,23 @@ impl LocalApic {
         self.set_icr((u64::from(apic_id) << shift) | (1 << 14) | (0b100 << 8));
     }
 
+    /// Send an INIT IPI, which resets the target and leaves it waiting for a startup IPI.
+    pub fn ipi_init(&mut self, apic_id: u32) {
+        let shift = if self.x2 { 32 } else { 56 };
+        self.set_icr((u64::from(apic_id) << shift) | (1 << 14) | (0b101 << 8));
+    }
+
+    /// Send a startup IPI, which starts the target in real mode at `page` * 4 KiB.
+    pub fn ipi_startup(&mut self, apic_id: u32, page: u8) {
+        let shift = if self.x2 { 32 } else { 56 };
+        self.set_icr((u64::from(apic_id) << shift) | (1 << 14) | (0b110 << 8) | u64::from(page));
+    }
+
     pub unsafe fn eoi(&mut self) {
         if self.x2 {
             wrmsr(IA32_X2APIC_EOI, 0);
         } else {
-            self.write(0xB0, 0);
+            self.regs().eoi.value.write(0);
         }
     }
     /// Reads the Error Status Register.
@@ -177,79 +240,163 @@ impl LocalApic {
             // read the updated value
             rdmsr(IA32_X2APIC_ESR) as u32
         } else {
-            self.write(0x280, 0);
-            self.read(0x280)
+            self.regs().esr.value.write(0);
+            self.regs().esr.value.read()
         }
     }
     pub unsafe fn lvt_timer(&mut self) -> u32 {
         if self.x2 {
             rdmsr(IA32_X2APIC_LVT_TIMER) as u32
         } else {
-            self.read(0x320)
+            self.regs().lvt_timer.value.read()
         }
     }
     pub unsafe fn set_lvt_timer(&mut self, value: u32) {
         if self.x2 {
             wrmsr(IA32_X2APIC_LVT_TIMER, u64::from(value));
         } else {
-            self.write(0x320, value);
+            self.regs().lvt_timer.value.write(value);
         }
     }
     pub unsafe fn init_count(&mut self) -> u32 {
         if self.x2 {
             rdmsr(IA32_X2APIC_INIT_COUNT) as u32
         } else {
-            self.read(0x380)
+            self.regs().init_count.value.read()
         }
     }
     pub unsafe fn set_init_count(&mut self, initial_count: u32) {
         if self.x2 {
             wrmsr(IA32_X2APIC_INIT_COUNT, u64::from(initial_count));
         } else {
-            self.write(0x380, initial_count);
+            self.regs().init_count.value.write(initial_count);
         }
     }
     pub unsafe fn cur_count(&mut self) -> u32 {
         if self.x2 {
             rdmsr(IA32_X2APIC_CUR_COUNT) as u32
         } else {
-            self.read(0x390)
+            self.regs().cur_count.value.read()
         }
     }
     pub unsafe fn div_conf(&mut self) -> u32 {
         if self.x2 {
             rdmsr(IA32_X2APIC_DIV_CONF) as u32
         } else {
-            self.read(0x3E0)
+            self.regs().div_conf.value.read()
         }
     }
     pub unsafe fn set_div_conf(&mut self, div_conf: u32) {
         if self.x2 {
             wrmsr(IA32_X2APIC_DIV_CONF, u64::from(div_conf));
         } else {
-            self.write(0x3E0, div_conf);
+            self.regs().div_conf.value.write(div_conf);
         }
     }
     pub unsafe fn lvt_error(&mut self) -> u32 {
         if self.x2 {
             rdmsr(IA32_X2APIC_LVT_ERROR) as u32
         } else {
-            self.read(0x370)
+            self.regs().lvt_error.value.read()
         }
     }
     pub unsafe fn set_lvt_error(&mut self, lvt_error: u32) {
         if self.x2 {
             wrmsr(IA32_X2APIC_LVT_ERROR, u64::from(lvt_error));
         } else {
-            self.write(0x370, lvt_error);
+            self.regs().lvt_error.value.write(lvt_error);
         }
     }
     unsafe fn setup_error_int(&mut self) {
-        let vector = 49u32;
-        self.set_lvt_error(vector);
+        self.set_lvt_error(u32::from(LAPIC_ERROR_VECTOR));
+    }
+
+    /// Measure the timer's rate against PIT channel 2.
+    unsafe fn calibrate_timer(&mut self) {
+        self.set_div_conf(TIMER_DIVIDE);
+        self.set_lvt_timer(LVT_MASKED | (LvtTimerMode::OneShot as u32) << 17);
+        self.set_init_count(u32::MAX);
+        pit::wait_us(u64::from(CALIBRATION_MS) * 1000);
+        let elapsed = u32::MAX - self.cur_count();
+        self.set_init_count(0);
+
+        let per_ms = elapsed / CALIBRATION_MS;
+        if per_ms == 0 {
+            // init_noncore falls back to the PIT
+            serial_println!("Local APIC timer isn't counting");
+            return;
+        }
+        serial_println!("Local APIC timer: {} kHz at divide by 16", per_ms);
+        TIMER_COUNTS_PER_MS.store(per_ms, atomic::Ordering::SeqCst);
+        clocksource::register(&LapicTickClock);
+    }
+
+    /// Start the system tick on this CPU, if the timer works.
+    unsafe fn setup_timer(&mut self) {
+        if !timer_calibrated() {
+            return;
+        }
+        self.start_timer(LvtTimerMode::Periodic, 1_000_000 / TICK_HZ);
+    }
+
+    /// Fire `LAPIC_TIMER_VECTOR` every `micros` microseconds in periodic mode, or once after
+    /// `micros` microseconds in one-shot and TSC-deadline mode. Replaces whatever the timer was
+    /// doing.
+    pub unsafe fn start_timer(&mut self, mode: LvtTimerMode, micros: u32) {
+        if mode == LvtTimerMode::TscDeadline {
+            self.arm_deadline(tsc::read() + tsc::us_to_cycles(u64::from(micros)));
+            return;
+        }
+        let per_ms = TIMER_COUNTS_PER_MS.load(atomic::Ordering::SeqCst);
+        assert!(per_ms != 0, "local APIC timer not calibrated");
+        let count = (u64::from(per_ms) * u64::from(micros) / 1000).clamp(1, u64::from(u32::MAX));
+
+        self.set_div_conf(TIMER_DIVIDE);
+        self.set_lvt_timer((mode as u32) << 17 | u32::from(LAPIC_TIMER_VECTOR));
+        // writing the initial count starts the timer
+        self.set_init_count(count as u32);
+    }
+
+    /// Fire `LAPIC_TIMER_VECTOR` once, when this CPU's TSC reaches `deadline`. A deadline in
+    /// the past fires immediately. Replaces whatever the timer was doing.
+    pub unsafe fn arm_deadline(&mut self, deadline: u64) {
+        assert!(tsc::has_deadline_mode(), "no TSC-deadline mode");
+        if self.lvt_timer() & LVT_MODE_MASK != (LvtTimerMode::TscDeadline as u32) << 17 {
+            self.set_lvt_timer(
+                (LvtTimerMode::TscDeadline as u32) << 17 | u32::from(LAPIC_TIMER_VECTOR),
+            );
+            // the MMIO write switching modes has to land before the MSR write arming it
+            atomic::fence(atomic::Ordering::SeqCst);
+        }
+        // 0 disarms the timer
+        wrmsr(IA32_TSC_DEADLINE, deadline.max(1));
+    }
+
+    /// Stop this CPU's timer. Leaving TSC-deadline mode also disarms any deadline.
+    pub unsafe fn stop_timer(&mut self) {
+        self.set_lvt_timer(LVT_MASKED);
+        self.set_init_count(0);
+    }
+}
+
+/// The tick count the local APIC timers drive.
+struct LapicTickClock;
+
+impl ClockSource for LapicTickClock {
+    fn name(&self) -> &'static str {
+        "lapic-tick"
+    }
+
+    fn rating(&self) -> u32 {
+        rating::LAPIC_TICK
+    }
+
+    fn read_ns(&self) -> u64 {
+        time::ticks() * TICK_NS
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[repr(u8)]
 pub enum LvtTimerMode {
     OneShot = 0b00,
@@ -2,53 +2,57 @@
 
 use x86_64::structures::paging::OffsetPageTable;
 
+use crate::serial_println;
+
 // pub mod cpu;
 pub mod ioapic;
 pub mod local_apic;
 pub mod pic;
-// pub mod pit;
-// pub mod rtc;
+pub mod pit;
+pub mod rtc;
 // pub mod serial;
-// #[cfg(feature = "acpi")]
-// pub mod hpet;
+#[cfg(feature = "acpi")]
+pub mod hpet;
+pub mod tsc;
 // #[cfg(feature = "system76_ec_debug")]
 // pub mod system76_ec;
 
 pub unsafe fn init(active_table: &mut OffsetPageTable) {
     pic::init();
+    tsc::init();
     local_apic::init(active_table);
 }
 pub unsafe fn init_after_acpi(_active_table: &mut OffsetPageTable) {
-    // this will disable the IOAPIC if needed.
-    //ioapic::init(active_table);
+    // this will disable the 8259 PIC if there's an I/O APIC to take over
+    ioapic::init();
+
+    init_noncore();
+}
+
+#[cfg(feature = "hpet_timer")]
+unsafe fn init_hpet() -> bool {
+    hpet::enable_system_timer()
 }
 
-// #[cfg(feature = "acpi")]
-// unsafe fn init_hpet() -> bool {
-//     use crate::acpi::ACPI_TABLE;
-//     if let Some(ref mut hpet) = *ACPI_TABLE.hpet.write() {
-//         hpet::init(hpet)
-//     } else {
-//         false
-//     }
-// }
-
-#[cfg(not(feature = "acpi"))]
+#[cfg(not(feature = "hpet_timer"))]
 unsafe fn init_hpet() -> bool {
     false
 }
 
-// pub unsafe fn init_noncore() {
-//     if init_hpet() {
-//         log::info!("HPET used as system timer");
-//     } else {
-//         pit::init();
-//         log::info!("PIT used as system timer");
-//     }
-
-//     rtc::init();
-//     serial::init();
-// }
+/// Pick the system timer and read the wall clock. Runs once the ACPI tables are parsed.
+pub unsafe fn init_noncore() {
+    if init_hpet() {
+        serial_println!("HPET used as system timer");
+    } else if !local_apic::timer_calibrated() {
+        pit::init();
+        serial_println!("PIT used as system timer");
+    } else {
+        serial_println!("Local APIC timer used as system timer");
+    }
+
+    rtc::init();
+    // serial::init();
+}
 
 pub unsafe fn init_ap() {
     local_apic::init_ap();
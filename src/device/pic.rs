@@ -1,6 +1,4 @@
-// use crate::syscall::io::{Io, Pio};
-// use crate::arch::interrupt::irq;
-
+use crate::interrupts::irq::{self, IrqMethod};
 use crate::pio::{Io, Pio};
 
 pub static mut MASTER: Pic = Pic::new(0x20);
@@ -23,16 +21,16 @@ pub unsafe fn init() {
     MASTER.data.write(1);
     SLAVE.data.write(1);
 
-    // Unmask interrupts
-    MASTER.data.write(0);
-    SLAVE.data.write(0);
+    // Mask everything but the cascade; register_irq unmasks IRQs as handlers show up
+    MASTER.data.write(0xFF & !(1 << 2));
+    SLAVE.data.write(0xFF);
 
     // Ack remaining interrupts
     MASTER.ack();
     SLAVE.ack();
 
     // probably already set to PIC, but double-check
-    // irq::set_irq_method(irq::IrqMethod::Pic);
+    irq::set_irq_method(IrqMethod::Pic);
 }
 
 pub unsafe fn disable() {
@@ -1,17 +1,86 @@
-use crate::syscall::io::{Io, Pio};
+use core::ptr::addr_of_mut;
+
+use crate::pio::{Io, Pio};
+use crate::time::clocksource::{self, rating, ClockSource};
+use crate::time::{self, TICK_HZ, TICK_NS};
 
 pub static mut CHAN0: Pio<u8> = Pio::new(0x40);
 pub static mut CHAN1: Pio<u8> = Pio::new(0x41);
 pub static mut CHAN2: Pio<u8> = Pio::new(0x42);
 pub static mut COMMAND: Pio<u8> = Pio::new(0x43);
+/// NMI status and control, a.k.a. port B. Bit 0 gates channel 2, bit 1 connects it to the
+/// speaker, and bit 5 reads back channel 2's output.
+pub static mut PORT_B: Pio<u8> = Pio::new(0x61);
 
 static SELECT_CHAN0: u8 = 0;
+static SELECT_CHAN2: u8 = 0x80;
 static LOHI: u8 = 0x30;
+/// Mode 2, binary counting.
+static RATE_GENERATOR: u8 = 0x04;
+
+/// Input clock of every channel, in Hz.
+pub const PIT_HZ: u64 = 1_193_182;
 
-static CHAN0_DIVISOR: u16 = 2685;
+static CHAN0_DIVISOR: u16 = (PIT_HZ / TICK_HZ as u64) as u16;
 
+const CHAN2_GATE: u8 = 1 << 0;
+const CHAN2_SPEAKER: u8 = 1 << 1;
+const CHAN2_OUT: u8 = 1 << 5;
+
+/// Drive the system tick from channel 0, for machines whose local APIC timer doesn't work.
 pub unsafe fn init() {
-    COMMAND.write(SELECT_CHAN0 | LOHI | 5);
+    COMMAND.write(SELECT_CHAN0 | LOHI | RATE_GENERATOR);
     CHAN0.write((CHAN0_DIVISOR & 0xFF) as u8);
     CHAN0.write((CHAN0_DIVISOR >> 8) as u8);
+    time::use_irq0_tick();
+    clocksource::register(&PitTickClock);
+}
+
+/// The tick count, once channel 0 drives it.
+struct PitTickClock;
+
+impl ClockSource for PitTickClock {
+    fn name(&self) -> &'static str {
+        "pit-tick"
+    }
+
+    fn rating(&self) -> u32 {
+        rating::PIT_TICK
+    }
+
+    fn read_ns(&self) -> u64 {
+        time::ticks() * TICK_NS
+    }
+}
+
+/// Spin for `micros` microseconds, timed by channel 2 in one-shot mode. Doesn't need
+/// interrupts, and leaves channel 0 alone. The counter is 16 bits wide, so one call can wait
+/// at most about 54 ms.
+///
+/// Channel 2 is shared by the whole machine, so callers on different CPUs must not overlap.
+pub unsafe fn wait_us(micros: u64) {
+    let count = micros * PIT_HZ / 1_000_000;
+    assert!(
+        count > 0 && count <= 0xFFFF,
+        "can't wait {} us on the PIT",
+        micros
+    );
+
+    let port_b = &mut *addr_of_mut!(PORT_B);
+    let chan2 = &mut *addr_of_mut!(CHAN2);
+
+    // stop the channel and keep it away from the speaker while it's programmed
+    let gate = port_b.read() & !(CHAN2_GATE | CHAN2_SPEAKER);
+    port_b.write(gate);
+
+    // mode 0: OUT goes low on load and high once the count hits zero
+    (*addr_of_mut!(COMMAND)).write(SELECT_CHAN2 | LOHI);
+    chan2.write(count as u8);
+    chan2.write((count >> 8) as u8);
+
+    port_b.write(gate | CHAN2_GATE);
+    while port_b.read() & CHAN2_OUT == 0 {
+        core::hint::spin_loop();
+    }
+    port_b.write(gate);
 }
@@ -1,9 +1,10 @@
-use crate::syscall::io::{Io, Pio};
+use crate::acpi::fadt::{Fadt, FADT};
+use crate::pio::{Io, Pio};
 use crate::time;
 
 pub fn init() {
     let mut rtc = Rtc::new();
-    time::START.lock().0 = rtc.time();
+    time::set_boot_time(rtc.time());
 }
 
 fn cvt_bcd(value: usize) -> usize {
@@ -58,11 +59,7 @@ impl Rtc {
 
     /// Get time without waiting
     pub unsafe fn time_no_wait(&mut self) -> u64 {
-        /*let century_register = if let Some(ref fadt) = acpi::ACPI_TABLE.lock().fadt {
-            Some(fadt.century)
-        } else {
-            None
-        };*/
+        let century_register = FADT.get().and_then(Fadt::century_register);
 
         let mut second = self.read(0) as usize;
         let mut minute = self.read(2) as usize;
@@ -70,10 +67,9 @@ impl Rtc {
         let mut day = self.read(7) as usize;
         let mut month = self.read(8) as usize;
         let mut year = self.read(9) as usize;
-        let mut century = /* TODO: Fix invalid value from VirtualBox
-        if let Some(century_reg) = century_register {
+        let mut century = if let Some(century_reg) = century_register {
             self.read(century_reg) as usize
-        } else */ {
+        } else {
             20
         };
         let register_b = self.read(0xB);
@@ -85,12 +81,14 @@ impl Rtc {
             day = cvt_bcd(day);
             month = cvt_bcd(month);
             year = cvt_bcd(year);
-            century = /* TODO: Fix invalid value from VirtualBox
             if century_register.is_some() {
-                cvt_bcd(century)
-            } else */ {
-                century
-            };
+                century = cvt_bcd(century);
+            }
+        }
+
+        // some firmware, e.g. VirtualBox, names a century register that holds garbage
+        if !(19..=29).contains(&century) {
+            century = 20;
         }
 
         if register_b & 2 != 2 || hour & 0x80 == 0x80 {
@@ -146,3 +144,14 @@ impl Rtc {
         }
     }
 }
+
+#[test_case]
+fn test_rtc_boot_time() {
+    // 2020-01-01, well before anyone runs this
+    const EARLIEST: u64 = 1_577_836_800;
+
+    let boot = time::boot_time();
+    assert!(boot >= EARLIEST, "boot time {} is before 2020", boot);
+    let now = Rtc::new().time();
+    assert!(now >= boot && now - boot < 24 * 3600);
+}
@@ -0,0 +1,200 @@
+//! # Time stamp counter
+//! The TSC frequency comes from CPUID where the CPU or hypervisor enumerates it, and is
+//! measured against PIT channel 2 otherwise. It is only a usable clock if it is invariant,
+//! i.e. ticks at a constant rate through P-, C- and T-state changes.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use x86::cpuid::CpuId;
+
+use crate::device::pit;
+use crate::serial_println;
+use crate::time::clocksource::{self, rating, ClockSource};
+
+/// TSC frequency in kHz, or 0 before `init`.
+static TSC_KHZ: AtomicU64 = AtomicU64::new(0);
+static INVARIANT: AtomicBool = AtomicBool::new(false);
+/// TSC value when `init` ran.
+static BOOT_TSC: AtomicU64 = AtomicU64::new(0);
+
+/// How long to measure the TSC against the PIT for, when CPUID doesn't tell.
+const CALIBRATION_US: u64 = 20_000;
+
+/// Where the TSC frequency came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Source {
+    /// Leaf 0x15, crystal clock frequency times the TSC/crystal ratio.
+    Crystal,
+    /// Leaf 0x16, processor base frequency.
+    BaseFrequency,
+    /// Hypervisor leaf 0x4000_0010.
+    Hypervisor,
+    Pit,
+}
+
+/// Determine the TSC frequency. Runs once, on the BSP.
+pub unsafe fn init() {
+    let cpuid = CpuId::new();
+    let invariant = cpuid
+        .get_advanced_power_mgmt_info()
+        .map_or(false, |info| info.has_invariant_tsc());
+
+    let (khz, source) = match cpuid_khz(&cpuid) {
+        Some(found) => found,
+        None => (measure_khz(), Source::Pit),
+    };
+    assert!(khz > 0, "TSC isn't counting");
+
+    serial_println!(
+        "TSC: {} kHz from {:?}{}",
+        khz,
+        source,
+        if invariant { ", invariant" } else { "" }
+    );
+    INVARIANT.store(invariant, Ordering::SeqCst);
+    BOOT_TSC.store(read(), Ordering::SeqCst);
+    TSC_KHZ.store(khz, Ordering::SeqCst);
+
+    clocksource::register(&TscClock);
+}
+
+struct TscClock;
+
+impl ClockSource for TscClock {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn rating(&self) -> u32 {
+        if is_invariant() {
+            rating::INVARIANT_TSC
+        } else {
+            rating::TSC
+        }
+    }
+
+    fn read_ns(&self) -> u64 {
+        nanos_since_boot()
+    }
+}
+
+fn cpuid_khz(cpuid: &CpuId) -> Option<(u64, Source)> {
+    if let Some(info) = cpuid.get_tsc_info() {
+        if let Some(hz) = info.tsc_frequency() {
+            return Some((hz / 1000, Source::Crystal));
+        }
+        // the ratio without the crystal frequency: the TSC runs at the base frequency
+        if info.numerator() != 0 && info.denominator() != 0 {
+            if let Some(freq) = cpuid.get_processor_frequency_info() {
+                let mhz = freq.processor_base_frequency();
+                if mhz != 0 {
+                    return Some((u64::from(mhz) * 1000, Source::BaseFrequency));
+                }
+            }
+        }
+    }
+    cpuid
+        .get_hypervisor_info()
+        .and_then(|info| info.tsc_frequency())
+        .filter(|&khz| khz != 0)
+        .map(|khz| (u64::from(khz), Source::Hypervisor))
+}
+
+unsafe fn measure_khz() -> u64 {
+    let start = read();
+    pit::wait_us(CALIBRATION_US);
+    let elapsed = read() - start;
+    elapsed * 1000 / CALIBRATION_US
+}
+
+/// Read this CPU's TSC.
+#[inline]
+pub fn read() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// TSC frequency in kHz.
+pub fn khz() -> u64 {
+    let khz = TSC_KHZ.load(Ordering::Relaxed);
+    assert!(khz != 0, "TSC not calibrated");
+    khz
+}
+
+/// Whether the TSC ticks at a constant rate regardless of power states.
+pub fn is_invariant() -> bool {
+    INVARIANT.load(Ordering::Relaxed)
+}
+
+/// Whether this CPU's local APIC timer supports TSC-deadline mode.
+pub fn has_deadline_mode() -> bool {
+    CpuId::new()
+        .get_feature_info()
+        .map_or(false, |info| info.has_tsc_deadline())
+}
+
+/// TSC cycles in `micros` microseconds.
+pub fn us_to_cycles(micros: u64) -> u64 {
+    micros * khz() / 1000
+}
+
+/// Nanoseconds since `init`. An AP's TSC may trail the BSP's, so this is 0 there until it has
+/// caught up with the BSP's count at `init`.
+pub fn nanos_since_boot() -> u64 {
+    let cycles = read().saturating_sub(BOOT_TSC.load(Ordering::Relaxed));
+    (u128::from(cycles) * 1_000_000 / u128::from(khz())) as u64
+}
+
+#[test_case]
+fn test_tsc_frequency() {
+    let start = read();
+    unsafe { pit::wait_us(50_000) };
+    let measured = (read() - start) * 1000 / 50_000;
+    // within 2%
+    assert!(
+        measured.abs_diff(khz()) * 50 <= khz(),
+        "TSC ran at {} kHz, calibrated at {} kHz",
+        measured,
+        khz()
+    );
+}
+
+#[test_case]
+fn test_tsc_deadline() {
+    use crate::device::local_apic::{LvtTimerMode, LOCAL_APIC};
+    use crate::percpu;
+    use crate::time::TICK_HZ;
+    use x86_64::instructions::interrupts;
+
+    if !has_deadline_mode() {
+        serial_println!("no TSC-deadline mode, skipped");
+        return;
+    }
+
+    let apic = unsafe { &mut *core::ptr::addr_of_mut!(LOCAL_APIC) };
+    let ticks = || percpu!(counters.timer_ticks).load(Ordering::Relaxed);
+    unsafe { apic.stop_timer() };
+    // take any tick the periodic timer left pending; sti only opens the window after the nop
+    interrupts::enable();
+    x86_64::instructions::nop();
+    interrupts::disable();
+
+    let deadline = read() + us_to_cycles(2000);
+    let before = ticks();
+    unsafe { apic.arm_deadline(deadline) };
+
+    interrupts::enable();
+    while ticks() == before {
+        core::hint::spin_loop();
+    }
+    interrupts::disable();
+    let fired = read();
+
+    unsafe { apic.start_timer(LvtTimerMode::Periodic, 1_000_000 / TICK_HZ) };
+    assert_eq!(ticks(), before + 1);
+    assert!(fired >= deadline, "deadline fired early");
+    assert!(
+        fired - deadline < us_to_cycles(1000),
+        "deadline fired {} cycles late",
+        fired - deadline
+    );
+}
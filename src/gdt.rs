@@ -1,40 +1,50 @@
+use alloc::boxed::Box;
+use core::ptr::addr_of;
+
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
+use crate::memory::stack::KernelStack;
+
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+pub const NMI_IST_INDEX: u16 = 2;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 3;
+
+/// Every IST entry in use. Each CPU gets its own stack for each of them.
+const IST_INDEXES: [u16; 4] = [
+    DOUBLE_FAULT_IST_INDEX,
+    PAGE_FAULT_IST_INDEX,
+    NMI_IST_INDEX,
+    MACHINE_CHECK_IST_INDEX,
+];
+
+/// Size of the guarded IST stacks allocated by `init_cpu`.
+const IST_STACK_PAGES: usize = 5;
+
+/// Static IST stacks for the BSP's boot tables, used until the heap is up.
+const BOOT_STACK_SIZE: usize = 4096 * 2;
+static mut BOOT_STACKS: [[u8; BOOT_STACK_SIZE]; IST_INDEXES.len()] =
+    [[0; BOOT_STACK_SIZE]; IST_INDEXES.len()];
 
 lazy_static! {
-    static ref TSS: TaskStateSegment = {
+    /// Only ever loaded by the BSP, and only until `init_cpu` gives it tables of its own.
+    static ref BOOT_TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
+        let mut ist = [VirtAddr::zero(); 7];
+        for (i, &index) in IST_INDEXES.iter().enumerate() {
+            let stack = unsafe { addr_of!(BOOT_STACKS[i]) };
+            ist[index as usize] = VirtAddr::from_ptr(stack) + BOOT_STACK_SIZE;
+        }
+        tss.interrupt_stack_table = ist;
         tss
     };
 }
 
 lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                data_selector,
-                tss_selector,
-            },
-        )
-    };
+    static ref BOOT_GDT: (GlobalDescriptorTable, Selectors) = build_gdt(&BOOT_TSS);
 }
 
 struct Selectors {
@@ -43,14 +53,76 @@ struct Selectors {
     tss_selector: SegmentSelector,
 }
 
+/// Load the boot GDT and TSS on the BSP. Runs before there is a heap to allocate per-CPU
+/// tables from.
 pub fn init() {
+    load(&BOOT_GDT.0, &BOOT_GDT.1);
+}
+
+/// Give the current CPU its own GDT and TSS, with guard-protected stacks for every IST entry,
+/// and load them. Needs the kernel heap. The tables and stacks live for the rest of the
+/// kernel's life.
+pub fn init_cpu() {
+    let mut ist = [VirtAddr::zero(); 7];
+    for &index in IST_INDEXES.iter() {
+        let stack = KernelStack::new_mapped(IST_STACK_PAGES).expect("no memory for IST stack");
+        ist[index as usize] = stack.top();
+        core::mem::forget(stack);
+    }
+
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table = ist;
+    let tss: &'static TaskStateSegment = Box::leak(Box::new(tss));
+
+    let (gdt, selectors) = build_gdt(tss);
+    let gdt: &'static GlobalDescriptorTable = Box::leak(Box::new(gdt));
+    load(gdt, &selectors);
+}
+
+fn build_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    (
+        gdt,
+        Selectors {
+            code_selector,
+            data_selector,
+            tss_selector,
+        },
+    )
+}
+
+fn load(gdt: &'static GlobalDescriptorTable, selectors: &Selectors) {
     use x86_64::instructions::segmentation::{Segment, CS};
     use x86_64::instructions::tables::load_tss;
 
-    GDT.0.load();
+    gdt.load();
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
-        x86_64::registers::segmentation::SS::set_reg(GDT.1.data_selector);
+        CS::set_reg(selectors.code_selector);
+        load_tss(selectors.tss_selector);
+        x86_64::registers::segmentation::SS::set_reg(selectors.data_selector);
+    }
+}
+
+#[test_case]
+fn test_gdt_per_cpu() {
+    use alloc::vec::Vec;
+    use spin::Mutex;
+    use x86_64::instructions::tables::sgdt;
+
+    static BASES: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+
+    fn record() {
+        let base = sgdt().base.as_u64();
+        BASES.lock().push(base);
     }
+
+    crate::run_on_all_cpus(record);
+    let mut bases = core::mem::take(&mut *BASES.lock());
+    let count = bases.len();
+    bases.sort_unstable();
+    bases.dedup();
+    assert_eq!(bases.len(), count, "CPUs share a GDT");
 }
@@ -0,0 +1,389 @@
+//! # CPU exceptions
+//! All 32 architectural exception vectors enter through a small assembly stub that saves the
+//! general purpose registers into an `ExceptionFrame` and calls `exception_handler`. Breakpoints,
+//! NMIs and page faults on the lazy part of a kernel stack return to the interrupted code;
+//! everything else dumps the registers and panics.
+
+use core::arch::global_asm;
+use core::fmt;
+use core::sync::atomic::Ordering;
+
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::structures::idt::{
+    Entry, HandlerFunc, InterruptDescriptorTable, PageFaultErrorCode, SelectorErrorCode,
+};
+use x86_64::VirtAddr;
+
+use crate::memory::stack::{self, StackFault};
+use crate::{gdt, percpu, serial_println};
+
+const NMI: u64 = 2;
+const BREAKPOINT: u64 = 3;
+const DOUBLE_FAULT: u64 = 8;
+const INVALID_TSS: u64 = 10;
+const SEGMENT_NOT_PRESENT: u64 = 11;
+const STACK_SEGMENT_FAULT: u64 = 12;
+const GENERAL_PROTECTION_FAULT: u64 = 13;
+const PAGE_FAULT: u64 = 14;
+const MACHINE_CHECK: u64 = 18;
+
+/// Mnemonic and name of every exception vector.
+const EXCEPTIONS: [(&str, &str); 32] = [
+    ("#DE", "DIVIDE ERROR"),
+    ("#DB", "DEBUG"),
+    ("NMI", "NON-MASKABLE INTERRUPT"),
+    ("#BP", "BREAKPOINT"),
+    ("#OF", "OVERFLOW"),
+    ("#BR", "BOUND RANGE EXCEEDED"),
+    ("#UD", "INVALID OPCODE"),
+    ("#NM", "DEVICE NOT AVAILABLE"),
+    ("#DF", "DOUBLE FAULT"),
+    ("", "COPROCESSOR SEGMENT OVERRUN"),
+    ("#TS", "INVALID TSS"),
+    ("#NP", "SEGMENT NOT PRESENT"),
+    ("#SS", "STACK SEGMENT FAULT"),
+    ("#GP", "GENERAL PROTECTION FAULT"),
+    ("#PF", "PAGE FAULT"),
+    ("", "RESERVED"),
+    ("#MF", "X87 FLOATING POINT"),
+    ("#AC", "ALIGNMENT CHECK"),
+    ("#MC", "MACHINE CHECK"),
+    ("#XM", "SIMD FLOATING POINT"),
+    ("#VE", "VIRTUALIZATION"),
+    ("#CP", "CONTROL PROTECTION"),
+    ("", "RESERVED"),
+    ("", "RESERVED"),
+    ("", "RESERVED"),
+    ("", "RESERVED"),
+    ("", "RESERVED"),
+    ("", "RESERVED"),
+    ("#HV", "HYPERVISOR INJECTION"),
+    ("#VC", "VMM COMMUNICATION"),
+    ("#SX", "SECURITY"),
+    ("", "RESERVED"),
+];
+
+/// General purpose registers, in the order the entry stub leaves them on the stack.
+#[repr(C)]
+#[derive(Debug)]
+pub struct Registers {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+}
+
+/// Everything on the stack when `exception_handler` runs.
+#[repr(C)]
+#[derive(Debug)]
+pub struct ExceptionFrame {
+    pub registers: Registers,
+    pub vector: u64,
+    /// The CPU's error code, or 0 for exceptions that don't push one.
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+// Each stub is padded to STUB_SIZE bytes, so the stub for vector n is at
+// exception_stubs + n * STUB_SIZE. Vectors without an error code push a zero in its place.
+global_asm!(
+    r#"
+    .macro exception_stub n, has_error
+    .balign 16
+    .if \has_error == 0
+        push 0
+    .endif
+        push \n
+        jmp os81_exception_common
+    .endm
+
+    .text
+    .balign 16
+    os81_exception_stubs:
+    exception_stub 0, 0
+    exception_stub 1, 0
+    exception_stub 2, 0
+    exception_stub 3, 0
+    exception_stub 4, 0
+    exception_stub 5, 0
+    exception_stub 6, 0
+    exception_stub 7, 0
+    exception_stub 8, 1
+    exception_stub 9, 0
+    exception_stub 10, 1
+    exception_stub 11, 1
+    exception_stub 12, 1
+    exception_stub 13, 1
+    exception_stub 14, 1
+    exception_stub 15, 0
+    exception_stub 16, 0
+    exception_stub 17, 1
+    exception_stub 18, 0
+    exception_stub 19, 0
+    exception_stub 20, 0
+    exception_stub 21, 1
+    exception_stub 22, 0
+    exception_stub 23, 0
+    exception_stub 24, 0
+    exception_stub 25, 0
+    exception_stub 26, 0
+    exception_stub 27, 0
+    exception_stub 28, 0
+    exception_stub 29, 1
+    exception_stub 30, 1
+    exception_stub 31, 0
+
+    os81_exception_common:
+        push rax
+        push rbx
+        push rcx
+        push rdx
+        push rsi
+        push rdi
+        push rbp
+        push r8
+        push r9
+        push r10
+        push r11
+        push r12
+        push r13
+        push r14
+        push r15
+        // the CPU aligned the stack before pushing its frame, and 22 words since then keep it
+        // 16 byte aligned for the call
+        mov rdi, rsp
+        cld
+        call {handler}
+        pop r15
+        pop r14
+        pop r13
+        pop r12
+        pop r11
+        pop r10
+        pop r9
+        pop r8
+        pop rbp
+        pop rdi
+        pop rsi
+        pop rdx
+        pop rcx
+        pop rbx
+        pop rax
+        // vector and error code
+        add rsp, 16
+        iretq
+    "#,
+    handler = sym exception_handler,
+);
+
+extern "C" {
+    fn os81_exception_stubs();
+}
+
+const STUB_SIZE: u64 = 16;
+
+/// Point all 32 exception vectors at their stubs, with the IST stacks from `gdt`.
+pub(super) fn init_idt(idt: &mut InterruptDescriptorTable) {
+    // the typed fields don't expose the reserved vectors; every entry has the same layout
+    let entries = unsafe { &mut *(idt as *mut _ as *mut [Entry<HandlerFunc>; 256]) };
+    let stubs = VirtAddr::new(os81_exception_stubs as *const () as u64);
+    for (vector, entry) in entries.iter_mut().take(32).enumerate() {
+        let options = unsafe { entry.set_handler_addr(stubs + vector as u64 * STUB_SIZE) };
+        let ist = match vector as u64 {
+            DOUBLE_FAULT => Some(gdt::DOUBLE_FAULT_IST_INDEX),
+            PAGE_FAULT => Some(gdt::PAGE_FAULT_IST_INDEX),
+            NMI => Some(gdt::NMI_IST_INDEX),
+            MACHINE_CHECK => Some(gdt::MACHINE_CHECK_IST_INDEX),
+            _ => None,
+        };
+        if let Some(index) = ist {
+            unsafe { options.set_stack_index(index) };
+        }
+    }
+}
+
+extern "C" fn exception_handler(frame: &mut ExceptionFrame) {
+    match frame.vector {
+        BREAKPOINT => {
+            serial_println!("EXCEPTION: BREAKPOINT\n{:#x?}", frame);
+            return;
+        }
+        NMI => {
+            // may have interrupted a print on this CPU, so it can't wait for the port
+            crate::serial::try_print(format_args!(
+                "NMI on CPU {}\n{:#x?}\n",
+                percpu!(cpu_index),
+                frame
+            ));
+            return;
+        }
+        PAGE_FAULT => {
+            percpu!(counters.page_faults).fetch_add(1, Ordering::Relaxed);
+            let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+            if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+                match stack::handle_page_fault(Cr2::read()) {
+                    StackFault::Mapped => return,
+                    StackFault::Overflow { bottom, top } => fatal(
+                        frame,
+                        Some(format_args!(
+                            "stack overflow, stack is {:?}..{:?}",
+                            bottom, top
+                        )),
+                    ),
+                    StackFault::Locked => fatal(
+                        frame,
+                        Some(format_args!(
+                            "kernel stack page fault with the page table or frame allocator locked"
+                        )),
+                    ),
+                    StackFault::NotAStack => {}
+                }
+            }
+        }
+        // a fault the page fault handler couldn't take, e.g. before its IST stack was set up
+        DOUBLE_FAULT if stack::is_guard_hit(Cr2::read()) => fatal(
+            frame,
+            Some(format_args!("stack overflow at {:?}", Cr2::read())),
+        ),
+        _ => {}
+    }
+    fatal(frame, None);
+}
+
+/// Dump everything we know about the exception, then panic.
+fn fatal(frame: &ExceptionFrame, note: Option<fmt::Arguments>) -> ! {
+    let (mnemonic, name) = EXCEPTIONS[frame.vector as usize];
+    serial_println!(
+        "EXCEPTION: {} ({}, vector {}) on CPU {}",
+        name,
+        mnemonic,
+        frame.vector,
+        percpu!(cpu_index)
+    );
+    if let Some(note) = note {
+        serial_println!("{}", note);
+    }
+    serial_println!("{}", ErrorCode(frame.vector, frame.error_code));
+    dump(frame);
+    panic!("EXCEPTION: {} on CPU {}", name, percpu!(cpu_index));
+}
+
+fn dump(frame: &ExceptionFrame) {
+    let r = &frame.registers;
+    serial_println!(
+        "RIP={:016x} CS={:04x} RFLAGS={:016x} RSP={:016x} SS={:04x}",
+        frame.rip,
+        frame.cs,
+        frame.rflags,
+        frame.rsp,
+        frame.ss
+    );
+    serial_println!(
+        "RAX={:016x} RBX={:016x} RCX={:016x} RDX={:016x}",
+        r.rax,
+        r.rbx,
+        r.rcx,
+        r.rdx
+    );
+    serial_println!("RSI={:016x} RDI={:016x} RBP={:016x}", r.rsi, r.rdi, r.rbp);
+    serial_println!(
+        "R8 ={:016x} R9 ={:016x} R10={:016x} R11={:016x}",
+        r.r8,
+        r.r9,
+        r.r10,
+        r.r11
+    );
+    serial_println!(
+        "R12={:016x} R13={:016x} R14={:016x} R15={:016x}",
+        r.r12,
+        r.r13,
+        r.r14,
+        r.r15
+    );
+    let (cr3_frame, cr3_flags) = Cr3::read_raw();
+    serial_println!(
+        "CR0={:016x} CR2={:016x} CR3={:016x} CR4={:016x}",
+        Cr0::read_raw(),
+        Cr2::read_raw(),
+        cr3_frame.start_address().as_u64() | u64::from(cr3_flags),
+        Cr4::read_raw()
+    );
+}
+
+/// An exception's error code, decoded according to its vector.
+struct ErrorCode(u64, u64);
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ErrorCode(vector, code) = *self;
+        write!(f, "Error code: {:#x}", code)?;
+        match vector {
+            INVALID_TSS | SEGMENT_NOT_PRESENT | STACK_SEGMENT_FAULT | GENERAL_PROTECTION_FAULT
+                if code != 0 =>
+            {
+                let selector = SelectorErrorCode::new_truncate(code);
+                write!(
+                    f,
+                    " (selector index {} in {:?}{})",
+                    selector.index(),
+                    selector.descriptor_table(),
+                    if selector.external() {
+                        ", external"
+                    } else {
+                        ""
+                    }
+                )
+            }
+            GENERAL_PROTECTION_FAULT => write!(f, " (not segment related)"),
+            PAGE_FAULT => write!(
+                f,
+                " ({:?}) at {:?}",
+                PageFaultErrorCode::from_bits_truncate(code),
+                Cr2::read()
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[test_case]
+fn test_breakpoint_preserves_registers() {
+    let (r12, r15): (u64, u64);
+    unsafe {
+        core::arch::asm!(
+            "mov r12, 0x1234",
+            "mov r15, 0x5678",
+            "int3",
+            "mov {0}, r12",
+            "mov {1}, r15",
+            out(reg) r12,
+            out(reg) r15,
+            out("r12") _,
+            out("r15") _,
+        );
+    }
+    assert_eq!((r12, r15), (0x1234, 0x5678));
+}
+
+#[test_case]
+fn test_selector_error_code() {
+    use alloc::string::ToString;
+
+    let decoded = ErrorCode(GENERAL_PROTECTION_FAULT, 0x1a).to_string();
+    assert_eq!(decoded, "Error code: 0x1a (selector index 3 in Idt)");
+}
@@ -0,0 +1,302 @@
+//! # IRQ dispatch
+//! Every vector from `IRQ_BASE` up gets a stub in the IDT that calls `dispatch`, which runs the
+//! handlers registered for that vector and then sends the EOI to whichever interrupt controller
+//! is active.
+//!
+//! Legacy ISA IRQs 0-15 always arrive on vectors 32-47: the 8259 PIC is programmed that way by
+//! `device::pic::init`, and `device::ioapic::init` maps them to the same vectors. Vectors 48 and
+//! 49 are the local APIC timer and error interrupts, and 50 is the cross-CPU call IPI. Everything
+//! from `FIRST_DYNAMIC_VECTOR` to `LAST_DYNAMIC_VECTOR` is handed out by `allocate_vector`, e.g.
+//! for MSIs.
+//!
+//! Several handlers may share a vector; all of them run, in registration order, on every
+//! interrupt, so each one has to check its own device.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use spin::{Mutex, RwLock};
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+use crate::device::{ioapic, local_apic::LOCAL_APIC, pic};
+
+/// Vector of legacy IRQ 0.
+pub const IRQ_BASE: u8 = 32;
+/// Number of legacy ISA IRQs.
+pub const LEGACY_IRQS: u8 = 16;
+pub const LAPIC_TIMER_VECTOR: u8 = 48;
+pub const LAPIC_ERROR_VECTOR: u8 = 49;
+/// Sent by `smp` to run queued calls.
+pub const IPI_CALL_VECTOR: u8 = 50;
+pub const FIRST_DYNAMIC_VECTOR: u8 = 51;
+pub const LAST_DYNAMIC_VECTOR: u8 = 0xEF;
+/// Spurious interrupt vector programmed into the local APIC. Never EOIed.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// A function run when its vector fires.
+pub type IrqHandler = fn();
+
+/// The controller legacy IRQs come from, and so where their EOI goes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum IrqMethod {
+    Pic = 0,
+    Apic = 1,
+}
+
+static IRQ_METHOD: AtomicU8 = AtomicU8::new(IrqMethod::Pic as u8);
+
+const NUM_VECTORS: usize = 256 - IRQ_BASE as usize;
+
+/// Registered handlers, indexed by vector - `IRQ_BASE`.
+static HANDLERS: RwLock<[Vec<IrqHandler>; NUM_VECTORS]> = {
+    const NONE: Vec<IrqHandler> = Vec::new();
+    RwLock::new([NONE; NUM_VECTORS])
+};
+
+/// Bitmap of dynamic vectors handed out by `allocate_vector`.
+static ALLOCATED_VECTORS: Mutex<[u64; 4]> = Mutex::new([0; 4]);
+
+/// Take legacy IRQs from `method` from now on, unmasking the ones that already have handlers
+/// on that controller.
+pub fn set_irq_method(method: IrqMethod) {
+    IRQ_METHOD.store(method as u8, Ordering::SeqCst);
+    let handlers = HANDLERS.read();
+    for irq in 0..LEGACY_IRQS {
+        if !handlers[irq as usize].is_empty() {
+            unsafe { set_irq_mask(irq, false) };
+        }
+    }
+}
+
+pub fn irq_method() -> IrqMethod {
+    match IRQ_METHOD.load(Ordering::SeqCst) {
+        0 => IrqMethod::Pic,
+        _ => IrqMethod::Apic,
+    }
+}
+
+/// Add `handler` to the chain for legacy IRQ `irq` and unmask the IRQ. Returns the vector it
+/// arrives on.
+pub fn register_irq(irq: u8, handler: IrqHandler) -> u8 {
+    assert!(irq < LEGACY_IRQS, "IRQ {} is not a legacy IRQ", irq);
+    let vector = IRQ_BASE + irq;
+    register_vector(vector, handler);
+    unsafe { set_irq_mask(irq, false) };
+    vector
+}
+
+/// Remove `handler` from the chain for legacy IRQ `irq`, masking the IRQ if that was the last
+/// handler.
+pub fn unregister_irq(irq: u8, handler: IrqHandler) {
+    assert!(irq < LEGACY_IRQS, "IRQ {} is not a legacy IRQ", irq);
+    if unregister_vector(IRQ_BASE + irq, handler) {
+        unsafe { set_irq_mask(irq, true) };
+    }
+}
+
+/// Add `handler` to the chain for `vector`.
+pub fn register_vector(vector: u8, handler: IrqHandler) {
+    assert!(
+        (IRQ_BASE..SPURIOUS_VECTOR).contains(&vector),
+        "vector {} can't take handlers",
+        vector
+    );
+    // with interrupts on, an interrupt on this CPU would spin on the lock we hold
+    interrupts::without_interrupts(|| {
+        HANDLERS.write()[(vector - IRQ_BASE) as usize].push(handler);
+    });
+}
+
+/// Remove `handler` from the chain for `vector`. Returns true if the chain is now empty.
+pub fn unregister_vector(vector: u8, handler: IrqHandler) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut handlers = HANDLERS.write();
+        let chain = &mut handlers[(vector - IRQ_BASE) as usize];
+        if let Some(index) = chain.iter().position(|&h| h as usize == handler as usize) {
+            chain.remove(index);
+        }
+        chain.is_empty()
+    })
+}
+
+/// Reserve a free vector between `FIRST_DYNAMIC_VECTOR` and `LAST_DYNAMIC_VECTOR`.
+pub fn allocate_vector() -> Option<u8> {
+    let mut allocated = ALLOCATED_VECTORS.lock();
+    let vector = (FIRST_DYNAMIC_VECTOR..=LAST_DYNAMIC_VECTOR)
+        .find(|&v| allocated[v as usize / 64] & (1 << (v % 64)) == 0)?;
+    allocated[vector as usize / 64] |= 1 << (vector % 64);
+    Some(vector)
+}
+
+/// Return a vector obtained from `allocate_vector`. Its handlers should be unregistered first.
+pub fn free_vector(vector: u8) {
+    assert!(
+        (FIRST_DYNAMIC_VECTOR..=LAST_DYNAMIC_VECTOR).contains(&vector),
+        "vector {} is not dynamic",
+        vector
+    );
+    let mut allocated = ALLOCATED_VECTORS.lock();
+    assert!(
+        allocated[vector as usize / 64] & (1 << (vector % 64)) != 0,
+        "vector {} is not allocated",
+        vector
+    );
+    allocated[vector as usize / 64] &= !(1 << (vector % 64));
+}
+
+unsafe fn set_irq_mask(irq: u8, masked: bool) {
+    match irq_method() {
+        IrqMethod::Pic => {
+            let (chip, line) = if irq < 8 {
+                (&mut *core::ptr::addr_of_mut!(pic::MASTER), irq)
+            } else {
+                (&mut *core::ptr::addr_of_mut!(pic::SLAVE), irq - 8)
+            };
+            if masked {
+                chip.mask_set(line);
+            } else {
+                chip.mask_clear(line);
+            }
+        }
+        IrqMethod::Apic => {
+            if masked {
+                ioapic::mask(irq);
+            } else {
+                ioapic::unmask(irq);
+            }
+        }
+    }
+}
+
+/// Run the handlers for `vector`, then acknowledge it.
+fn dispatch(vector: u8) {
+    crate::percpu!(counters.interrupts).fetch_add(1, Ordering::Relaxed);
+
+    if vector == SPURIOUS_VECTOR {
+        return;
+    }
+    let legacy_pic_irq = match irq_method() {
+        IrqMethod::Pic if vector < IRQ_BASE + LEGACY_IRQS => Some(vector - IRQ_BASE),
+        _ => None,
+    };
+    if let Some(irq) = legacy_pic_irq {
+        if unsafe { pic_spurious(irq) } {
+            return;
+        }
+    }
+
+    for handler in HANDLERS.read()[(vector - IRQ_BASE) as usize].iter() {
+        handler();
+    }
+
+    unsafe {
+        match legacy_pic_irq {
+            Some(irq) => {
+                if irq >= 8 {
+                    (*core::ptr::addr_of_mut!(pic::SLAVE)).ack();
+                }
+                (*core::ptr::addr_of_mut!(pic::MASTER)).ack();
+            }
+            None => (*core::ptr::addr_of_mut!(LOCAL_APIC)).eoi(),
+        }
+    }
+}
+
+/// Whether PIC IRQ 7 or 15 is spurious, acknowledging the master for a spurious 15 since it
+/// did see the cascade.
+unsafe fn pic_spurious(irq: u8) -> bool {
+    match irq {
+        7 => (*core::ptr::addr_of_mut!(pic::MASTER)).isr() & (1 << 7) == 0,
+        15 if (*core::ptr::addr_of_mut!(pic::SLAVE)).isr() & (1 << 7) == 0 => {
+            (*core::ptr::addr_of_mut!(pic::MASTER)).ack();
+            true
+        }
+        _ => false,
+    }
+}
+
+extern "x86-interrupt" fn irq_stub<const VECTOR: u8>(_stack_frame: InterruptStackFrame) {
+    dispatch(VECTOR);
+}
+
+macro_rules! set_stubs {
+    ($idt:ident; $($row:literal)*) => {
+        $( set_stubs!(@row $idt, $row, 0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15); )*
+    };
+    (@row $idt:ident, $row:literal, $($column:literal)*) => {
+        $( $idt[$row * 16 + $column].set_handler_fn(irq_stub::<{ $row * 16 + $column }>); )*
+    };
+}
+
+/// Point every vector from `IRQ_BASE` up at its dispatch stub.
+pub(super) fn init_idt(idt: &mut InterruptDescriptorTable) {
+    set_stubs!(idt; 2 3 4 5 6 7 8 9 10 11 12 13 14 15);
+}
+
+#[test_case]
+fn test_vector_allocation() {
+    let first = allocate_vector().unwrap();
+    let second = allocate_vector().unwrap();
+    assert_ne!(first, second);
+    assert!((FIRST_DYNAMIC_VECTOR..=LAST_DYNAMIC_VECTOR).contains(&first));
+    free_vector(first);
+    assert_eq!(allocate_vector(), Some(first));
+    free_vector(first);
+    free_vector(second);
+}
+
+#[test_case]
+fn test_shared_vector_dispatch() {
+    use core::sync::atomic::AtomicUsize;
+
+    static FIRST: AtomicUsize = AtomicUsize::new(0);
+    static SECOND: AtomicUsize = AtomicUsize::new(0);
+    fn first() {
+        FIRST.fetch_add(1, Ordering::SeqCst);
+    }
+    fn second() {
+        SECOND.fetch_add(1, Ordering::SeqCst);
+    }
+
+    let vector = allocate_vector().unwrap();
+    register_vector(vector, first);
+    register_vector(vector, second);
+    interrupts::without_interrupts(|| dispatch(vector));
+    assert_eq!(FIRST.load(Ordering::SeqCst), 1);
+    assert_eq!(SECOND.load(Ordering::SeqCst), 1);
+
+    assert!(!unregister_vector(vector, first));
+    interrupts::without_interrupts(|| dispatch(vector));
+    assert_eq!(FIRST.load(Ordering::SeqCst), 1);
+    assert_eq!(SECOND.load(Ordering::SeqCst), 2);
+
+    assert!(unregister_vector(vector, second));
+    free_vector(vector);
+}
+
+#[test_case]
+fn test_legacy_irq_delivery() {
+    use core::sync::atomic::AtomicUsize;
+
+    use crate::device::pit;
+
+    static FIRED: AtomicUsize = AtomicUsize::new(0);
+    fn count() {
+        FIRED.fetch_add(1, Ordering::SeqCst);
+    }
+
+    // QEMU has an I/O APIC, so legacy IRQs go through it rather than the 8259
+    assert_eq!(irq_method(), IrqMethod::Apic);
+    register_irq(0, count);
+    interrupts::enable();
+    for _ in 0..6 {
+        unsafe { pit::wait_us(50_000) };
+    }
+    interrupts::disable();
+    unregister_irq(0, count);
+    // channel 0 runs at 18.2 Hz or faster; a second IRQ means the first one was EOIed
+    let fired = FIRED.load(Ordering::SeqCst);
+    assert!(fired >= 2, "IRQ 0 fired {} times in 300 ms", fired);
+}
@@ -0,0 +1,45 @@
+use crate::pio::{Io, Pio};
+use crate::{serial_print, time};
+use lazy_static::lazy_static;
+use x86_64::structures::idt::InterruptDescriptorTable;
+
+pub mod exception;
+pub mod irq;
+
+pub use self::irq::{register_irq, register_vector, unregister_irq, IrqHandler};
+
+lazy_static! {
+    static ref IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        exception::init_idt(&mut idt);
+        irq::init_idt(&mut idt);
+        idt
+    };
+}
+
+pub fn init_idt() {
+    IDT.load();
+}
+
+/// Register the kernel's own handlers for the system tick and the legacy timer and keyboard
+/// IRQs. Runs once the interrupt controllers are set up.
+pub fn init_irqs() {
+    register_vector(irq::LAPIC_TIMER_VECTOR, time::tick);
+    register_irq(0, timer_interrupt_handler);
+    register_irq(1, keyboard_interrupt_handler);
+}
+
+pub(crate) fn timer_interrupt_handler() {
+    serial_print!(".");
+}
+
+fn keyboard_interrupt_handler() {
+    // read the scancode so the controller can send the next one
+    let _scancode = Pio::<u8>::new(0x60).read();
+}
+
+#[test_case]
+fn test_breakpoint_exception() {
+    // invoke a breakpoint exception
+    x86_64::instructions::interrupts::int3();
+}
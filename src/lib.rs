@@ -8,6 +8,8 @@
 #![reexport_test_harness_main = "test_main"]
 
 extern crate alloc;
+#[cfg(test)]
+use core::sync::atomic::AtomicUsize;
 use core::{ops::DerefMut, panic::PanicInfo, sync::atomic::Ordering};
 
 pub mod acpi;
@@ -17,24 +19,47 @@ pub mod device;
 pub mod gdt;
 pub mod interrupts;
 pub mod memory;
+pub mod mmio;
+pub mod percpu;
 pub mod pio;
+pub mod power;
 pub mod serial;
+pub mod smp;
+pub mod time;
 
 /// Virtual address of the beginning of the physical memory map setup by the bootloader.
 pub const PHYS_OFFSET: u64 = 0x0000_4000_0000_0000; // must match bootloader conf in Cargo.toml
 
-pub fn kstart(phys_mem_offset: u64, memory_regions: &'static MemoryRegions) {
+pub fn kstart(
+    phys_mem_offset: u64,
+    memory_regions: &'static MemoryRegions,
+    rsdp_address: Option<u64>,
+) -> ! {
+    init(phys_mem_offset, memory_regions, rsdp_address);
+
+    kmain()
+}
+
+/// Bring up the BSP, the kernel heap, devices and APs, without entering `kmain`.
+/// `rsdp_address` is the RSDP's physical address if the bootloader found it, like it does on
+/// UEFI boots where it isn't in the BIOS area.
+pub fn init(
+    phys_mem_offset: u64,
+    memory_regions: &'static MemoryRegions,
+    rsdp_address: Option<u64>,
+) {
+    unsafe { percpu::init_bsp() };
+
     serial_print!("Initting...");
 
     gdt::init();
 
     interrupts::init_idt();
 
-    // unsafe { interrupts::PICS.lock().initialize() };
-
     assert_eq!(phys_mem_offset, PHYS_OFFSET);
 
     let mut active_table = unsafe { memory::init(VirtAddr::new(phys_mem_offset)) };
+    unsafe { memory::mmio::init_pat() };
 
     unsafe {
         memory::init_frame_alloc(memory_regions);
@@ -43,6 +68,9 @@ pub fn kstart(phys_mem_offset: u64, memory_regions: &'static MemoryRegions) {
     allocator::init_heap(&mut active_table, FRAME_ALLOC.lock().deref_mut())
         .expect("heap initialization failed");
 
+    gdt::init_cpu();
+    percpu::init_scratch_stack();
+
     // Reset AP variables
     ap_init::CPU_COUNT.store(1, Ordering::SeqCst);
     ap_init::AP_READY.store(false, Ordering::SeqCst);
@@ -50,50 +78,124 @@ pub fn kstart(phys_mem_offset: u64, memory_regions: &'static MemoryRegions) {
 
     // Initialize devices (pic/apic)
     unsafe { device::init(&mut active_table) };
+    interrupts::init_irqs();
+    smp::init();
 
-    ap_init::init_aps(&mut active_table);
-
-    kmain()
+    ap_init::init_aps(&mut active_table, rsdp_address);
+    power::init();
 }
 
 pub unsafe extern "C" fn kstart_ap(args_ptr: *const ap_init::KernelArgsAp) -> ! {
-    serial_println!("stuff from an ap");
-
     let args = &*args_ptr;
     let cpu_id = args.cpu_id as usize;
+    percpu::init_ap(cpu_id);
+
+    serial_println!("stuff from an ap");
+
     let bsp_table = args.page_table as usize;
     let _stack_start = args.stack_start as usize;
     let stack_end = args.stack_end as usize;
 
-    gdt::init();
+    gdt::init_cpu();
+    percpu::init_scratch_stack();
 
     interrupts::init_idt();
 
-    while !ap_init::BSP_READY.load(Ordering::SeqCst) {
-        core::arch::x86_64::_mm_pause()
-    }
-
-    device::init_ap();
+    memory::mmio::init_pat();
 
+    // tell the BSP this AP made it, then wait for it to finish bringing the others up
     ap_init::AP_READY.store(true, Ordering::SeqCst);
     while !ap_init::BSP_READY.load(Ordering::SeqCst) {
         core::arch::x86_64::_mm_pause()
     }
 
-    crate::kmain_ap(cpu_id);
+    device::init_ap();
+    smp::mark_online();
+
+    crate::kmain_ap();
 }
 
-pub fn kmain() {
+pub fn kmain() -> ! {
     serial_println!("stuff from main bsp");
     x86_64::instructions::interrupts::enable();
+    // nothing left to do
+    power::shutdown();
     crate::hlt_loop();
 }
 
-pub fn kmain_ap(cpu_id: usize) -> ! {
-    serial_println!("stuff from ap {}", cpu_id);
+pub fn kmain_ap() -> ! {
+    serial_println!("stuff from ap {}", percpu!(cpu_index));
     x86_64::instructions::interrupts::enable();
+    #[cfg(test)]
+    ap_test_loop();
+    #[cfg(not(test))]
     crate::hlt_loop();
 }
+
+/// Work handed out by `run_on_all_cpus`, and a counter bumped every time it changes.
+#[cfg(test)]
+static AP_WORK: spin::Mutex<Option<&'static (dyn Fn() + Sync)>> = spin::Mutex::new(None);
+#[cfg(test)]
+static AP_WORK_GENERATION: AtomicUsize = AtomicUsize::new(0);
+#[cfg(test)]
+static AP_WORK_DONE: AtomicUsize = AtomicUsize::new(0);
+
+/// Run `f` on the BSP and every AP, returning once all of them have finished.
+#[cfg(test)]
+pub fn run_on_all_cpus(f: impl Fn() + Sync) {
+    let aps = smp::online_count() - 1;
+    let work: &(dyn Fn() + Sync) = &f;
+    // SAFETY: the APs are done with `f` by the time this returns
+    let work: &'static (dyn Fn() + Sync) = unsafe { core::mem::transmute(work) };
+    *AP_WORK.lock() = Some(work);
+    AP_WORK_DONE.store(0, Ordering::SeqCst);
+    AP_WORK_GENERATION.fetch_add(1, Ordering::SeqCst);
+
+    f();
+
+    while AP_WORK_DONE.load(Ordering::SeqCst) < aps {
+        core::hint::spin_loop();
+    }
+    *AP_WORK.lock() = None;
+}
+
+/// Run `f` on every CPU like `run_on_all_cpus`, and collect what it returned, by CPU index.
+/// Indices of CPUs that aren't online are skipped.
+#[cfg(test)]
+pub fn map_all_cpus<T: Send>(f: impl Fn() -> T + Sync) -> alloc::vec::Vec<T> {
+    let cpus = ap_init::CPU_COUNT.load(Ordering::SeqCst);
+    let results = spin::Mutex::new((0..cpus).map(|_| None).collect::<alloc::vec::Vec<_>>());
+    run_on_all_cpus(|| {
+        let result = f();
+        results.lock()[percpu!(cpu_index)] = Some(result);
+    });
+    results
+        .into_inner()
+        .into_iter()
+        .enumerate()
+        .filter(|&(cpu, _)| smp::is_online(cpu))
+        .map(|(_, result)| result.expect("a CPU didn't run the work"))
+        .collect()
+}
+
+#[cfg(test)]
+fn ap_test_loop() -> ! {
+    let mut seen = AP_WORK_GENERATION.load(Ordering::SeqCst);
+    loop {
+        let generation = AP_WORK_GENERATION.load(Ordering::SeqCst);
+        if generation == seen {
+            core::hint::spin_loop();
+            continue;
+        }
+        seen = generation;
+        let work = *AP_WORK.lock();
+        if let Some(f) = work {
+            f();
+        }
+        AP_WORK_DONE.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
 pub trait Testable {
     fn run(&self) -> ();
 }
@@ -109,6 +211,76 @@ where
     }
 }
 
+/// A test that needs at least `cpus` CPUs online. Declare one with `smp_test!`.
+pub struct SmpTest {
+    pub name: &'static str,
+    pub cpus: usize,
+    pub test: fn(),
+}
+
+impl Testable for SmpTest {
+    fn run(&self) {
+        serial_print!("{}...\t", self.name);
+        let online = smp::online_count();
+        assert!(
+            online >= self.cpus,
+            "needs {} CPUs, but only {} are online",
+            self.cpus,
+            online
+        );
+        (self.test)();
+        serial_println!("[ok]");
+    }
+}
+
+/// Left in the test binary by `smp_test!`. The boot runner looks for `SMP_MARKER_MAGIC` and
+/// starts QEMU with as many CPUs as the most demanding test needs.
+#[repr(C)]
+pub struct SmpMarker {
+    pub magic: [u8; 16],
+    pub cpus: u32,
+}
+
+/// Must match the boot runner.
+pub const SMP_MARKER_MAGIC: [u8; 16] = *b"os81-smp-test-cp";
+
+impl SmpMarker {
+    pub const fn new(cpus: usize) -> SmpMarker {
+        SmpMarker {
+            magic: SMP_MARKER_MAGIC,
+            cpus: cpus as u32,
+        }
+    }
+}
+
+/// Make the function `$name` a test that needs at least `$cpus` CPUs, in place of
+/// `#[test_case]`:
+///
+/// ```ignore
+/// #[cfg(test)]
+/// fn test_lock_contention() {
+///     crate::run_on_all_cpus(|| { /* ... */ });
+/// }
+/// crate::smp_test!(test_lock_contention, 4);
+/// ```
+#[macro_export]
+macro_rules! smp_test {
+    ($name:ident, $cpus:expr) => {
+        #[cfg(test)]
+        mod $name {
+            #[test_case]
+            static TEST: $crate::SmpTest = $crate::SmpTest {
+                name: module_path!(),
+                cpus: $cpus,
+                test: super::$name,
+            };
+
+            #[used]
+            static MARKER: $crate::SmpMarker = $crate::SmpMarker::new($cpus);
+        }
+    };
+}
+
 pub fn test_runner(tests: &[&dyn Testable]) {
     serial_println!("Running {} tests", tests.len());
     for test in tests {
@@ -138,6 +310,9 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
         let mut port = Port::new(0xf4);
         port.write(exit_code as u32);
     }
+    // still here without QEMU's isa-debug-exit device, or on real hardware; this runs from the
+    // panic handler, so nothing that could wait on a lock
+    power::shutdown_now();
 }
 
 pub fn hlt_loop() -> ! {
@@ -158,8 +333,16 @@ entry_point!(test_kernel_main);
 
 /// Entry point for `cargo xtest`
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
-    init();
+fn test_kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    let phys_mem_offset = boot_info
+        .physical_memory_offset
+        .into_option()
+        .expect("Kernel requires a bootloader-provided physical memory map");
+    init(
+        phys_mem_offset,
+        &boot_info.memory_regions,
+        boot_info.rsdp_addr.into_option(),
+    );
     test_main();
     hlt_loop();
 }
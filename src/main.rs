@@ -1,7 +1,7 @@
 #![no_std]
 #![no_main]
 #![feature(custom_test_frameworks)]
-#![test_runner(test_runner)]
+#![test_runner(os81::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
 extern crate alloc;
@@ -14,15 +14,16 @@ use os81::serial_println;
 entry_point!(kernel_main);
 
 fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
-    use os81::memory::{self, BootInfoFrameAllocator};
-    use x86_64::VirtAddr;
-
     let phys_mem_offset = boot_info
         .physical_memory_offset
         .into_option()
         .expect("Kernel requires a bootloader-provided physical memory map");
 
-    os81::kstart(phys_mem_offset, &boot_info.memory_regions);
+    os81::init(
+        phys_mem_offset,
+        &boot_info.memory_regions,
+        boot_info.rsdp_addr.into_option(),
+    );
 
     // Write a green stripe on successful init
     if let Some(framebuffer) = boot_info.framebuffer.as_mut() {
@@ -64,7 +65,7 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
 
     serial_println!("It did not crash!");
 
-    os81::hlt_loop();
+    os81::kmain();
 }
 
 /// This function is called on panic.
@@ -0,0 +1,305 @@
+//! # Physical frame allocator
+//! A binary buddy allocator over the usable regions of the bootloader's memory map.
+//!
+//! Free blocks are kept on intrusive, doubly linked free lists (one per order) that live inside
+//! the free frames themselves, reached through the physical memory map at `PHYS_OFFSET`. A byte
+//! per frame records whether that frame heads a free block, and of which order, so finding and
+//! unlinking a buddy is O(1).
+
+use core::{mem, ptr, slice};
+
+use bootloader::boot_info::{MemoryRegionKind, MemoryRegions};
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
+    PhysAddr,
+};
+
+const FRAME_SIZE: u64 = 4096;
+
+/// Largest block order handed out: 2^MAX_ORDER frames (4 MiB).
+pub const MAX_ORDER: usize = 10;
+
+/// Marks the end of a free list, or an unlinked block.
+const NIL: u64 = u64::MAX;
+
+/// State byte of a frame that does not head a free block.
+const NOT_FREE: u8 = 0;
+
+/// Header written into the first frame of every free block.
+#[repr(C)]
+struct FreeBlock {
+    next: u64,
+    prev: u64,
+}
+
+/// A FrameAllocator that hands out and takes back naturally aligned blocks of 2^k frames.
+pub struct BuddyFrameAllocator {
+    /// Per-frame state, indexed by physical frame number. `NOT_FREE`, or `order + 1` if the
+    /// frame is the first frame of a free block of that order.
+    state: &'static mut [u8],
+    /// Frame number of the first block on each order's free list.
+    free_lists: [u64; MAX_ORDER + 1],
+    free_frames: usize,
+    total_frames: usize,
+}
+
+unsafe impl Send for BuddyFrameAllocator {}
+
+impl BuddyFrameAllocator {
+    /// An allocator that owns no memory. Every allocation fails.
+    pub const fn empty() -> Self {
+        BuddyFrameAllocator {
+            state: &mut [],
+            free_lists: [NIL; MAX_ORDER + 1],
+            free_frames: 0,
+            total_frames: 0,
+        }
+    }
+
+    /// Create a FrameAllocator from the passed memory map.
+    ///
+    /// The per-frame state table is carved out of the first usable region large enough to hold
+    /// it; those frames are never handed out.
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed
+    /// memory map is valid, that all frames marked as `USABLE` in it are really unused, and that
+    /// the complete physical memory is mapped at `PHYS_OFFSET`.
+    pub unsafe fn new(memory_map: &'static MemoryRegions) -> Self {
+        let usable = || {
+            memory_map
+                .iter()
+                .filter(|r| r.kind == MemoryRegionKind::Usable)
+                .map(|r| (align_up(r.start), r.end & !(FRAME_SIZE - 1)))
+                .filter(|(start, end)| start < end)
+        };
+
+        let frame_count = usable().map(|(_, end)| end / FRAME_SIZE).max().unwrap_or(0) as usize;
+        let state_frames = (frame_count as u64 + FRAME_SIZE - 1) / FRAME_SIZE;
+
+        let (state_start, _) = usable()
+            .find(|(start, end)| end - start >= state_frames * FRAME_SIZE)
+            .expect("no usable region large enough for the frame allocator state");
+        let state_end = state_start + state_frames * FRAME_SIZE;
+
+        let state_ptr = (crate::PHYS_OFFSET + state_start) as *mut u8;
+        ptr::write_bytes(state_ptr, NOT_FREE, frame_count);
+
+        let mut allocator = BuddyFrameAllocator {
+            state: slice::from_raw_parts_mut(state_ptr, frame_count),
+            ..Self::empty()
+        };
+
+        for (start, end) in usable() {
+            // skip the frames holding our own state table
+            if start < state_end && state_start < end {
+                allocator.add_range(start, state_start);
+                allocator.add_range(state_end, end);
+            } else {
+                allocator.add_range(start, end);
+            }
+        }
+
+        allocator
+    }
+
+    /// Hand the frames in `[start, end)` to the allocator, as the largest aligned blocks that fit.
+    unsafe fn add_range(&mut self, start: u64, end: u64) {
+        let mut frame = start / FRAME_SIZE;
+        let end = end / FRAME_SIZE;
+        while frame < end {
+            let mut order = (frame.trailing_zeros() as usize).min(MAX_ORDER);
+            while frame + (1 << order) > end {
+                order -= 1;
+            }
+            self.total_frames += 1 << order;
+            self.free_block(frame, order);
+            frame += 1 << order;
+        }
+    }
+
+    /// Number of frames currently available.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Number of frames currently handed out.
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+
+    /// Number of frames managed by this allocator.
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    /// Allocate a block of 2^order frames, aligned to its own size.
+    pub fn allocate_order(&mut self, order: usize) -> Option<PhysFrame> {
+        if order > MAX_ORDER {
+            return None;
+        }
+        let mut current = (order..=MAX_ORDER).find(|&o| self.free_lists[o] != NIL)?;
+        let frame = self.free_lists[current];
+        unsafe { self.unlink(frame, current) };
+
+        // give back the upper halves we don't need
+        while current > order {
+            current -= 1;
+            unsafe { self.push(frame + (1 << current), current) };
+        }
+
+        self.free_frames -= 1 << order;
+        Some(PhysFrame::containing_address(PhysAddr::new(
+            frame * FRAME_SIZE,
+        )))
+    }
+
+    /// Return a block previously obtained from `allocate_order` with the same order.
+    ///
+    /// This function is unsafe because the caller must guarantee that the block is no longer
+    /// in use.
+    pub unsafe fn deallocate_order(&mut self, frame: PhysFrame, order: usize) {
+        let number = frame.start_address().as_u64() / FRAME_SIZE;
+        assert!(order <= MAX_ORDER, "order {} out of range", order);
+        assert_eq!(number & ((1 << order) - 1), 0, "misaligned block");
+        assert!(
+            (number as usize) < self.state.len(),
+            "frame {:?} not managed by the frame allocator",
+            frame
+        );
+        self.free_block(number, order);
+    }
+
+    /// Allocate count contigous frames, return the start address of the first frame.
+    ///
+    /// The run is rounded up to the next power of two and aligned to that size.
+    pub fn allocate_contiguous_frames(&mut self, count: usize) -> Option<PhysAddr> {
+        assert_ne!(count, 0);
+        self.allocate_order(order_for(count))
+            .map(|frame| frame.start_address())
+    }
+
+    /// Free a run obtained from `allocate_contiguous_frames` with the same count.
+    ///
+    /// This function is unsafe because the caller must guarantee that the frames are no longer
+    /// in use.
+    pub unsafe fn deallocate_contiguous_frames(&mut self, start: PhysAddr, count: usize) {
+        assert_ne!(count, 0);
+        self.deallocate_order(PhysFrame::containing_address(start), order_for(count));
+    }
+
+    /// Insert a block into the free lists, merging it with its buddies where possible.
+    unsafe fn free_block(&mut self, mut frame: u64, mut order: usize) {
+        debug_assert_eq!(self.state[frame as usize], NOT_FREE, "double free");
+        self.free_frames += 1 << order;
+
+        while order < MAX_ORDER {
+            let buddy = frame ^ (1 << order);
+            if buddy as usize >= self.state.len() || self.state[buddy as usize] != order as u8 + 1 {
+                break;
+            }
+            self.unlink(buddy, order);
+            frame = frame.min(buddy);
+            order += 1;
+        }
+        self.push(frame, order);
+    }
+
+    unsafe fn push(&mut self, frame: u64, order: usize) {
+        let head = self.free_lists[order];
+        *block(frame) = FreeBlock {
+            next: head,
+            prev: NIL,
+        };
+        if head != NIL {
+            (*block(head)).prev = frame;
+        }
+        self.free_lists[order] = frame;
+        self.state[frame as usize] = order as u8 + 1;
+    }
+
+    unsafe fn unlink(&mut self, frame: u64, order: usize) {
+        let FreeBlock { next, prev } = mem::replace(
+            &mut *block(frame),
+            FreeBlock {
+                next: NIL,
+                prev: NIL,
+            },
+        );
+        if prev == NIL {
+            self.free_lists[order] = next;
+        } else {
+            (*block(prev)).next = next;
+        }
+        if next != NIL {
+            (*block(next)).prev = prev;
+        }
+        self.state[frame as usize] = NOT_FREE;
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.allocate_order(0)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.deallocate_order(frame, 0)
+    }
+}
+
+/// The smallest order whose blocks hold `count` frames.
+pub fn order_for(count: usize) -> usize {
+    count.next_power_of_two().trailing_zeros() as usize
+}
+
+fn align_up(addr: u64) -> u64 {
+    (addr + FRAME_SIZE - 1) & !(FRAME_SIZE - 1)
+}
+
+fn block(frame: u64) -> *mut FreeBlock {
+    (crate::PHYS_OFFSET + frame * FRAME_SIZE) as *mut FreeBlock
+}
+
+#[test_case]
+fn test_alloc_free_roundtrip() {
+    let mut alloc = super::FRAME_ALLOC.lock();
+    let free = alloc.free_frames();
+
+    let frames: [PhysFrame; 8] = core::array::from_fn(|_| alloc.allocate_frame().unwrap());
+    assert_eq!(alloc.free_frames(), free - 8);
+    for frame in frames {
+        unsafe { alloc.deallocate_frame(frame) };
+    }
+    assert_eq!(alloc.free_frames(), free);
+}
+
+#[test_case]
+fn test_contiguous_is_aligned() {
+    let mut alloc = super::FRAME_ALLOC.lock();
+    let free = alloc.free_frames();
+
+    let start = alloc.allocate_contiguous_frames(12).unwrap();
+    assert_eq!(start.as_u64() % (16 * FRAME_SIZE), 0);
+    assert_eq!(alloc.free_frames(), free - 16);
+    unsafe { alloc.deallocate_contiguous_frames(start, 12) };
+    assert_eq!(alloc.free_frames(), free);
+}
+
+#[test_case]
+fn test_buddies_coalesce() {
+    let mut alloc = super::FRAME_ALLOC.lock();
+    let free = alloc.free_frames();
+
+    let block = alloc.allocate_order(4).unwrap();
+    let upper = block + 8;
+    unsafe {
+        alloc.deallocate_order(block, 3);
+        alloc.deallocate_order(upper, 3);
+    }
+    // the upper half must have been merged back rather than left on the order 3 list
+    let upper_number = upper.start_address().as_u64() / FRAME_SIZE;
+    assert_eq!(alloc.state[upper_number as usize], NOT_FREE);
+    assert_eq!(alloc.free_frames(), free);
+}
@@ -0,0 +1,324 @@
+//! # MMIO mappings
+//! Maps device memory (and firmware tables) into a dedicated kernel window with an explicit
+//! cache mode, instead of every driver calling `map_to` on its own.
+//!
+//! Mappings are reference counted: asking for a range that an existing mapping with the same
+//! cache mode already covers hands out that mapping again. The pages are unmapped, and the
+//! virtual range reused, when the last `MmioRegion` for them is dropped.
+
+use alloc::vec::Vec;
+use core::ops::DerefMut;
+
+use spin::Mutex;
+use x86::msr::{rdmsr, wrmsr, IA32_PAT};
+use x86_64::{
+    instructions::tlb,
+    structures::paging::{
+        page_table::PageTableEntry, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+        PhysFrame, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::{with_active_table, FRAME_ALLOC};
+use crate::smp;
+
+/// Start of the virtual window MMIO mappings are placed in.
+pub const MMIO_START: u64 = 0x_5000_0000_0000;
+/// Size of the MMIO window.
+pub const MMIO_SIZE: u64 = 0x100_0000_0000; // 1 TiB
+
+const PAGE_SIZE: u64 = 4096;
+
+/// The PAT bit of a 4 KiB page table entry sits where `HUGE_PAGE` does in higher levels.
+const PAT_4K: PageTableFlags = PageTableFlags::HUGE_PAGE;
+
+/// PAT layout programmed by `init_pat`. The lower half matches the power-on default, so plain
+/// `WRITE_THROUGH`/`NO_CACHE` flags keep their usual meaning; PA5 is write-combining.
+const PAT_VALUE: u64 = 0x0007_0106_0007_0406; // PA7..PA0: UC UC- WC WB UC UC- WT WB
+
+/// Memory type used for a mapping.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Normal cached memory, e.g. ACPI tables in RAM.
+    WriteBack,
+    WriteThrough,
+    /// Writes may be buffered and merged, reads are uncached. For framebuffers.
+    WriteCombining,
+    /// Strong uncacheable, for device registers.
+    Uncached,
+}
+
+impl CacheMode {
+    fn flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::WriteBack => PageTableFlags::empty(),
+            CacheMode::WriteThrough => PageTableFlags::WRITE_THROUGH, // PA1
+            CacheMode::WriteCombining => PAT_4K | PageTableFlags::WRITE_THROUGH, // PA5
+            CacheMode::Uncached => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH, // PA3
+        }
+    }
+}
+
+/// Program this CPU's PAT so every `CacheMode` is expressible. Must run on every CPU, since
+/// mappings are shared between them.
+pub unsafe fn init_pat() {
+    let has_pat = x86::cpuid::CpuId::new()
+        .get_feature_info()
+        .map_or(false, |info| info.has_pat());
+    if has_pat && rdmsr(IA32_PAT) != PAT_VALUE {
+        wrmsr(IA32_PAT, PAT_VALUE);
+        x86_64::instructions::tlb::flush_all();
+    }
+}
+
+/// A live mapping of physical memory into the MMIO window.
+#[derive(Debug)]
+pub struct MmioRegion {
+    virt: VirtAddr,
+    phys: PhysAddr,
+    len: usize,
+}
+
+impl MmioRegion {
+    /// Virtual address corresponding to the `phys` passed to `map_mmio`.
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.virt
+    }
+
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn as_ptr<T>(&self) -> *mut T {
+        self.virt.as_mut_ptr()
+    }
+
+    /// Keep the mapping for the rest of the kernel's life, returning its virtual address.
+    pub fn leak(self) -> VirtAddr {
+        let virt = self.virt;
+        core::mem::forget(self);
+        virt
+    }
+}
+
+impl Drop for MmioRegion {
+    fn drop(&mut self) {
+        let virt = self.virt.as_u64();
+        let mut mappings = MAPPINGS.lock();
+        let index = mappings
+            .live
+            .iter()
+            .position(|m| m.virt <= virt && virt < m.virt + m.pages * PAGE_SIZE)
+            .expect("dropped MmioRegion has no mapping");
+        let mapping = &mut mappings.live[index];
+        mapping.refs -= 1;
+        if mapping.refs > 0 {
+            return;
+        }
+
+        let mapping = mappings.live.swap_remove(index);
+        // the range is in neither list until the shootdown is done, so nobody reuses it early
+        drop(mappings);
+        with_active_table(|table| {
+            for i in 0..mapping.pages {
+                let page = Page::<Size4KiB>::containing_address(VirtAddr::new(
+                    mapping.virt + i * PAGE_SIZE,
+                ));
+                assert!(clear_entry(table, page), "MMIO page was not mapped");
+            }
+        });
+        smp::flush_tlb_range(VirtAddr::new(mapping.virt), mapping.pages);
+        MAPPINGS.lock().free.push((mapping.virt, mapping.pages));
+    }
+}
+
+struct Mapping {
+    /// First mapped physical page.
+    phys: u64,
+    /// First page in the window.
+    virt: u64,
+    pages: u64,
+    cache_mode: CacheMode,
+    refs: usize,
+}
+
+struct Mappings {
+    live: Vec<Mapping>,
+    /// Virtual ranges (start, pages) returned by dropped mappings.
+    free: Vec<(u64, u64)>,
+    /// Window space above this has never been handed out.
+    next: u64,
+}
+
+static MAPPINGS: Mutex<Mappings> = Mutex::new(Mappings {
+    live: Vec::new(),
+    free: Vec::new(),
+    next: MMIO_START,
+});
+
+/// Map `len` bytes of physical memory starting at `phys` with the given cache mode.
+///
+/// Panics if the MMIO window or physical memory for page tables is exhausted.
+pub fn map_mmio(phys: PhysAddr, len: usize, cache_mode: CacheMode) -> MmioRegion {
+    assert_ne!(len, 0, "empty MMIO mapping");
+    let first = page_base(phys.as_u64());
+    let end = phys.as_u64() + len as u64;
+    let pages = (end - first + PAGE_SIZE - 1) / PAGE_SIZE;
+
+    // held across the page table update, so nobody reuses the mapping before it exists
+    let mut mappings = MAPPINGS.lock();
+
+    // reuse a mapping that already covers this range
+    if let Some(mapping) = mappings.live.iter_mut().find(|m| {
+        m.cache_mode == cache_mode
+            && m.phys <= first
+            && first + pages * PAGE_SIZE <= m.phys + m.pages * PAGE_SIZE
+    }) {
+        mapping.refs += 1;
+        return MmioRegion {
+            virt: VirtAddr::new(mapping.virt + (phys.as_u64() - mapping.phys)),
+            phys,
+            len,
+        };
+    }
+
+    let virt = mappings.allocate(pages);
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE
+        | cache_mode.flags();
+    with_active_table(|table| {
+        for i in 0..pages {
+            let page = Page::<Size4KiB>::containing_address(VirtAddr::new(virt + i * PAGE_SIZE));
+            let frame = PhysFrame::containing_address(PhysAddr::new(first + i * PAGE_SIZE));
+            // a leftover translation would alias with the wrong frame or cache mode
+            if clear_entry(table, page) {
+                tlb::flush(page.start_address());
+            }
+            let result = unsafe {
+                table
+                    .map_to(page, frame, flags, FRAME_ALLOC.lock().deref_mut())
+                    .expect("failed to map MMIO page")
+            };
+            result.flush();
+        }
+    });
+    mappings.live.push(Mapping {
+        phys: first,
+        virt,
+        pages,
+        cache_mode,
+        refs: 1,
+    });
+
+    MmioRegion {
+        virt: VirtAddr::new(virt + (phys.as_u64() - first)),
+        phys,
+        len,
+    }
+}
+
+impl Mappings {
+    /// Find `pages` pages of window space.
+    fn allocate(&mut self, pages: u64) -> u64 {
+        if let Some(index) = self.free.iter().position(|&(_, free)| free >= pages) {
+            let (start, free) = self.free[index];
+            if free == pages {
+                self.free.swap_remove(index);
+            } else {
+                self.free[index] = (start + pages * PAGE_SIZE, free - pages);
+            }
+            return start;
+        }
+
+        let start = self.next;
+        assert!(
+            start + pages * PAGE_SIZE <= MMIO_START + MMIO_SIZE,
+            "MMIO window exhausted"
+        );
+        self.next += pages * PAGE_SIZE;
+        start
+    }
+}
+
+/// The level 1 entry for `page`, if the tables above it exist.
+fn entry<'a>(
+    table: &'a mut OffsetPageTable<'_>,
+    page: Page<Size4KiB>,
+) -> Option<&'a mut PageTableEntry> {
+    let offset = table.phys_offset();
+    let mut level = table.level_4_table();
+    for index in [page.p4_index(), page.p3_index(), page.p2_index()] {
+        let flags = level[index].flags();
+        if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+            return None;
+        }
+        let next = offset + level[index].addr().as_u64();
+        level = unsafe { &mut *next.as_mut_ptr::<PageTable>() };
+    }
+    Some(&mut level[page.p1_index()])
+}
+
+/// Clear the translation for `page` without flushing it, returning whether there was one.
+/// `Mapper::unmap` won't touch write-combining pages, taking `PAT_4K` for a huge page bit.
+fn clear_entry(table: &mut OffsetPageTable, page: Page<Size4KiB>) -> bool {
+    match entry(table, page) {
+        Some(entry) if !entry.is_unused() => {
+            entry.set_unused();
+            true
+        }
+        _ => false,
+    }
+}
+
+fn page_base(addr: u64) -> u64 {
+    addr & !(PAGE_SIZE - 1)
+}
+
+#[test_case]
+fn test_map_share_unmap() {
+    use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Translate};
+
+    let frame = FRAME_ALLOC.lock().allocate_frame().unwrap();
+    let phys = frame.start_address();
+    unsafe { *((crate::PHYS_OFFSET + phys.as_u64() + 8) as *mut u64) = 0x1234_5678 };
+
+    let region = map_mmio(phys, 4096, CacheMode::WriteBack);
+    let inner = map_mmio(phys + 8u64, 8, CacheMode::WriteBack);
+    assert_eq!(inner.virt_addr(), region.virt_addr() + 8u64);
+    assert_eq!(unsafe { *inner.as_ptr::<u64>() }, 0x1234_5678);
+
+    let virt = region.virt_addr();
+    drop(region);
+    assert!(with_active_table(|table| table.translate_addr(virt).is_some()));
+    drop(inner);
+    assert!(with_active_table(|table| table.translate_addr(virt).is_none()));
+
+    unsafe { FRAME_ALLOC.lock().deallocate_frame(frame) };
+}
+
+#[test_case]
+fn test_write_combining_unmap() {
+    use x86_64::structures::paging::{FrameAllocator, FrameDeallocator};
+
+    let frame = FRAME_ALLOC.lock().allocate_frame().unwrap();
+    let region = map_mmio(frame.start_address(), 4096, CacheMode::WriteCombining);
+    unsafe { region.as_ptr::<u64>().write_volatile(0x1234_5678) };
+    let page = Page::<Size4KiB>::containing_address(region.virt_addr());
+    let flags = with_active_table(|table| entry(table, page).unwrap().flags());
+    assert!(flags.contains(PAT_4K));
+
+    drop(region);
+    assert!(with_active_table(|table| entry(table, page).unwrap().is_unused()));
+    // the freed range goes to the next mapping, which must not trip over anything left behind
+    let region = map_mmio(frame.start_address(), 4096, CacheMode::WriteBack);
+    assert_eq!(unsafe { region.as_ptr::<u64>().read_volatile() }, 0x1234_5678);
+    drop(region);
+
+    unsafe { FRAME_ALLOC.lock().deallocate_frame(frame) };
+}
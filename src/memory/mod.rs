@@ -0,0 +1,122 @@
+use bootloader::boot_info::MemoryRegions;
+use x86_64::{
+    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+pub use self::frame::BuddyFrameAllocator;
+pub use self::mmio::{map_mmio, CacheMode, MmioRegion};
+
+pub mod frame;
+pub mod mmio;
+pub mod stack;
+
+/// Initialize a new OffsetPageTable.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// complete physical memory is mapped to virtual memory at the passed
+/// `physical_memory_offset`. Also, this function must be only called once
+/// to avoid aliasing `&mut` references (which is undefined behavior).
+pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    let level_4_table = active_level_4_table(physical_memory_offset);
+    OffsetPageTable::new(level_4_table, physical_memory_offset)
+}
+
+/// Serializes changes made to the kernel page tables through `with_active_table`.
+static MAPPER_LOCK: spin::Mutex<()> = spin::Mutex::new(());
+
+/// Run `f` with a mapper for the active page table.
+///
+/// This is how code that runs after boot (heap growth, drivers) edits the kernel address space.
+/// Calls are serialized with each other, but not with early boot code still holding the
+/// `OffsetPageTable` from `init`. `f` must not allocate from the kernel heap, since growing the
+/// heap comes back through here.
+pub fn with_active_table<R>(f: impl FnOnce(&mut OffsetPageTable) -> R) -> R {
+    let _guard = MAPPER_LOCK.lock();
+    let phys_offset = VirtAddr::new(crate::PHYS_OFFSET);
+    // SAFETY: the physical memory map is set up by the bootloader, and MAPPER_LOCK keeps this the
+    // only table handed out at a time.
+    let mut table = unsafe { OffsetPageTable::new(active_level_4_table(phys_offset), phys_offset) };
+    f(&mut table)
+}
+
+/// Like `with_active_table`, but returns `None` instead of waiting if the table is in use,
+/// possibly by this CPU.
+pub fn try_with_active_table<R>(f: impl FnOnce(&mut OffsetPageTable) -> R) -> Option<R> {
+    let _guard = MAPPER_LOCK.try_lock()?;
+    let phys_offset = VirtAddr::new(crate::PHYS_OFFSET);
+    // SAFETY: as in `with_active_table`
+    let mut table = unsafe { OffsetPageTable::new(active_level_4_table(phys_offset), phys_offset) };
+    Some(f(&mut table))
+}
+
+pub fn phys_addr_of(table: &mut OffsetPageTable) -> PhysAddr {
+    let virt_addr = table.level_4_table() as *mut _ as u64;
+    let phys_offset = table.phys_offset().as_u64();
+    // virt_addr == phys_offset + physical_addr
+    PhysAddr::new(virt_addr - phys_offset)
+}
+
+/// Returns a mutable reference to the active level 4 table.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// complete physical memory is mapped to virtual memory at the passed
+/// `physical_memory_offset`. Also, this function must be only called once
+/// to avoid aliasing `&mut` references (which is undefined behavior).
+unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
+    use x86_64::registers::control::Cr3;
+
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let phys = level_4_table_frame.start_address();
+    let virt = physical_memory_offset + phys.as_u64();
+    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
+
+    &mut *page_table_ptr // unsafe
+}
+
+// pub fn print_lookup_path(level_4_table: &PageTable, addr: u64) {
+//     let page_table_walker = PageTableWa
+//     let p4 = &self.level_4_table;
+//     let p3 = self.page_table_walker.next_table(&p4[page.p4_index()])?;
+//     let p2 = self.page_table_walker.next_table(&p3[page.p3_index()])?;
+//     let p1 = self.page_table_walker.next_table(&p2[page.p2_index()])?;
+
+//     let p1_entry = &p1[page.p1_index()];
+
+//     if p1_entry.is_unused() {
+//         return Err(TranslateError::PageNotMapped);
+//     }
+
+//     PhysFrame::from_start_address(p1_entry.addr())
+//         .map_err(|AddressNotAligned| TranslateError::InvalidFrameAddress(p1_entry.addr()))
+//     const MASK: u64 = (1 << 9) - 1;
+//     let l4 = (addr >> 39) & MASK;
+//     let l3 = (addr >> 30) & MASK;
+//     let l2 = (addr >> 21) & MASK;
+//     let l1 = (addr >> 12) & MASK;
+
+//     let l4_entry = &level_4_table[l4 as usize];
+//     serial_println!("l4 entry: {:?}", l4_entry);
+
+//     let level_3_table = l4_entry.
+
+// }
+
+/// A FrameAllocator that always returns `None`.
+pub struct EmptyFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        None
+    }
+}
+
+pub static FRAME_ALLOC: spin::Mutex<BuddyFrameAllocator> =
+    spin::Mutex::new(BuddyFrameAllocator::empty());
+
+/// SAFETY: same preconditions as BuddyFrameAllocator::new
+pub unsafe fn init_frame_alloc(memory_map: &'static MemoryRegions) {
+    let new_allocator = unsafe { BuddyFrameAllocator::new(memory_map) };
+    *FRAME_ALLOC.lock() = new_allocator;
+}
@@ -0,0 +1,241 @@
+//! # Kernel stacks
+//! Every kernel stack gets its own slot in a dedicated virtual window. The stack sits at the top
+//! of its slot and everything below it, starting with the guard page right under its lowest
+//! page, stays unmapped, so running off the end faults instead of corrupting whatever used to
+//! be next to it in physical memory.
+//!
+//! Stacks from `KernelStack::new` only have their topmost pages mapped up front; the rest are
+//! mapped on first touch by the page fault handler, which runs on an IST stack so that it can
+//! service faults on the current one. That needs the page table and frame allocator locks, so
+//! stacks used while those may be held, or before the IDT is loaded, come from
+//! `KernelStack::new_mapped` instead.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page,
+        PageTableFlags, Size4KiB,
+    },
+    VirtAddr,
+};
+
+use super::{try_with_active_table, with_active_table, BuddyFrameAllocator, FRAME_ALLOC};
+use crate::smp;
+
+/// Start of the virtual window kernel stacks are placed in.
+pub const STACK_WINDOW_START: u64 = 0x_5800_0000_0000;
+/// Virtual space reserved per stack, including the guard page.
+pub const STACK_SLOT_SIZE: u64 = 1024 * 1024;
+/// Largest stack that fits in a slot, in pages.
+pub const MAX_STACK_PAGES: usize = (STACK_SLOT_SIZE / PAGE_SIZE) as usize - 1;
+const MAX_STACKS: usize = 1024;
+
+const PAGE_SIZE: u64 = 4096;
+/// Pages mapped when a demand-paged stack is created.
+const EAGER_PAGES: usize = 4;
+/// How many times the fault handler tries for the page table and frame allocator locks. The
+/// CPU holding them may be the faulting one, which would never let go.
+const FAULT_LOCK_TRIES: usize = 1 << 20;
+
+/// Size in pages of the stack in each slot, or 0 if the slot is free. Read by the page fault
+/// handler without taking any locks.
+static SLOT_PAGES: [AtomicUsize; MAX_STACKS] = {
+    const FREE: AtomicUsize = AtomicUsize::new(0);
+    [FREE; MAX_STACKS]
+};
+/// Serializes slot allocation.
+static SLOT_LOCK: Mutex<()> = Mutex::new(());
+
+/// A kernel stack with an unmapped guard page below it. Unmapped and freed on drop.
+#[derive(Debug)]
+pub struct KernelStack {
+    slot: usize,
+    pages: usize,
+}
+
+/// What a faulting address means for the kernel stacks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StackFault {
+    /// The address isn't in the stack window, or belongs to a free slot.
+    NotAStack,
+    /// The address is below the stack in its slot: the stack overflowed.
+    Overflow { bottom: VirtAddr, top: VirtAddr },
+    /// The address is in a not-yet-mapped page of a live stack, which has now been mapped.
+    Mapped,
+    /// The address is in a not-yet-mapped page of a live stack, but the page table or frame
+    /// allocator stayed locked, most likely by the faulting code itself.
+    Locked,
+}
+
+impl KernelStack {
+    /// Allocate a demand-paged stack of `pages` pages.
+    pub fn new(pages: usize) -> Option<KernelStack> {
+        Self::allocate(pages, EAGER_PAGES.min(pages))
+    }
+
+    /// Allocate a stack of `pages` pages with every page mapped up front. Used for IST stacks,
+    /// which have to work while the page fault handler can't.
+    pub fn new_mapped(pages: usize) -> Option<KernelStack> {
+        Self::allocate(pages, pages)
+    }
+
+    fn allocate(pages: usize, eager: usize) -> Option<KernelStack> {
+        assert!(pages > 0 && pages <= MAX_STACK_PAGES, "bad stack size");
+
+        let slot = {
+            let _guard = SLOT_LOCK.lock();
+            let slot = SLOT_PAGES
+                .iter()
+                .position(|p| p.load(Ordering::SeqCst) == 0)?;
+            SLOT_PAGES[slot].store(pages, Ordering::SeqCst);
+            slot
+        };
+
+        let stack = KernelStack { slot, pages };
+        let top = stack.top().as_u64();
+        for i in 1..=eager as u64 {
+            if !map_stack_page(VirtAddr::new(top - i * PAGE_SIZE)) {
+                // drop unmaps whatever made it in
+                return None;
+            }
+        }
+        Some(stack)
+    }
+
+    /// One past the highest usable address; the initial stack pointer.
+    pub fn top(&self) -> VirtAddr {
+        VirtAddr::new(STACK_WINDOW_START + (self.slot as u64 + 1) * STACK_SLOT_SIZE)
+    }
+
+    /// The lowest usable address.
+    pub fn bottom(&self) -> VirtAddr {
+        self.top() - self.pages as u64 * PAGE_SIZE
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let bottom = self.bottom().as_u64();
+        let mut frames = Vec::with_capacity(self.pages);
+        with_active_table(|table| {
+            for i in 0..self.pages as u64 {
+                let page =
+                    Page::<Size4KiB>::containing_address(VirtAddr::new(bottom + i * PAGE_SIZE));
+                if let Ok((frame, flush)) = table.unmap(page) {
+                    flush.ignore();
+                    frames.push(frame);
+                }
+            }
+        });
+        // no CPU may still reach the frames through its TLB when they're handed out again
+        smp::flush_tlb_range(self.bottom(), self.pages as u64);
+        for frame in frames {
+            unsafe { FRAME_ALLOC.lock().deallocate_frame(frame) };
+        }
+        SLOT_PAGES[self.slot].store(0, Ordering::SeqCst);
+    }
+}
+
+/// Back the page containing `addr` with a fresh frame. Returns whether it's mapped now, which
+/// it also is if another CPU got there first; false means out of memory.
+fn map_stack_page(addr: VirtAddr) -> bool {
+    with_active_table(|table| map_stack_page_in(table, &mut FRAME_ALLOC.lock(), addr))
+}
+
+fn map_stack_page_in(
+    table: &mut OffsetPageTable,
+    frame_allocator: &mut BuddyFrameAllocator,
+    addr: VirtAddr,
+) -> bool {
+    let page = Page::<Size4KiB>::containing_address(addr);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let frame = match frame_allocator.allocate_frame() {
+        Some(frame) => frame,
+        None => return false,
+    };
+    match unsafe { table.map_to(page, frame, flags, frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            true
+        }
+        Err(error) => {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            matches!(error, MapToError::PageAlreadyMapped(_))
+        }
+    }
+}
+
+/// Classify a page fault at `addr`, mapping the page if it is the lazy part of a live stack.
+///
+/// Called from the page fault handler, so it never waits on a lock for good: if this CPU faulted
+/// while holding the page table or frame allocator lock, it gives up with `Locked`.
+pub fn handle_page_fault(addr: VirtAddr) -> StackFault {
+    let addr = addr.as_u64();
+    if addr < STACK_WINDOW_START || addr >= STACK_WINDOW_START + MAX_STACKS as u64 * STACK_SLOT_SIZE
+    {
+        return StackFault::NotAStack;
+    }
+    let slot = ((addr - STACK_WINDOW_START) / STACK_SLOT_SIZE) as usize;
+    let pages = SLOT_PAGES[slot].load(Ordering::SeqCst);
+    if pages == 0 {
+        return StackFault::NotAStack;
+    }
+
+    let top = STACK_WINDOW_START + (slot as u64 + 1) * STACK_SLOT_SIZE;
+    let bottom = top - pages as u64 * PAGE_SIZE;
+    if addr < bottom {
+        StackFault::Overflow {
+            bottom: VirtAddr::new(bottom),
+            top: VirtAddr::new(top),
+        }
+    } else {
+        for _ in 0..FAULT_LOCK_TRIES {
+            let mapped = try_with_active_table(|table| {
+                let mut frame_allocator = FRAME_ALLOC.try_lock()?;
+                Some(map_stack_page_in(
+                    table,
+                    &mut frame_allocator,
+                    VirtAddr::new(addr),
+                ))
+            });
+            match mapped.flatten() {
+                Some(true) => return StackFault::Mapped,
+                Some(false) => panic!("out of memory growing kernel stack at {:#x}", addr),
+                None => core::hint::spin_loop(),
+            }
+        }
+        StackFault::Locked
+    }
+}
+
+/// Whether `addr` lies in the guard area of a live stack. Safe to call from the double fault
+/// handler, since it takes no locks and maps nothing.
+pub fn is_guard_hit(addr: VirtAddr) -> bool {
+    let addr = addr.as_u64();
+    if addr < STACK_WINDOW_START || addr >= STACK_WINDOW_START + MAX_STACKS as u64 * STACK_SLOT_SIZE
+    {
+        return false;
+    }
+    let slot = ((addr - STACK_WINDOW_START) / STACK_SLOT_SIZE) as usize;
+    let pages = SLOT_PAGES[slot].load(Ordering::SeqCst) as u64;
+    let top = STACK_WINDOW_START + (slot as u64 + 1) * STACK_SLOT_SIZE;
+    pages != 0 && addr < top - pages * PAGE_SIZE
+}
+
+#[test_case]
+fn test_stack_demand_paging() {
+    let stack = KernelStack::new(16).unwrap();
+    let bottom = stack.bottom();
+    // well below the eagerly mapped pages
+    let addr = bottom + 2 * PAGE_SIZE;
+    unsafe { addr.as_mut_ptr::<u64>().write_volatile(0xdead_beef) };
+    assert_eq!(unsafe { addr.as_ptr::<u64>().read_volatile() }, 0xdead_beef);
+
+    assert!(is_guard_hit(bottom - 1u64));
+    assert!(!is_guard_hit(bottom));
+    drop(stack);
+    assert!(!is_guard_hit(bottom - 1u64));
+}
@@ -0,0 +1,45 @@
+use core::cmp::PartialEq;
+use core::ops::{BitAnd, BitOr, Not};
+use core::ptr;
+
+use x86_64::VirtAddr;
+
+use crate::pio::Io;
+
+/// Generic memory mapped register.
+///
+/// `Mmio` has the same layout as `T`, so a device's register file can be described as a
+/// `#[repr(C)]` struct of `Mmio`, `ReadOnly<Mmio>` and `WriteOnly<Mmio>` fields, with reserved
+/// arrays for the gaps, and then laid over the mapped registers with `register_block`.
+#[repr(transparent)]
+pub struct Mmio<T> {
+    value: T,
+}
+
+impl<T> Io for Mmio<T>
+where
+    T: Copy + PartialEq + BitAnd<Output = T> + BitOr<Output = T> + Not<Output = T>,
+{
+    type Value = T;
+
+    /// Read
+    #[inline(always)]
+    fn read(&self) -> T {
+        unsafe { ptr::read_volatile(&self.value) }
+    }
+
+    /// Write
+    #[inline(always)]
+    fn write(&mut self, value: T) {
+        unsafe { ptr::write_volatile(&mut self.value, value) };
+    }
+}
+
+/// Reinterpret the mapped registers at `addr` as the register block `T`.
+///
+/// This function is unsafe because the caller must guarantee that `addr` stays mapped with a
+/// suitable cache mode for the rest of the kernel's life, that `T` matches the device's layout,
+/// and that nothing else holds a reference to the same registers.
+pub unsafe fn register_block<T>(addr: VirtAddr) -> &'static mut T {
+    &mut *addr.as_mut_ptr::<T>()
+}
@@ -0,0 +1,205 @@
+//! # Per-CPU data
+//! Every CPU has a `PerCpu` block, and `IA32_GS_BASE` points at the current CPU's one. The first
+//! word of the block points back at the block itself, so finding it is a single `gs`-relative
+//! load. The `percpu!` macro reads a field of the current CPU's block.
+//!
+//! The BSP's block is static and installed first thing in `init`. AP blocks are allocated by the
+//! BSP when it starts the AP, and the AP installs its block first thing in `kstart_ap`.
+
+use alloc::boxed::Box;
+use core::arch::asm;
+use core::ptr::{addr_of, null_mut};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+
+use spin::Mutex;
+use x86::msr::{wrmsr, IA32_GS_BASE};
+
+use crate::memory::stack::KernelStack;
+use crate::smp::CallQueue;
+use crate::time::timer::TimerWheel;
+
+/// Highest number of CPUs the kernel keeps per-CPU blocks for.
+pub const MAX_CPUS: usize = 1024;
+
+/// Size of each CPU's scratch stack, in pages.
+const SCRATCH_STACK_PAGES: usize = 16;
+
+/// Data owned by one CPU. Fields other CPUs may touch, or that change after bring-up, are
+/// atomics.
+#[repr(C)]
+#[derive(Debug)]
+pub struct PerCpu {
+    /// Points at this block. Must stay the first field; `current` relies on it.
+    this: *const PerCpu,
+    /// Logical CPU index: 0 for the BSP, then APs in the order they were started.
+    pub cpu_index: usize,
+    pub apic_id: u32,
+    /// The task running on this CPU, or 0 if there is none.
+    pub current_task: AtomicUsize,
+    /// Top of a stack for code that can't trust the one it's on, or 0 until allocated.
+    pub scratch_stack: AtomicU64,
+    pub counters: Counters,
+    /// Timers armed on this CPU.
+    pub timers: Mutex<TimerWheel>,
+    /// Whether this CPU takes cross-CPU calls and TLB shootdowns yet.
+    pub online: AtomicBool,
+    /// Cross-CPU calls waiting to run on this CPU.
+    pub calls: CallQueue,
+}
+
+/// Per-CPU event counters.
+#[derive(Debug)]
+pub struct Counters {
+    pub interrupts: AtomicU64,
+    pub page_faults: AtomicU64,
+    /// Local APIC timer ticks taken by this CPU.
+    pub timer_ticks: AtomicU64,
+}
+
+// SAFETY: `this` only ever points at the block itself, which is never freed.
+unsafe impl Sync for PerCpu {}
+
+static mut BSP_PERCPU: PerCpu = PerCpu::new(0, 0);
+
+/// Every CPU's block, by logical index.
+static CPUS: [AtomicPtr<PerCpu>; MAX_CPUS] = {
+    const NONE: AtomicPtr<PerCpu> = AtomicPtr::new(null_mut());
+    [NONE; MAX_CPUS]
+};
+
+/// Read a field of the current CPU's `PerCpu` block, e.g. `percpu!(cpu_index)` or
+/// `percpu!(counters.interrupts).load(Ordering::Relaxed)`.
+#[macro_export]
+macro_rules! percpu {
+    ($($field:tt)+) => {
+        $crate::percpu::current().$($field)+
+    };
+}
+
+impl PerCpu {
+    const fn new(cpu_index: usize, apic_id: u32) -> PerCpu {
+        PerCpu {
+            this: core::ptr::null(),
+            cpu_index,
+            apic_id,
+            current_task: AtomicUsize::new(0),
+            scratch_stack: AtomicU64::new(0),
+            counters: Counters {
+                interrupts: AtomicU64::new(0),
+                page_faults: AtomicU64::new(0),
+                timer_ticks: AtomicU64::new(0),
+            },
+            timers: Mutex::new(TimerWheel::new()),
+            online: AtomicBool::new(false),
+            calls: CallQueue::new(),
+        }
+    }
+}
+
+/// The current CPU's block.
+#[inline]
+pub fn current() -> &'static PerCpu {
+    let this: *const PerCpu;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) this, options(nostack, readonly, preserves_flags));
+        &*this
+    }
+}
+
+/// The block of the CPU with logical index `cpu_index`, if it has been brought up.
+pub fn get(cpu_index: usize) -> Option<&'static PerCpu> {
+    let block = CPUS.get(cpu_index)?.load(Ordering::SeqCst);
+    unsafe { block.as_ref() }
+}
+
+/// Install the BSP's block. Runs before anything else in `init`, so it mustn't allocate.
+pub unsafe fn init_bsp() {
+    let block = &mut *core::ptr::addr_of_mut!(BSP_PERCPU);
+    *block = PerCpu::new(0, cpuid_apic_id());
+    install(block);
+}
+
+/// Allocate the block for the AP with the given logical index and APIC ID, for it to install
+/// with `init_ap` once it's running.
+pub fn allocate_ap(cpu_index: usize, apic_id: u32) {
+    assert!(cpu_index < MAX_CPUS, "CPU index {} out of range", cpu_index);
+    let block = Box::leak(Box::new(PerCpu::new(cpu_index, apic_id)));
+    block.this = block;
+    CPUS[cpu_index].store(block, Ordering::SeqCst);
+}
+
+/// Free the block of an AP that failed to start, so the index can go to the next one. The AP
+/// must be held in INIT, so that nothing refers to the block any more.
+pub unsafe fn release_ap(cpu_index: usize) {
+    let block = CPUS[cpu_index].swap(null_mut(), Ordering::SeqCst);
+    if !block.is_null() {
+        drop(Box::from_raw(block));
+    }
+}
+
+/// Point GS at the block `allocate_ap` set up for this AP. Must run before the AP touches
+/// anything that uses `percpu!`, including the heap.
+pub unsafe fn init_ap(cpu_index: usize) {
+    let block = CPUS[cpu_index].load(Ordering::SeqCst);
+    assert!(!block.is_null(), "no per-CPU block for CPU {}", cpu_index);
+    wrmsr(IA32_GS_BASE, block as u64);
+}
+
+unsafe fn install(block: &'static mut PerCpu) {
+    block.this = block;
+    CPUS[block.cpu_index].store(block, Ordering::SeqCst);
+    wrmsr(IA32_GS_BASE, addr_of!(*block) as u64);
+}
+
+/// Give the current CPU its scratch stack. Needs the frame allocator.
+pub fn init_scratch_stack() {
+    // fully mapped, since whatever runs on it may hold the locks demand paging needs
+    let stack = KernelStack::new_mapped(SCRATCH_STACK_PAGES).expect("no memory for scratch stack");
+    current()
+        .scratch_stack
+        .store(stack.top().as_u64(), Ordering::SeqCst);
+    core::mem::forget(stack);
+}
+
+/// APIC ID of the current CPU according to CPUID, the full x2APIC ID where available.
+fn cpuid_apic_id() -> u32 {
+    let cpuid = x86::cpuid::CpuId::new();
+    if let Some(mut topology) = cpuid.get_extended_topology_info() {
+        if let Some(level) = topology.next() {
+            return level.x2apic_id();
+        }
+    }
+    cpuid
+        .get_feature_info()
+        .map_or(0, |info| u32::from(info.initial_local_apic_id()))
+}
+
+#[test_case]
+fn test_percpu_blocks() {
+    fn check() {
+        let block = current();
+        assert!(core::ptr::eq(get(percpu!(cpu_index)).unwrap(), block));
+        assert_eq!(percpu!(apic_id), cpuid_apic_id());
+        assert_ne!(percpu!(scratch_stack).load(Ordering::SeqCst), 0);
+    }
+
+    crate::run_on_all_cpus(check);
+
+    let cpus = crate::ap_init::CPU_COUNT.load(Ordering::SeqCst);
+    for index in 0..cpus {
+        assert_eq!(get(index).unwrap().cpu_index, index);
+    }
+    assert!(get(cpus).is_none());
+}
+
+#[test_case]
+fn test_percpu_counters() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let before = percpu!(counters.interrupts).load(Ordering::Relaxed);
+        percpu!(counters.interrupts).fetch_add(1, Ordering::Relaxed);
+        assert_eq!(
+            percpu!(counters.interrupts).load(Ordering::Relaxed),
+            before + 1
+        );
+    });
+}
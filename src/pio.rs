@@ -114,3 +114,46 @@ impl Io for Pio<u32> {
         }
     }
 }
+
+/// A register that can only be read, e.g. a status register.
+#[repr(transparent)]
+pub struct ReadOnly<I> {
+    inner: I,
+}
+
+impl<I> ReadOnly<I> {
+    pub const fn new(inner: I) -> ReadOnly<I> {
+        ReadOnly { inner }
+    }
+}
+
+impl<I: Io> ReadOnly<I> {
+    #[inline(always)]
+    pub fn read(&self) -> I::Value {
+        self.inner.read()
+    }
+
+    #[inline(always)]
+    pub fn readf(&self, flags: I::Value) -> bool {
+        self.inner.readf(flags)
+    }
+}
+
+/// A register that can only be written, e.g. a doorbell or end-of-interrupt register.
+#[repr(transparent)]
+pub struct WriteOnly<I> {
+    inner: I,
+}
+
+impl<I> WriteOnly<I> {
+    pub const fn new(inner: I) -> WriteOnly<I> {
+        WriteOnly { inner }
+    }
+}
+
+impl<I: Io> WriteOnly<I> {
+    #[inline(always)]
+    pub fn write(&mut self, value: I::Value) {
+        self.inner.write(value)
+    }
+}
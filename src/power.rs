@@ -0,0 +1,167 @@
+//! # Power
+//! Rebooting and powering off the machine. Both go through ACPI's fixed hardware as described by
+//! the FADT, and fall back to legacy mechanisms on machines where that doesn't work.
+//!
+//! The S5 sleep types come from AML, which `init` runs once at boot, so that powering off later,
+//! including from the panic handler, never has to lock the namespace.
+
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use spin::Once;
+use x86_64::instructions::{interrupts, tables::lidt};
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::VirtAddr;
+
+use crate::acpi::aml;
+use crate::acpi::fadt::{Fadt, FixedRegisters, FADT, REGISTERS};
+use crate::acpi::gas::Register;
+use crate::pio::{Io, Pio};
+use crate::{serial_println, time};
+
+/// How long each reset method gets to take effect before the next one is tried.
+const RESET_WAIT: Duration = Duration::from_millis(100);
+/// How long the firmware gets to switch to ACPI mode.
+const ACPI_ENABLE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// PM1 control register bits.
+const SCI_EN: u64 = 1 << 0;
+const SLP_TYP_SHIFT: u64 = 10;
+const SLP_TYP_MASK: u64 = 0b111 << SLP_TYP_SHIFT;
+const SLP_EN: u64 = 1 << 13;
+
+/// 8042 keyboard controller command port, its input buffer full status bit, and the command
+/// that pulses the CPU reset line.
+const KBC_COMMAND: u16 = 0x64;
+const KBC_INPUT_FULL: u8 = 1 << 1;
+const KBC_PULSE_RESET: u8 = 0xFE;
+
+/// SLP_TYPa and SLP_TYPb for S5, found by `init`.
+static S5_SLEEP_TYPES: Once<(u8, u8)> = Once::new();
+
+/// Read the S5 sleep types from `\_S5`. Must run after the AML namespace is loaded.
+pub fn init() {
+    if let Some(types) = s5_sleep_types() {
+        S5_SLEEP_TYPES.call_once(|| types);
+    }
+}
+
+/// Restart the machine: through the FADT reset register, then the 8042 keyboard controller, and
+/// as a last resort with a triple fault.
+pub fn reboot() -> ! {
+    serial_println!("Rebooting");
+    interrupts::disable();
+
+    if let Some((register, value)) = REGISTERS.get().and_then(|registers| registers.reset) {
+        register.write(u64::from(value));
+        time::sleep_for(RESET_WAIT);
+    }
+
+    let mut kbc = Pio::<u8>::new(KBC_COMMAND);
+    time::poll_until(RESET_WAIT, || kbc.read() & KBC_INPUT_FULL == 0);
+    kbc.write(KBC_PULSE_RESET);
+    time::sleep_for(RESET_WAIT);
+
+    // with no IDT, the breakpoint faults, the fault double faults and the double fault resets
+    unsafe {
+        lidt(&DescriptorTablePointer {
+            limit: 0,
+            base: VirtAddr::new(0),
+        });
+    }
+    x86_64::instructions::interrupts::int3();
+    crate::hlt_loop();
+}
+
+/// Power the machine off by entering ACPI sleep state S5. Returns if that isn't possible.
+pub fn shutdown() {
+    let (fadt, registers) = match (FADT.get(), REGISTERS.get()) {
+        (Some(fadt), Some(registers)) => (fadt, registers),
+        _ => {
+            serial_println!("Can't shut down: no FADT");
+            return;
+        }
+    };
+    let (pm1a, &types) = match (registers.pm1a_control, S5_SLEEP_TYPES.get()) {
+        (Some(pm1a), Some(types)) => (pm1a, types),
+        _ => {
+            serial_println!("Can't shut down: no PM1a control block or \\_S5");
+            return;
+        }
+    };
+    if !enable_acpi(fadt, pm1a) {
+        serial_println!("Can't shut down: ACPI mode won't enable");
+        return;
+    }
+
+    serial_println!("Shutting down");
+    interrupts::disable();
+    enter_s5(registers, pm1a, types);
+
+    time::sleep_for(RESET_WAIT);
+    serial_println!("Still running after entering S5");
+}
+
+/// Power off right away, taking no locks and running no AML, for the panic handler. Only works
+/// once `init` has run and ACPI mode is on; returns if the machine is still running.
+pub fn shutdown_now() {
+    interrupts::disable();
+    let registers = match REGISTERS.get() {
+        Some(registers) => registers,
+        None => return,
+    };
+    if let (Some(pm1a), Some(&types)) = (registers.pm1a_control, S5_SLEEP_TYPES.get()) {
+        if pm1a.read().map_or(false, |value| value & SCI_EN != 0) {
+            enter_s5(registers, pm1a, types);
+        }
+    }
+}
+
+/// Write the S5 sleep types and SLP_EN to the PM1 control registers.
+fn enter_s5(registers: &FixedRegisters, pm1a: Register, (slp_typ_a, slp_typ_b): (u8, u8)) {
+    let sleep = |block: Register, slp_typ: u8| {
+        let value = block.read().unwrap_or(0) & !SLP_TYP_MASK;
+        block.write(value | u64::from(slp_typ) << SLP_TYP_SHIFT | SLP_EN);
+    };
+    sleep(pm1a, slp_typ_a);
+    if let Some(pm1b) = registers.pm1b_control {
+        sleep(pm1b, slp_typ_b);
+    }
+}
+
+/// Switch the chipset from legacy to ACPI mode if the firmware left it in legacy mode.
+fn enable_acpi(fadt: &Fadt, pm1a: Register) -> bool {
+    let sci_enabled = || pm1a.read().map_or(false, |value| value & SCI_EN != 0);
+    if sci_enabled() {
+        return true;
+    }
+    let (port, enable) = (fadt.smi_command_port, fadt.acpi_enable);
+    if port == 0 || enable == 0 {
+        // hardware-reduced, or no way to switch
+        return false;
+    }
+    Pio::<u8>::new(port as u16).write(enable);
+    time::poll_until(ACPI_ENABLE_TIMEOUT, sci_enabled)
+}
+
+/// SLP_TYPa and SLP_TYPb for S5, from the `\_S5` package.
+fn s5_sleep_types() -> Option<(u8, u8)> {
+    let s5 = match aml::evaluate("\\_S5", Vec::new()) {
+        Ok(s5) => s5,
+        Err(error) => {
+            serial_println!("Can't evaluate \\_S5: {:?}", error);
+            return None;
+        }
+    };
+    let types = s5.as_package().ok()?;
+    let slp_typ = |i: usize| Some(types.get(i)?.as_integer().ok()? as u8);
+    let slp_typ_a = slp_typ(0)?;
+    Some((slp_typ_a, slp_typ(1).unwrap_or(slp_typ_a)))
+}
+
+#[test_case]
+fn test_s5_sleep_types() {
+    let (slp_typ_a, slp_typ_b) = s5_sleep_types().expect("no \\_S5 in the DSDT");
+    assert!(slp_typ_a < 8 && slp_typ_b < 8);
+    assert_eq!(S5_SLEEP_TYPES.get(), Some(&(slp_typ_a, slp_typ_b)));
+}
@@ -20,6 +20,17 @@ pub fn _print(args: ::core::fmt::Arguments) {
     });
 }
 
+/// Print `args` unless the port is in use, and return whether it printed. For handlers such as
+/// the NMI's, which can interrupt a print on their own CPU and would wait on it forever.
+pub fn try_print(args: ::core::fmt::Arguments) -> bool {
+    use core::fmt::Write;
+
+    match SERIAL1.try_lock() {
+        Some(mut port) => port.write_fmt(args).is_ok(),
+        None => false,
+    }
+}
+
 /// Prints to the host through the serial interface.
 #[macro_export]
 macro_rules! serial_print {
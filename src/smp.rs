@@ -0,0 +1,218 @@
+//! # Cross-CPU calls
+//! `call_on` and `call_on_all` run a closure on other CPUs: the caller queues it on each target's
+//! `PerCpu` block, sends it `IPI_CALL_VECTOR`, and waits until every target has run it. TLB
+//! shootdowns are built on top: code that unmaps or changes pages other CPUs may have cached
+//! calls `flush_tlb_range` or `flush_tlb_all` once the page table lock is released.
+//!
+//! A CPU only takes calls once it has marked itself online with `mark_online`, after its local
+//! APIC is up. A CPU waiting for a call to finish runs the calls queued for it meanwhile, so two
+//! CPUs calling each other don't deadlock. One that spins on a lock with interrupts off while
+//! the holder waits on a call to it still does, so don't make calls with such locks held.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Mutex;
+use x86_64::instructions::{interrupts, tlb};
+use x86_64::VirtAddr;
+
+use crate::ap_init::CPU_COUNT;
+use crate::device::local_apic::LOCAL_APIC;
+use crate::interrupts::irq::{self, IPI_CALL_VECTOR};
+use crate::percpu;
+use crate::percpu::PerCpu;
+
+/// Ranges longer than this many pages are flushed by reloading CR3 instead of page by page.
+const FLUSH_ALL_THRESHOLD: u64 = 32;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// A closure waiting to run on some CPUs. Lives on the caller's stack until `pending` drops
+/// to zero.
+struct Call {
+    func: *const (dyn Fn() + Sync),
+    /// Targets that haven't finished running `func` yet.
+    pending: AtomicUsize,
+}
+
+/// Calls queued for a CPU, in its `PerCpu` block.
+pub struct CallQueue(Mutex<Vec<*const Call>>);
+
+// SAFETY: a queued `Call` stays alive and unmoved until every target has run it.
+unsafe impl Send for CallQueue {}
+unsafe impl Sync for CallQueue {}
+
+impl CallQueue {
+    pub const fn new() -> CallQueue {
+        CallQueue(Mutex::new(Vec::new()))
+    }
+}
+
+impl core::fmt::Debug for CallQueue {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_tuple("CallQueue")
+            .field(&self.0.lock().len())
+            .finish()
+    }
+}
+
+/// Take calls on the BSP. Needs its local APIC and the IRQ table.
+pub fn init() {
+    irq::register_vector(IPI_CALL_VECTOR, run_queued_calls);
+    mark_online();
+}
+
+/// Start taking calls and TLB shootdowns on this CPU. Flushes the TLB, since shootdowns sent
+/// before now missed it.
+pub fn mark_online() {
+    percpu!(online).store(true, Ordering::SeqCst);
+    tlb::flush_all();
+}
+
+/// Whether the CPU with logical index `cpu` takes calls.
+pub fn is_online(cpu: usize) -> bool {
+    percpu::get(cpu).map_or(false, |block| block.online.load(Ordering::SeqCst))
+}
+
+/// How many CPUs take calls. Less than `CPU_COUNT` if an AP got stuck during start-up.
+pub fn online_count() -> usize {
+    (0..CPU_COUNT.load(Ordering::SeqCst))
+        .filter(|&cpu| is_online(cpu))
+        .count()
+}
+
+/// Run `f` on CPU `cpu` and wait for it to finish. Returns false, without running `f`, if that
+/// CPU isn't online.
+pub fn call_on(cpu: usize, f: impl Fn() + Sync) -> bool {
+    if !is_online(cpu) {
+        return false;
+    }
+    if cpu == percpu!(cpu_index) {
+        f();
+    } else {
+        call_many(core::iter::once(cpu), &f);
+    }
+    true
+}
+
+/// Run `f` on every online CPU, this one included, and wait for all of them to finish.
+pub fn call_on_all(f: impl Fn() + Sync) {
+    call_on_others(&f);
+    f();
+}
+
+/// Run `f` on every online CPU but this one, and wait for them to finish.
+pub fn call_on_others(f: impl Fn() + Sync) {
+    let me = percpu!(cpu_index);
+    let cpus = CPU_COUNT.load(Ordering::SeqCst);
+    call_many((0..cpus).filter(|&cpu| cpu != me && is_online(cpu)), &f);
+}
+
+fn call_many(targets: impl Iterator<Item = usize>, f: &(dyn Fn() + Sync)) {
+    // SAFETY: the targets are done with `f` by the time this returns
+    let f: &'static (dyn Fn() + Sync) = unsafe { core::mem::transmute(f) };
+    let call = Call {
+        func: f,
+        pending: AtomicUsize::new(0),
+    };
+    for cpu in targets {
+        let block: &PerCpu = percpu::get(cpu).expect("call to a missing CPU");
+        call.pending.fetch_add(1, Ordering::SeqCst);
+        block.calls.0.lock().push(&call);
+        unsafe { (*core::ptr::addr_of_mut!(LOCAL_APIC)).ipi(block.apic_id, IPI_CALL_VECTOR) };
+    }
+
+    while call.pending.load(Ordering::SeqCst) != 0 {
+        run_queued_calls();
+        core::hint::spin_loop();
+    }
+}
+
+/// Run the calls queued for this CPU. The `IPI_CALL_VECTOR` handler.
+fn run_queued_calls() {
+    loop {
+        // with interrupts on, the IPI could come in while the queue is locked
+        let call = interrupts::without_interrupts(|| percpu!(calls).0.lock().pop());
+        let call = match call {
+            Some(call) => call,
+            None => return,
+        };
+        unsafe {
+            (*(*call).func)();
+            // the caller may return as soon as this hits zero, so it's the last access
+            (*call).pending.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Flush `pages` pages from `start` out of every online CPU's TLB.
+pub fn flush_tlb_range(start: VirtAddr, pages: u64) {
+    let flush = || {
+        if pages > FLUSH_ALL_THRESHOLD {
+            tlb::flush_all();
+        } else {
+            for i in 0..pages {
+                tlb::flush(start + i * PAGE_SIZE);
+            }
+        }
+    };
+    call_on_all(flush);
+}
+
+/// Flush every online CPU's whole TLB, except global pages.
+pub fn flush_tlb_all() {
+    call_on_all(tlb::flush_all);
+}
+
+#[cfg(test)]
+fn test_call_on_each_cpu() {
+    let cpus = CPU_COUNT.load(Ordering::SeqCst);
+    for cpu in 0..cpus {
+        let ran_on = AtomicUsize::new(usize::MAX);
+        assert!(call_on(cpu, || ran_on.store(percpu!(cpu_index), Ordering::SeqCst)));
+        assert_eq!(ran_on.load(Ordering::SeqCst), cpu);
+    }
+    assert!(!call_on(cpus, || ()));
+
+    let runs = AtomicUsize::new(0);
+    call_on_all(|| {
+        runs.fetch_add(1, Ordering::SeqCst);
+    });
+    assert_eq!(runs.load(Ordering::SeqCst), cpus);
+}
+crate::smp_test!(test_call_on_each_cpu, 2);
+
+#[cfg(test)]
+fn test_tlb_shootdown() {
+    use crate::memory::mmio::{map_mmio, CacheMode};
+    use crate::memory::FRAME_ALLOC;
+    use x86_64::structures::paging::{FrameAllocator, FrameDeallocator};
+
+    let frames = [
+        FRAME_ALLOC.lock().allocate_frame().unwrap(),
+        FRAME_ALLOC.lock().allocate_frame().unwrap(),
+    ];
+    for (i, frame) in frames.iter().enumerate() {
+        let addr = crate::PHYS_OFFSET + frame.start_address().as_u64();
+        unsafe { *(addr as *mut u64) = 0x5eed_0000 + i as u64 };
+    }
+
+    // get the first frame's translation into every TLB, then unmap it. The second frame is
+    // likely mapped at the same address, and a CPU that missed the shootdown would still see
+    // the first one there.
+    for (i, frame) in frames.iter().enumerate() {
+        let region = map_mmio(frame.start_address(), 8, CacheMode::WriteBack);
+        let ptr = region.as_ptr::<u64>() as usize;
+        let seen = crate::map_all_cpus(|| unsafe { *(ptr as *const u64) });
+        assert!(
+            seen.iter().all(|&value| value == 0x5eed_0000 + i as u64),
+            "stale translation: {:x?}",
+            seen
+        );
+    }
+
+    for frame in frames {
+        unsafe { FRAME_ALLOC.lock().deallocate_frame(frame) };
+    }
+}
+crate::smp_test!(test_tlb_shootdown, 2);
@@ -0,0 +1,100 @@
+//! # Clocksources
+//! Anything that can tell the time registers itself here with a rating, and the monotonic clock
+//! follows the best-rated one. Switching sources keeps the clock continuous: the new source
+//! takes over from whatever the old one read at the moment of the switch.
+
+use alloc::vec::Vec;
+
+use spin::RwLock;
+
+use crate::serial_println;
+
+/// Ratings of the kernel's own clocksources. Higher is better.
+pub mod rating {
+    /// A TSC that keeps a constant rate through power state changes.
+    pub const INVARIANT_TSC: u32 = 400;
+    pub const HPET: u32 = 300;
+    /// A TSC that may change rate or stop in deep C-states.
+    pub const TSC: u32 = 150;
+    /// Tick counters, only as precise as `TICK_HZ`.
+    pub const LAPIC_TICK: u32 = 50;
+    pub const PIT_TICK: u32 = 40;
+}
+
+/// A monotonic counter the kernel can tell the time by.
+pub trait ClockSource: Sync {
+    fn name(&self) -> &'static str;
+
+    /// How good this source is; the highest-rated one registered drives the clock.
+    fn rating(&self) -> u32;
+
+    /// Nanoseconds since an epoch of the source's choosing. Must never go backwards, on any
+    /// CPU.
+    fn read_ns(&self) -> u64;
+}
+
+struct Current {
+    source: &'static dyn ClockSource,
+    /// What `source` read when it took over.
+    source_base: u64,
+    /// What the clock read when `source` took over.
+    clock_base: u64,
+}
+
+static SOURCES: RwLock<Vec<&'static dyn ClockSource>> = RwLock::new(Vec::new());
+static CURRENT: RwLock<Option<Current>> = RwLock::new(None);
+
+/// Make `source` available, switching the clock over to it if it beats the current one.
+pub fn register(source: &'static dyn ClockSource) {
+    SOURCES.write().push(source);
+
+    let mut current = CURRENT.write();
+    let better = current
+        .as_ref()
+        .map_or(true, |current| source.rating() > current.source.rating());
+    if !better {
+        return;
+    }
+
+    let clock_base = current.as_ref().map_or(0, now_from);
+    serial_println!(
+        "Clocksource: {} (rating {})",
+        source.name(),
+        source.rating()
+    );
+    *current = Some(Current {
+        source,
+        source_base: source.read_ns(),
+        clock_base,
+    });
+}
+
+/// Nanoseconds since the first clocksource was registered, or 0 before that.
+pub fn now_ns() -> u64 {
+    CURRENT.read().as_ref().map_or(0, now_from)
+}
+
+fn now_from(current: &Current) -> u64 {
+    // a source read on another CPU may trail the base by a hair
+    let elapsed = current.source.read_ns().saturating_sub(current.source_base);
+    current.clock_base + elapsed
+}
+
+/// Name and rating of the clocksource the clock follows.
+pub fn current() -> Option<(&'static str, u32)> {
+    CURRENT
+        .read()
+        .as_ref()
+        .map(|current| (current.source.name(), current.source.rating()))
+}
+
+/// Every registered clocksource, in registration order.
+pub fn sources() -> Vec<&'static dyn ClockSource> {
+    SOURCES.read().clone()
+}
+
+#[test_case]
+fn test_best_clocksource_wins() {
+    let best = sources().iter().map(|source| source.rating()).max();
+    assert_eq!(current().map(|(_, rating)| rating), best);
+}
@@ -0,0 +1,264 @@
+//! # Timekeeping
+//! The system tick is every CPU's local APIC timer, calibrated against the PIT at boot and
+//! running in periodic mode at `TICK_HZ`. Each CPU counts its own ticks in its per-CPU block;
+//! the BSP's ticks also drive the global counter returned by `ticks`. The BSP can take its
+//! ticks from the HPET or the PIT on legacy IRQ 0 instead, see `device::init_noncore`.
+//!
+//! Monotonic time comes from the best clocksource registered with `clocksource`: the TSC,
+//! HPET, or failing those a tick counter. Wall-clock time is the RTC's reading at boot
+//! advanced by the monotonic clock.
+
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use x86_64::instructions::{hlt, interrupts as cpu_interrupts};
+
+use crate::device::local_apic::{LvtTimerMode, LOCAL_APIC};
+use crate::interrupts::{self, register_irq, unregister_irq};
+use crate::percpu;
+
+pub mod clocksource;
+pub mod timer;
+
+pub use self::clocksource::ClockSource;
+pub use self::timer::Timer;
+
+/// Rate of the system tick, in Hz.
+pub const TICK_HZ: u32 = 100;
+/// Length of one tick, in nanoseconds.
+pub const TICK_NS: u64 = 1_000_000_000 / TICK_HZ as u64;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// Unix time when the RTC was read at boot.
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+/// Monotonic time when the RTC was read at boot.
+static BOOT_TIME_MONOTONIC: AtomicU64 = AtomicU64::new(0);
+
+/// Ticks since the BSP's timer was started.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Milliseconds since the BSP's timer was started, to tick precision.
+pub fn uptime_ms() -> u64 {
+    ticks() * 1000 / u64::from(TICK_HZ)
+}
+
+/// Nanoseconds on the monotonic clock.
+pub fn monotonic_ns() -> u64 {
+    clocksource::now_ns()
+}
+
+/// A point on the monotonic clock.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Instant {
+        Instant(monotonic_ns())
+    }
+
+    /// Time from `earlier` to `self`, or zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    /// Nanoseconds since the monotonic clock started.
+    pub fn as_nanos(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        Instant(self.0.saturating_add(duration.as_nanos() as u64))
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// Unix time the kernel booted at, to the second, or 0 before the RTC has been read.
+pub fn boot_time() -> u64 {
+    BOOT_TIME.load(Ordering::Relaxed)
+}
+
+pub(crate) fn set_boot_time(unix_time: u64) {
+    BOOT_TIME_MONOTONIC.store(monotonic_ns(), Ordering::Relaxed);
+    BOOT_TIME.store(unix_time, Ordering::Relaxed);
+}
+
+/// Current time since the Unix epoch.
+pub fn wall_clock() -> Duration {
+    let since_rtc = monotonic_ns().saturating_sub(BOOT_TIME_MONOTONIC.load(Ordering::Relaxed));
+    Duration::from_secs(boot_time()) + Duration::from_nanos(since_rtc)
+}
+
+/// Wait until the monotonic clock reaches `deadline`.
+///
+/// With interrupts on, the CPU halts between ticks and only spins through the last one. With
+/// them off, it spins throughout, which never ends if the clock only advances on ticks.
+pub fn sleep_until(deadline: Instant) {
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return;
+        }
+        if cpu_interrupts::are_enabled() && deadline.0 - now.0 > TICK_NS {
+            hlt();
+        } else {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Wait for at least `duration`. See `sleep_until`.
+pub fn sleep_for(duration: Duration) {
+    sleep_until(Instant::now() + duration);
+}
+
+/// Spin until `done` returns true or `timeout` has passed, returning whether it did. Works with
+/// interrupts off as long as the clocksource runs by itself, which the TSC always does.
+pub fn poll_until(timeout: Duration, mut done: impl FnMut() -> bool) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if done() {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return done();
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Handler for the local APIC timer vector, and for IRQ 0 once `use_irq0_tick` has run.
+pub(crate) fn tick() {
+    percpu!(counters.timer_ticks).fetch_add(1, Ordering::Relaxed);
+    if percpu!(cpu_index) == 0 {
+        TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+    timer::run_local();
+}
+
+/// Take the BSP's ticks from legacy IRQ 0, which the caller has set up to fire at `TICK_HZ`,
+/// instead of its local APIC timer. Runs on the BSP.
+pub(crate) unsafe fn use_irq0_tick() {
+    // the BSP takes IRQ 0, so its local APIC timer would tick it twice
+    (*core::ptr::addr_of_mut!(LOCAL_APIC)).stop_timer();
+    register_irq(0, tick);
+    unregister_irq(0, interrupts::timer_interrupt_handler);
+}
+
+/// Undo `use_irq0_tick`, taking the BSP's ticks from its local APIC timer again. Runs on the
+/// BSP, and only if that timer was calibrated.
+pub(crate) unsafe fn use_lapic_tick() {
+    register_irq(0, interrupts::timer_interrupt_handler);
+    unregister_irq(0, tick);
+    (*core::ptr::addr_of_mut!(LOCAL_APIC)).start_timer(LvtTimerMode::Periodic, 1_000_000 / TICK_HZ);
+}
+
+#[test_case]
+fn test_tick_rate() {
+    use crate::ap_init::CPU_COUNT;
+    use crate::device::pit;
+    use x86_64::instructions::interrupts;
+
+    const WINDOW_MS: u64 = 200;
+    let expected = WINDOW_MS * u64::from(TICK_HZ) / 1000;
+    let cpus = CPU_COUNT.load(Ordering::SeqCst);
+    let cpu_ticks = |cpu| {
+        percpu::get(cpu)
+            .unwrap()
+            .counters
+            .timer_ticks
+            .load(Ordering::Relaxed)
+    };
+
+    let mut before = [0; percpu::MAX_CPUS];
+    for (cpu, count) in before.iter_mut().enumerate().take(cpus) {
+        *count = cpu_ticks(cpu);
+    }
+    let start = ticks();
+    // the APs already run with interrupts on
+    interrupts::enable();
+    for _ in 0..WINDOW_MS / 50 {
+        unsafe { pit::wait_us(50_000) };
+    }
+    interrupts::disable();
+
+    let measured = ticks() - start;
+    assert!(
+        measured.abs_diff(expected) <= 2,
+        "{} ticks in {} ms, expected {}",
+        measured,
+        WINDOW_MS,
+        expected
+    );
+    for (cpu, &count) in before.iter().enumerate().take(cpus) {
+        if !crate::smp::is_online(cpu) {
+            continue;
+        }
+        let measured = cpu_ticks(cpu) - count;
+        assert!(
+            measured.abs_diff(expected) <= 2,
+            "CPU {} took {} ticks in {} ms, expected {}",
+            cpu,
+            measured,
+            WINDOW_MS,
+            expected
+        );
+    }
+}
+
+#[test_case]
+fn test_monotonic_clock() {
+    use crate::device::pit;
+
+    let start = Instant::now();
+    let mut last = start;
+    for _ in 0..1000 {
+        let now = Instant::now();
+        assert!(now >= last, "clock went backwards");
+        last = now;
+    }
+
+    let before = Instant::now();
+    unsafe { pit::wait_us(20_000) };
+    let elapsed = before.elapsed();
+    // tick-based clocksources are only good to a tick either way
+    let slack = Duration::from_nanos(TICK_NS) + Duration::from_micros(400);
+    assert!(
+        elapsed + slack >= Duration::from_millis(20)
+            && elapsed <= Duration::from_millis(20) + slack,
+        "20 ms measured as {:?}",
+        elapsed
+    );
+}
+
+#[test_case]
+fn test_sleep_for() {
+    let start = Instant::now();
+    cpu_interrupts::enable();
+    sleep_for(Duration::from_millis(30));
+    cpu_interrupts::disable();
+    let slept = start.elapsed();
+    assert!(
+        slept >= Duration::from_millis(30),
+        "woke early after {:?}",
+        slept
+    );
+    assert!(slept < Duration::from_millis(60), "overslept: {:?}", slept);
+}
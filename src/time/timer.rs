@@ -0,0 +1,341 @@
+//! # Timers
+//! One-shot callbacks that run a given time from now. Every CPU keeps the timers armed on it in
+//! a hierarchical timer wheel driven by its own system tick: `LEVELS` levels of `SLOTS` slots,
+//! where a slot on level n covers `SLOTS`^n ticks. A timer further out than one turn of level 0
+//! sits in a coarser slot and cascades down a level each time its slot comes round, so arming,
+//! cancelling and ticking never walk more than one slot's list.
+//!
+//! Timers are intrusive: the wheel links the timers themselves, so nothing is allocated on the
+//! tick path. Callbacks run in the tick interrupt of the CPU the timer was armed on, with
+//! interrupts off; they must not block and shouldn't make large allocations.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::TICK_NS;
+use crate::percpu;
+
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const LEVELS: usize = 4;
+
+/// `Link::level` of a timer on the expired list.
+const EXPIRED: usize = LEVELS;
+/// `TimerInner::cpu` of a timer that isn't on any wheel.
+const NOT_QUEUED: usize = usize::MAX;
+
+/// A CPU's armed timers. Lives in the CPU's `PerCpu` block.
+pub struct TimerWheel {
+    /// The last tick processed.
+    now: u64,
+    slots: [[*const TimerInner; SLOTS]; LEVELS],
+    /// Timers whose tick has come, waiting for their callback to be run.
+    expired: *const TimerInner,
+}
+
+// SAFETY: the pointers are timers the wheel holds a reference to, only touched with the wheel
+// locked.
+unsafe impl Send for TimerWheel {}
+
+struct TimerInner {
+    callback: Box<dyn Fn() + Send + Sync>,
+    /// Serializes arming and cancelling.
+    lock: Mutex<()>,
+    /// Index of the CPU whose wheel holds the timer, or `NOT_QUEUED`. Only changes with that
+    /// wheel locked.
+    cpu: AtomicUsize,
+    /// Position on the wheel, guarded by the lock of the wheel holding the timer.
+    link: UnsafeCell<Link>,
+}
+
+// SAFETY: `link` is only touched with the lock of the wheel holding the timer.
+unsafe impl Sync for TimerInner {}
+
+struct Link {
+    next: *const TimerInner,
+    expires: u64,
+    level: usize,
+    slot: usize,
+}
+
+/// A callback that can be armed to run once after a delay, cancelled, and armed again.
+/// Dropping the timer cancels it.
+pub struct Timer {
+    inner: Arc<TimerInner>,
+}
+
+impl TimerWheel {
+    pub const fn new() -> TimerWheel {
+        TimerWheel {
+            now: 0,
+            slots: [[ptr::null(); SLOTS]; LEVELS],
+            expired: ptr::null(),
+        }
+    }
+
+    /// Queue `timer` to fire at tick `expires`, which mustn't be in the past. The wheel takes
+    /// over one reference to the timer.
+    unsafe fn insert(&mut self, timer: *const TimerInner, expires: u64) {
+        let (level, slot) = self.position(expires);
+        let link = &mut *(*timer).link.get();
+        link.expires = expires;
+        link.level = level;
+        link.slot = slot;
+        link.next = self.slots[level][slot];
+        self.slots[level][slot] = timer;
+    }
+
+    /// The slot a timer expiring at `expires` goes in: the finest level whose slot for it comes
+    /// round within one turn.
+    fn position(&self, expires: u64) -> (usize, usize) {
+        for level in 0..LEVELS {
+            let shift = SLOT_BITS * level as u32;
+            if (expires >> shift) - (self.now >> shift) < SLOTS as u64 {
+                return (level, (expires >> shift) as usize % SLOTS);
+            }
+        }
+        // out of range: park in the last slot of the top level and re-sort from there
+        let shift = SLOT_BITS * (LEVELS - 1) as u32;
+        (
+            LEVELS - 1,
+            ((self.now >> shift) as usize + SLOTS - 1) % SLOTS,
+        )
+    }
+
+    /// Unlink `timer`, which must be on this wheel. The caller gets the wheel's reference.
+    unsafe fn remove(&mut self, timer: *const TimerInner) {
+        let link = &*(*timer).link.get();
+        let mut cursor: *mut *const TimerInner = if link.level == EXPIRED {
+            &mut self.expired
+        } else {
+            &mut self.slots[link.level][link.slot]
+        };
+        while !(*cursor).is_null() {
+            if *cursor == timer {
+                *cursor = link.next;
+                return;
+            }
+            cursor = &mut (*(**cursor).link.get()).next;
+        }
+        panic!("timer missing from its wheel slot");
+    }
+
+    /// Move on one tick, cascading coarser slots that have come round and moving the timers
+    /// due now to the expired list.
+    unsafe fn advance(&mut self) {
+        self.now += 1;
+        for level in 1..LEVELS {
+            let shift = SLOT_BITS * level as u32;
+            if self.now & ((1 << shift) - 1) != 0 {
+                break;
+            }
+            let slot = (self.now >> shift) as usize % SLOTS;
+            let mut timer = core::mem::replace(&mut self.slots[level][slot], ptr::null());
+            while !timer.is_null() {
+                let link = &*(*timer).link.get();
+                let (next, expires) = (link.next, link.expires);
+                self.insert(timer, expires);
+                timer = next;
+            }
+        }
+
+        let slot = self.now as usize % SLOTS;
+        let mut timer = core::mem::replace(&mut self.slots[0][slot], ptr::null());
+        while !timer.is_null() {
+            let link = &mut *(*timer).link.get();
+            let next = link.next;
+            link.level = EXPIRED;
+            link.next = self.expired;
+            self.expired = timer;
+            timer = next;
+        }
+    }
+
+    /// Take a timer off the expired list. The caller gets the wheel's reference.
+    unsafe fn pop_expired(&mut self) -> Option<*const TimerInner> {
+        if self.expired.is_null() {
+            return None;
+        }
+        let timer = self.expired;
+        self.expired = (*(*timer).link.get()).next;
+        Some(timer)
+    }
+}
+
+impl fmt::Debug for TimerWheel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TimerWheel")
+            .field("now", &self.now)
+            .finish()
+    }
+}
+
+impl Timer {
+    pub fn new(callback: impl Fn() + Send + Sync + 'static) -> Timer {
+        Timer {
+            inner: Arc::new(TimerInner {
+                callback: Box::new(callback),
+                lock: Mutex::new(()),
+                cpu: AtomicUsize::new(NOT_QUEUED),
+                link: UnsafeCell::new(Link {
+                    next: ptr::null(),
+                    expires: 0,
+                    level: 0,
+                    slot: 0,
+                }),
+            }),
+        }
+    }
+
+    /// Run the callback on this CPU once at least `delay` has passed, replacing any earlier
+    /// arming.
+    pub fn arm(&self, delay: Duration) {
+        // round up, and add one for the part of the current tick that's already gone
+        let ticks = (delay.as_nanos() as u64 + TICK_NS - 1) / TICK_NS + 1;
+        interrupts::without_interrupts(|| {
+            let _guard = self.inner.lock.lock();
+            self.dequeue();
+
+            let mut wheel = percpu!(timers).lock();
+            let expires = wheel.now + ticks;
+            let timer = Arc::into_raw(self.inner.clone());
+            unsafe { wheel.insert(timer, expires) };
+            self.inner.cpu.store(percpu!(cpu_index), Ordering::SeqCst);
+        });
+    }
+
+    /// Stop the timer from firing. Returns false if it wasn't armed, or is already firing.
+    pub fn cancel(&self) -> bool {
+        interrupts::without_interrupts(|| {
+            let _guard = self.inner.lock.lock();
+            self.dequeue()
+        })
+    }
+
+    /// Whether the timer is armed and hasn't fired yet.
+    pub fn is_armed(&self) -> bool {
+        self.inner.cpu.load(Ordering::SeqCst) != NOT_QUEUED
+    }
+
+    /// Take the timer off whichever wheel holds it. Needs the timer's lock and interrupts off.
+    fn dequeue(&self) -> bool {
+        let cpu = self.inner.cpu.load(Ordering::SeqCst);
+        if cpu == NOT_QUEUED {
+            return false;
+        }
+        let mut wheel = percpu::get(cpu)
+            .expect("timer on a missing CPU")
+            .timers
+            .lock();
+        // firing is the only other way off the wheel, and it happens under the wheel lock
+        if self.inner.cpu.load(Ordering::SeqCst) != cpu {
+            return false;
+        }
+        let timer = Arc::as_ptr(&self.inner);
+        unsafe {
+            wheel.remove(timer);
+            drop(Arc::from_raw(timer));
+        }
+        self.inner.cpu.store(NOT_QUEUED, Ordering::SeqCst);
+        true
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// Catch this CPU's wheel up with its tick count and run the callbacks that came due. Called
+/// from the tick handler.
+pub(crate) fn run_local() {
+    let target = percpu!(counters.timer_ticks).load(Ordering::Relaxed);
+    loop {
+        let timer = {
+            let mut wheel = percpu!(timers).lock();
+            loop {
+                if let Some(timer) = unsafe { wheel.pop_expired() } {
+                    unsafe { (*timer).cpu.store(NOT_QUEUED, Ordering::SeqCst) };
+                    break timer;
+                }
+                if wheel.now >= target {
+                    return;
+                }
+                unsafe { wheel.advance() };
+            }
+        };
+        // run it with the wheel unlocked, so the callback can arm timers, itself included
+        let timer = unsafe { Arc::from_raw(timer) };
+        (timer.callback)();
+    }
+}
+
+#[test_case]
+fn test_wheel_cascade() {
+    let mut wheel = TimerWheel::new();
+    let expiries = [1, 63, 64, 65, 4096 + 3, 300_000];
+    let timers: alloc::vec::Vec<Timer> = expiries.iter().map(|_| Timer::new(|| ())).collect();
+    for (timer, &expires) in timers.iter().zip(expiries.iter()) {
+        unsafe { wheel.insert(Arc::into_raw(timer.inner.clone()), expires) };
+    }
+
+    let mut fired = 0;
+    while fired < expiries.len() {
+        unsafe { wheel.advance() };
+        while let Some(timer) = unsafe { wheel.pop_expired() } {
+            let index = timers
+                .iter()
+                .position(|t| Arc::as_ptr(&t.inner) == timer)
+                .unwrap();
+            assert_eq!(wheel.now, expiries[index], "timer fired at the wrong tick");
+            drop(unsafe { Arc::from_raw(timer) });
+            fired += 1;
+        }
+    }
+}
+
+#[test_case]
+fn test_timer_arm_cancel_rearm() {
+    use super::{sleep_for, Instant};
+    use core::sync::atomic::AtomicU64;
+
+    static FIRED: AtomicUsize = AtomicUsize::new(0);
+    static FIRED_AT: AtomicU64 = AtomicU64::new(0);
+    let timer = Timer::new(|| {
+        FIRED.fetch_add(1, Ordering::SeqCst);
+        FIRED_AT.store(Instant::now().as_nanos(), Ordering::SeqCst);
+    });
+
+    interrupts::enable();
+
+    timer.arm(Duration::from_millis(20));
+    assert!(timer.cancel());
+    assert!(!timer.cancel());
+    sleep_for(Duration::from_millis(50));
+    assert_eq!(FIRED.load(Ordering::SeqCst), 0, "cancelled timer fired");
+
+    // the second arming replaces the first
+    timer.arm(Duration::from_millis(200));
+    let start = Instant::now();
+    timer.arm(Duration::from_millis(30));
+    sleep_for(Duration::from_millis(100));
+    assert_eq!(FIRED.load(Ordering::SeqCst), 1);
+    assert!(!timer.is_armed());
+    let delay = Duration::from_nanos(FIRED_AT.load(Ordering::SeqCst) - start.as_nanos());
+    assert!(
+        delay >= Duration::from_millis(30),
+        "fired early, after {:?}",
+        delay
+    );
+
+    interrupts::disable();
+}